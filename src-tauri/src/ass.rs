@@ -0,0 +1,479 @@
+use crate::error::{CommandError, CommandResult};
+use rsubs_lib::ssa;
+use rsubs_lib::util::color::Color;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A styled ASS/SSA script reduced to what the player overlay draws: every
+/// event with its style and override tags already resolved. Parsing the file
+/// is left to rsubs-lib, only the override tags are read here.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct AssScript {
+    pub play_res_x: f64,
+    pub play_res_y: f64,
+    pub events: Vec<AssEvent>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct AssEvent {
+    /// Seconds.
+    pub start: f64,
+    pub end: f64,
+    pub layer: i32,
+    /// Numpad layout: 1 is bottom left, 5 the middle, 9 top right.
+    pub alignment: u8,
+    /// `\pos`, in script coordinates.
+    pub pos: Option<(f64, f64)>,
+    /// `\move`, takes the place of `pos`.
+    pub movement: Option<AssMove>,
+    /// `\fad`, seconds of fade in and fade out.
+    pub fade: Option<(f64, f64)>,
+    pub margin_l: f64,
+    pub margin_r: f64,
+    pub margin_v: f64,
+    pub segments: Vec<AssSegment>,
+}
+
+/// A straight move in script coordinates. `start` and `end` are seconds
+/// from the start of the event, both 0 to move for the whole event.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct AssMove {
+    pub from: (f64, f64),
+    pub to: (f64, f64),
+    pub start: f64,
+    pub end: f64,
+}
+
+/// A run of text sharing one look. Line breaks are kept as `\n`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct AssSegment {
+    pub text: String,
+    pub font: String,
+    /// In script pixels.
+    pub size: f64,
+    /// Css colors.
+    pub color: String,
+    pub outline_color: String,
+    pub outline: f64,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+}
+
+#[derive(Debug, Clone)]
+struct Style {
+    font: String,
+    size: f64,
+    color: String,
+    outline_color: String,
+    outline: f64,
+    bold: bool,
+    italic: bool,
+    underline: bool,
+    alignment: u8,
+    margin_l: f64,
+    margin_r: f64,
+    margin_v: f64,
+    /// `\p`, above 0 the text is vector drawing commands.
+    drawing: u32,
+}
+
+impl Default for Style {
+    fn default() -> Self {
+        Style {
+            font: "sans-serif".to_string(),
+            size: 20_f64,
+            color: "rgba(255,255,255,1.000)".to_string(),
+            outline_color: "rgba(0,0,0,1.000)".to_string(),
+            outline: 2_f64,
+            bold: false,
+            italic: false,
+            underline: false,
+            alignment: 2,
+            margin_l: 10_f64,
+            margin_r: 10_f64,
+            margin_v: 10_f64,
+            drawing: 0,
+        }
+    }
+}
+
+impl From<&ssa::SSAStyle> for Style {
+    fn from(s: &ssa::SSAStyle) -> Self {
+        Style {
+            font: s.fontname.trim().to_string(),
+            size: s.fontsize as f64,
+            color: css_color(&s.firstcolor),
+            outline_color: css_color(&s.outlinecolor),
+            outline: s.outline as f64,
+            bold: s.bold,
+            italic: s.italic,
+            underline: s.underline,
+            alignment: s.alignment as u8,
+            margin_l: s.lmargin as f64,
+            margin_r: s.rmargin as f64,
+            margin_v: s.vmargin as f64,
+            drawing: 0,
+        }
+    }
+}
+
+impl Style {
+    fn segment(&self, text: String) -> AssSegment {
+        AssSegment {
+            text,
+            font: self.font.clone(),
+            size: self.size,
+            color: self.color.clone(),
+            outline_color: self.outline_color.clone(),
+            outline: self.outline,
+            bold: self.bold,
+            italic: self.italic,
+            underline: self.underline,
+        }
+    }
+}
+
+/// `a` is kept as the script has it, 0 is opaque.
+fn css_color(c: &Color) -> String {
+    format!(
+        "rgba({},{},{},{:.3})",
+        c.r,
+        c.g,
+        c.b,
+        1_f64 - c.a as f64 / 255_f64
+    )
+}
+
+/// `&HAABBGGRR` (alpha inverted, 00 is opaque) to a css color.
+fn color(value: &str) -> Option<String> {
+    let hex = value
+        .trim()
+        .trim_start_matches('&')
+        .trim_start_matches(|c| c == 'H' || c == 'h')
+        .trim_end_matches('&');
+    let v = u32::from_str_radix(hex, 16).ok()?;
+    let (a, b, g, r) = (
+        (v >> 24) & 0xff,
+        (v >> 16) & 0xff,
+        (v >> 8) & 0xff,
+        v & 0xff,
+    );
+    Some(format!(
+        "rgba({},{},{},{:.3})",
+        r,
+        g,
+        b,
+        1_f64 - a as f64 / 255_f64
+    ))
+}
+
+/// Keeps the alpha of `base`, `\c` tags only change the color.
+fn with_alpha(rgb: &str, base: &str) -> String {
+    match (rgb.rfind(','), base.rfind(',')) {
+        (Some(i), Some(j)) => format!("{}{}", &rgb[..i], &base[j..]),
+        _ => rgb.to_string(),
+    }
+}
+
+/// `&HAA&` of the `\alpha` tags to a css opacity.
+fn opacity(value: &str) -> Option<f64> {
+    let hex = value
+        .trim()
+        .trim_start_matches('&')
+        .trim_start_matches(|c| c == 'H' || c == 'h')
+        .trim_end_matches('&');
+    let a = u8::from_str_radix(hex, 16).ok()?;
+    Some(1_f64 - a as f64 / 255_f64)
+}
+
+/// Swaps the alpha of a css color, `\alpha` tags only change the alpha.
+fn set_opacity(color: &str, opacity: f64) -> String {
+    match color.rfind(',') {
+        Some(i) => format!("{},{:.3})", &color[..i], opacity),
+        None => color.to_string(),
+    }
+}
+
+/// The numbers inside `\tag(1,2)`.
+fn args(value: &str) -> Vec<f64> {
+    value
+        .trim_start_matches('(')
+        .trim_end_matches(')')
+        .split(',')
+        .filter_map(|v| v.trim().parse().ok())
+        .collect()
+}
+
+/// Applies one override tag (without the backslash) to the event.
+fn apply_tag(tag: &str, style: &mut Style, base: &Style, event: &mut AssEvent) {
+    let starts = |p: &str| tag.strip_prefix(p);
+    if let Some(v) = starts("pos") {
+        let a = args(v);
+        if a.len() == 2 {
+            event.pos = Some((a[0], a[1]));
+        }
+    } else if let Some(v) = starts("move") {
+        let a = args(v);
+        if a.len() == 4 || a.len() == 6 {
+            let (start, end) = match a.get(4..6) {
+                Some(t) => (t[0] / 1000_f64, t[1] / 1000_f64),
+                None => (0_f64, 0_f64),
+            };
+            event.movement = Some(AssMove {
+                from: (a[0], a[1]),
+                to: (a[2], a[3]),
+                start,
+                end,
+            });
+        }
+    } else if starts("fade").is_some() {
+        // the seven argument fade, rare enough to show without it
+    } else if let Some(v) = starts("fad") {
+        let a = args(v);
+        if a.len() == 2 {
+            event.fade = Some((a[0] / 1000_f64, a[1] / 1000_f64));
+        }
+    } else if let Some(v) = starts("alpha") {
+        if let Some(a) = opacity(v) {
+            style.color = set_opacity(&style.color, a);
+            style.outline_color = set_opacity(&style.outline_color, a);
+        }
+    } else if let Some(v) = starts("1a") {
+        if let Some(a) = opacity(v) {
+            style.color = set_opacity(&style.color, a);
+        }
+    } else if let Some(v) = starts("3a") {
+        if let Some(a) = opacity(v) {
+            style.outline_color = set_opacity(&style.outline_color, a);
+        }
+    } else if let Some(v) = starts("an") {
+        if let Ok(n) = v.trim().parse::<u8>() {
+            event.alignment = n;
+        }
+    } else if let Some(v) = starts("fn") {
+        style.font = v.trim().to_string();
+    } else if let Some(v) = starts("fs") {
+        if let Ok(n) = v.trim().parse() {
+            style.size = n;
+        }
+    } else if let Some(v) = starts("1c").or_else(|| starts("c")) {
+        if let Some(c) = color(v) {
+            style.color = with_alpha(&c, &style.color);
+        }
+    } else if let Some(v) = starts("3c") {
+        if let Some(c) = color(v) {
+            style.outline_color = with_alpha(&c, &style.outline_color);
+        }
+    } else if let Some(v) = starts("bord") {
+        if let Ok(n) = v.trim().parse() {
+            style.outline = n;
+        }
+    } else if let Some(w) = starts("b").and_then(|v| v.trim().parse::<u32>().ok()) {
+        // `\b1`, `\b0` or a weight like `\b700`
+        style.bold = w == 1 || w >= 600;
+    } else if let Some(n) = starts("i").and_then(|v| v.trim().parse::<u32>().ok()) {
+        style.italic = n != 0;
+    } else if let Some(n) = starts("u").and_then(|v| v.trim().parse::<u32>().ok()) {
+        style.underline = n != 0;
+    } else if let Some(n) = starts("p").and_then(|v| v.trim().parse::<u32>().ok()) {
+        style.drawing = n;
+    } else if tag == "r" {
+        *style = base.clone();
+    }
+    // anything else (karaoke timings, transforms, clips...) is dropped and
+    // the text is drawn as is
+}
+
+/// Splits the text of an event into segments. Drawings can't be shown as
+/// text, their commands are skipped.
+fn parse_text(text: &str, base: &Style, event: &mut AssEvent) {
+    let mut style = base.clone();
+    let mut current = String::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        if let Some(stripped) = rest.strip_prefix('{') {
+            let end = stripped.find('}').unwrap_or(stripped.len());
+            let block = &stripped[..end];
+            rest = stripped.get(end + 1..).unwrap_or("");
+            if !current.is_empty() {
+                event
+                    .segments
+                    .push(style.segment(std::mem::take(&mut current)));
+            }
+            for tag in block.split('\\').filter(|t| !t.is_empty()) {
+                apply_tag(tag.trim(), &mut style, base, event);
+            }
+        } else if style.drawing > 0 {
+            let end = rest.find('{').unwrap_or(rest.len());
+            rest = &rest[end..];
+        } else if let Some(r) = rest
+            .strip_prefix("\\N")
+            .or_else(|| rest.strip_prefix("\\n"))
+        {
+            current.push('\n');
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix("\\h") {
+            current.push('\u{a0}');
+            rest = r;
+        } else {
+            let c = rest.chars().next().unwrap_or_default();
+            current.push(c);
+            rest = &rest[c.len_utf8()..];
+        }
+    }
+    if !current.is_empty() {
+        event.segments.push(style.segment(current));
+    }
+}
+
+/// A `[Script Info]` value, the section is plain `key: value` lines.
+fn script_info(raw: &str, key: &str) -> Option<f64> {
+    raw.lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(k, _)| k.trim().eq_ignore_ascii_case(key))
+        .and_then(|(_, v)| v.trim().parse().ok())
+}
+
+pub fn parse(raw: &str) -> CommandResult<AssScript> {
+    let raw = raw.trim_start_matches('\u{feff}');
+    let file = ssa::parse(raw.to_string())
+        .map_err(|e| CommandError::Parse(format!("Not an ASS/SSA script. {}", e)))?;
+    let styles: HashMap<&str, Style> = file
+        .styles
+        .iter()
+        .map(|s| (s.name.trim(), Style::from(s)))
+        .collect();
+    let mut script = AssScript {
+        play_res_x: script_info(raw, "playresx").unwrap_or(384_f64),
+        play_res_y: script_info(raw, "playresy").unwrap_or(288_f64),
+        events: vec![],
+    };
+    for e in &file.events {
+        let base = styles
+            .get(e.style.trim().trim_start_matches('*'))
+            .cloned()
+            .unwrap_or_default();
+        // 0 in an event keeps the margin of its style
+        let margin = |m: i32, style: f64| if m == 0 { style } else { m as f64 };
+        let mut event = AssEvent {
+            start: e.line_start.total_ms() as f64 / 1000_f64,
+            end: e.line_end.total_ms() as f64 / 1000_f64,
+            layer: e.layer,
+            alignment: base.alignment,
+            pos: None,
+            movement: None,
+            fade: None,
+            margin_l: margin(e.lmargin, base.margin_l),
+            margin_r: margin(e.rmargin, base.margin_r),
+            margin_v: margin(e.vmargin, base.margin_v),
+            segments: vec![],
+        };
+        parse_text(&e.line_text, &base, &mut event);
+        if !event.segments.is_empty() {
+            script.events.push(event);
+        }
+    }
+    script.events.sort_by(|a, b| {
+        a.start
+            .partial_cmp(&b.start)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+    Ok(script)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(text: &str) -> AssEvent {
+        let mut event = AssEvent::default();
+        parse_text(text, &Style::default(), &mut event);
+        event
+    }
+
+    #[test]
+    fn override_tags() {
+        let e = event("{\\pos(10,20)\\an7\\b1}Bold{\\i1\\c&H0000FF&}red\\Nnext{\\r}plain");
+        assert_eq!(e.pos, Some((10_f64, 20_f64)));
+        assert_eq!(e.alignment, 7);
+        let texts: Vec<&str> = e.segments.iter().map(|s| s.text.as_str()).collect();
+        assert_eq!(texts, ["Bold", "red\nnext", "plain"]);
+        assert!(e.segments[0].bold && !e.segments[0].italic);
+        assert!(e.segments[1].bold && e.segments[1].italic);
+        assert_eq!(e.segments[1].color, "rgba(255,0,0,1.000)");
+        assert_eq!(e.segments[2], Style::default().segment("plain".to_string()));
+    }
+
+    #[test]
+    fn alpha_fade_and_move() {
+        let e = event("{\\fad(200,300)\\move(0,0,100,50,0,500)\\alpha&H80&}a{\\1a&HFF&}b");
+        assert_eq!(e.fade, Some((0.2, 0.3)));
+        assert_eq!(
+            e.movement,
+            Some(AssMove {
+                from: (0_f64, 0_f64),
+                to: (100_f64, 50_f64),
+                start: 0_f64,
+                end: 0.5,
+            })
+        );
+        assert_eq!(e.segments[0].color, "rgba(255,255,255,0.498)");
+        assert_eq!(e.segments[0].outline_color, "rgba(0,0,0,0.498)");
+        assert_eq!(e.segments[1].color, "rgba(255,255,255,0.000)");
+        assert_eq!(e.segments[1].outline_color, "rgba(0,0,0,0.498)");
+
+        let e = event("{\\move(1,2,3,4)}x");
+        assert_eq!(e.movement.map(|m| (m.start, m.end)), Some((0_f64, 0_f64)));
+    }
+
+    #[test]
+    fn drawings_are_skipped() {
+        let e = event("{\\p1}m 0 0 l 10 0 10 10{\\p0}text");
+        let texts: Vec<&str> = e.segments.iter().map(|s| s.text.as_str()).collect();
+        assert_eq!(texts, ["text"]);
+        assert!(event("{\\p1}m 0 0 l 10 0 10 10").segments.is_empty());
+    }
+
+    const SCRIPT: &str = "[Script Info]
+ScriptType: v4.00+
+PlayResX: 1920
+PlayResY: 1080
+
+[V4+ Styles]
+Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding
+Style: Default,Arial,48,&H00FFFFFF,&H000000FF,&H00000000,&H00000000,0,0,0,0,100,100,0,0,1,3,0,2,20,20,40,1
+Style: Sign,Times New Roman,36,&H0000FFFF,&H000000FF,&H80000000,&H00000000,0,0,0,0,100,100,0,0,1,1,0,8,10,10,10,1
+
+[Events]
+Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text
+Dialogue: 1,0:00:02.00,0:00:04.00,Sign,,0,0,25,,Sign text
+Dialogue: 0,0:00:01.00,0:00:03.50,Default,,0,0,0,,Hello
+";
+
+    #[test]
+    fn styles_are_mapped() {
+        let script = parse(SCRIPT).unwrap();
+        assert_eq!((script.play_res_x, script.play_res_y), (1920_f64, 1080_f64));
+        let (hello, sign) = (&script.events[0], &script.events[1]);
+
+        assert_eq!((hello.start, hello.end, hello.layer), (1_f64, 3.5, 0));
+        assert_eq!(hello.alignment, 2);
+        assert_eq!(
+            (hello.margin_l, hello.margin_r, hello.margin_v),
+            (20_f64, 20_f64, 40_f64)
+        );
+        assert_eq!(hello.segments[0].font, "Arial");
+        assert_eq!(hello.segments[0].size, 48_f64);
+        assert_eq!(hello.segments[0].color, "rgba(255,255,255,1.000)");
+        assert_eq!(hello.segments[0].outline, 3_f64);
+
+        assert_eq!((sign.start, sign.layer), (2_f64, 1));
+        assert_eq!(sign.alignment, 8);
+        assert_eq!(sign.margin_v, 25_f64);
+        assert_eq!(sign.segments[0].text, "Sign text");
+        assert_eq!(sign.segments[0].font, "Times New Roman");
+        assert_eq!(sign.segments[0].color, "rgba(255,255,0,1.000)");
+        assert_eq!(sign.segments[0].outline_color, "rgba(0,0,0,0.498)");
+    }
+}
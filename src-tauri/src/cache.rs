@@ -0,0 +1,233 @@
+use crate::storage;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CacheEntry {
+    size: u64,
+    /// The `clock` when last read or written, higher is more recent.
+    last_used: u64,
+}
+
+/// A directory of files bounded to `max_bytes`, dropping the least recently
+/// used files first. Keys are hashed into file names so anything (urls...)
+/// can be used as a key.
+pub struct DiskCache {
+    dir: PathBuf,
+    max_bytes: u64,
+    entries: HashMap<String, CacheEntry>,
+    /// Counts uses, a burst within the same second still has an order.
+    clock: u64,
+}
+
+/// 64 bit FNV-1a of the key. The names outlive the build, so it must not be
+/// std's hasher, which may change between releases.
+fn file_name(key: &str) -> String {
+    let hash = key.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+impl DiskCache {
+    pub fn open(dir: PathBuf, max_bytes: u64) -> DiskCache {
+        let entries: HashMap<String, CacheEntry> =
+            storage::load_json(&dir.join("index.json")).unwrap_or_default();
+        // files removed behind our back (or never finished) are forgotten
+        let entries = entries
+            .into_iter()
+            .filter(|(name, _)| dir.join(name).exists())
+            .collect::<HashMap<_, _>>();
+        let clock = entries.values().map(|e| e.last_used).max().unwrap_or(0);
+        DiskCache {
+            dir,
+            max_bytes,
+            entries,
+            clock,
+        }
+    }
+
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.entries.contains_key(&file_name(key))
+    }
+
+    fn save_index(&self) -> Result<()> {
+        storage::save_json(&self.dir.join("index.json"), &self.entries)
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<Vec<u8>> {
+        let name = file_name(key);
+        if !self.entries.contains_key(&name) {
+            return None;
+        }
+        match fs::read(self.dir.join(&name)) {
+            Ok(data) => {
+                // only kept in memory, the next `put` writes it out
+                let used = self.tick();
+                if let Some(entry) = self.entries.get_mut(&name) {
+                    entry.last_used = used;
+                }
+                Some(data)
+            }
+            Err(_) => {
+                self.entries.remove(&name);
+                None
+            }
+        }
+    }
+
+    /// Stores `data` under `key`, unless it is bigger than the whole cache.
+    pub fn put(&mut self, key: &str, data: &[u8]) -> Result<()> {
+        if data.len() as u64 > self.max_bytes {
+            return Ok(());
+        }
+        let name = file_name(key);
+        fs::create_dir_all(&self.dir)?;
+        fs::write(self.dir.join(&name), data)?;
+        let last_used = self.tick();
+        self.entries.insert(
+            name.clone(),
+            CacheEntry {
+                size: data.len() as u64,
+                last_used,
+            },
+        );
+        self.evict(Some(&name));
+        self.save_index()
+    }
+
+    /// Changes the limit, evicting right away when it shrinks.
+    pub fn set_max_bytes(&mut self, max_bytes: u64) -> Result<()> {
+        self.max_bytes = max_bytes;
+        let before = self.entries.len();
+        self.evict(None);
+        if self.entries.len() != before {
+            self.save_index()?;
+        }
+        Ok(())
+    }
+
+    pub fn size(&self) -> u64 {
+        self.entries.values().map(|e| e.size).sum()
+    }
+
+    /// Drops the least recently used files until the cache fits, never `keep`.
+    fn evict(&mut self, keep: Option<&str>) {
+        let mut size = self.size();
+        if size <= self.max_bytes {
+            return;
+        }
+        let mut by_age: Vec<(String, CacheEntry)> = self
+            .entries
+            .iter()
+            .filter(|(k, _)| Some(k.as_str()) != keep)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect();
+        by_age.sort_by_key(|(_, e)| e.last_used);
+        for (name, entry) in by_age {
+            if size <= self.max_bytes {
+                break;
+            }
+            if let Err(e) = fs::remove_file(self.dir.join(&name)) {
+                println!("Failed to evict {} from the cache. {}", name, e);
+            }
+            self.entries.remove(&name);
+            size -= entry.size;
+        }
+    }
+
+    pub fn clear(&mut self) -> Result<()> {
+        for name in self.entries.keys() {
+            if let Err(e) = fs::remove_file(self.dir.join(name)) {
+                println!("Failed to remove {} from the cache. {}", name, e);
+            }
+        }
+        self.entries.clear();
+        self.save_index()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_names_are_stable() {
+        // published FNV-1a test vectors
+        assert_eq!(file_name(""), "cbf29ce484222325");
+        assert_eq!(file_name("a"), "af63dc4c8601ec8c");
+        assert_eq!(file_name("foobar"), "85944171f73967e8");
+    }
+
+    fn cache(name: &str, max_bytes: u64) -> DiskCache {
+        let dir =
+            std::env::temp_dir().join(format!("crunchyview-cache-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        DiskCache::open(dir, max_bytes)
+    }
+
+    #[test]
+    fn evicts_the_least_recently_used() {
+        let mut cache = cache("lru", 30);
+        cache.put("a", &[0; 10]).unwrap();
+        cache.put("b", &[0; 10]).unwrap();
+        cache.put("c", &[0; 10]).unwrap();
+        assert!(cache.get("a").is_some());
+
+        // all within the same second, the order still holds
+        cache.put("d", &[0; 10]).unwrap();
+        assert!(!cache.contains("b"));
+        assert!(cache.contains("a") && cache.contains("c") && cache.contains("d"));
+        assert_eq!(cache.size(), 30);
+
+        // the order survives a restart
+        let mut cache = DiskCache::open(cache.dir.clone(), 30);
+        cache.put("e", &[0; 10]).unwrap();
+        assert!(!cache.contains("c"));
+        assert_eq!(cache.get("e"), Some(vec![0; 10]));
+    }
+
+    #[test]
+    fn never_evicts_what_was_just_put() {
+        let mut cache = cache("keep", 30);
+        cache.put("a", &[0; 10]).unwrap();
+        cache.put("b", &[0; 25]).unwrap();
+        assert!(cache.contains("b"));
+        assert!(!cache.contains("a"));
+        assert_eq!(cache.get("b"), Some(vec![0; 25]));
+    }
+
+    #[test]
+    fn shrinking_evicts_right_away() {
+        let mut cache = cache("shrink", 30);
+        cache.put("a", &[0; 10]).unwrap();
+        cache.put("b", &[0; 10]).unwrap();
+        cache.put("c", &[0; 10]).unwrap();
+        cache.get("a");
+
+        cache.set_max_bytes(20).unwrap();
+        assert!(!cache.contains("b"));
+        assert_eq!(cache.size(), 20);
+        cache.set_max_bytes(0).unwrap();
+        assert_eq!(cache.size(), 0);
+        assert_eq!(cache.get("a"), None);
+    }
+
+    #[test]
+    fn skips_entries_bigger_than_the_cache() {
+        let mut cache = cache("big", 30);
+        cache.put("a", &[0; 10]).unwrap();
+        cache.put("b", &[0; 31]).unwrap();
+        assert!(!cache.contains("b"));
+        assert!(cache.contains("a"));
+        assert_eq!(cache.size(), 10);
+    }
+}
@@ -0,0 +1,966 @@
+use crate::error::{CommandError, CommandResult};
+use crate::settings::Quality;
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
+use futures_util::{stream, StreamExt};
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+
+/// Emitted to the frontend with a `DownloadProgress` after every segment.
+pub const PROGRESS_EVENT: &str = "download-progress";
+
+/// Segments written between two rewrites of the index. A resumed download
+/// carries on from the last one.
+const INDEX_CHECKPOINT: usize = 20;
+
+/// One entry of a master playlist.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Variant {
+    /// Bits per second.
+    pub bandwidth: u64,
+    pub resolution: Option<(u32, u32)>,
+    /// Absolute url of its media playlist.
+    pub uri: String,
+}
+
+/// `#EXT-X-KEY` with `METHOD=AES-128`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Key {
+    pub uri: String,
+    /// Defaults to the media sequence number of the segment when missing.
+    pub iv: Option<[u8; 16]>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub uri: String,
+    pub sequence: u64,
+    /// Seconds, from `#EXTINF`.
+    pub duration: f64,
+    pub key: Option<Key>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MediaPlaylist {
+    /// `#EXT-X-MAP`, written before the first segment of fmp4 streams.
+    pub init: Option<String>,
+    pub segments: Vec<Segment>,
+}
+
+impl MediaPlaylist {
+    /// The extension of the segments put together.
+    pub fn extension(&self) -> &'static str {
+        if self.init.is_some() {
+            "mp4"
+        } else {
+            "ts"
+        }
+    }
+}
+
+/// `path` with the extension of what `playlist` holds, unless it was named
+/// something else than one of the two.
+pub fn container_path(path: &Path, playlist: &MediaPlaylist) -> PathBuf {
+    match path.extension().and_then(|e| e.to_str()) {
+        None | Some("ts") | Some("mp4") => path.with_extension(playlist.extension()),
+        Some(_) => path.to_path_buf(),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct DownloadProgress {
+    /// Episode id, or the playlist url outside the app.
+    pub id: String,
+    /// The file being written, see `container_path`.
+    pub path: String,
+    /// Segments written so far.
+    pub done: usize,
+    pub total: usize,
+    pub bytes: u64,
+}
+
+/// Where an interrupted download stopped. The file holds at least `bytes`,
+/// the first `done` segments, its index may be a checkpoint behind.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Resume {
+    pub done: usize,
+    pub bytes: u64,
+}
+
+/// Where the segments sit inside a downloaded file. It is kept next to the
+/// file as an HLS playlist of byte ranges, so the player seeks in a download
+/// the way it does in a stream.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LocalIndex {
+    /// Size of the `#EXT-X-MAP` data at the start of the file.
+    pub init: Option<u64>,
+    /// Duration and size of every segment, in file order.
+    pub segments: Vec<(f64, u64)>,
+}
+
+impl LocalIndex {
+    pub fn path(video: &Path) -> PathBuf {
+        video.with_extension("m3u8")
+    }
+
+    pub fn read(video: &Path) -> Option<LocalIndex> {
+        let text = std::fs::read_to_string(LocalIndex::path(video)).ok()?;
+        let mut index = LocalIndex::default();
+        let mut duration = 0_f64;
+        for line in text.lines().map(str::trim) {
+            if let Some(attrs) = line.strip_prefix("#EXT-X-MAP:") {
+                let range = attributes(attrs).get("BYTERANGE").cloned()?;
+                index.init = range.split('@').next()?.parse().ok();
+            } else if let Some(v) = line.strip_prefix("#EXTINF:") {
+                duration = v.split(',').next()?.trim().parse().ok()?;
+            } else if let Some(v) = line.strip_prefix("#EXT-X-BYTERANGE:") {
+                index
+                    .segments
+                    .push((duration, v.split('@').next()?.trim().parse().ok()?));
+            }
+        }
+        Some(index)
+    }
+
+    pub fn bytes(&self) -> u64 {
+        self.init.unwrap_or(0) + self.segments.iter().map(|(_, size)| size).sum::<u64>()
+    }
+
+    /// The playlist, segments pointing at `uri`. Without `complete` players
+    /// treat it as a stream still being written.
+    pub fn render(&self, uri: &str, complete: bool) -> String {
+        let target = self
+            .segments
+            .iter()
+            .map(|(d, _)| d.ceil() as u64)
+            .max()
+            .unwrap_or(1);
+        // byte ranges need 4, a byte range on the map 6
+        let version = if self.init.is_some() { 6 } else { 4 };
+        let mut out = format!(
+            "#EXTM3U\n#EXT-X-VERSION:{}\n#EXT-X-TARGETDURATION:{}\n#EXT-X-MEDIA-SEQUENCE:0\n",
+            version, target
+        );
+        if complete {
+            out.push_str("#EXT-X-PLAYLIST-TYPE:VOD\n");
+        }
+        let mut offset = 0_u64;
+        if let Some(init) = self.init {
+            out.push_str(&format!(
+                "#EXT-X-MAP:URI=\"{}\",BYTERANGE=\"{}@0\"\n",
+                uri, init
+            ));
+            offset = init;
+        }
+        for (duration, size) in &self.segments {
+            out.push_str(&format!(
+                "#EXTINF:{:.3},\n#EXT-X-BYTERANGE:{}@{}\n{}\n",
+                duration, size, offset, uri
+            ));
+            offset += size;
+        }
+        if complete {
+            out.push_str("#EXT-X-ENDLIST\n");
+        }
+        out
+    }
+
+    fn write(&self, video: &Path, complete: bool) -> CommandResult<()> {
+        let uri = video
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        std::fs::write(LocalIndex::path(video), self.render(&uri, complete))
+            .map_err(|e| CommandError::Io(format!("Failed to write the index. {}", e)))
+    }
+}
+
+/// `uri` relative to the playlist at `base`.
+pub fn resolve(base: &str, uri: &str) -> String {
+    if uri.contains("://") {
+        return uri.to_string();
+    }
+    let base = base.split(|c| c == '?' || c == '#').next().unwrap_or(base);
+    if uri.starts_with('/') {
+        let host_start = base.find("://").map_or(0, |i| i + 3);
+        let origin = match base[host_start..].find('/') {
+            Some(i) => &base[..host_start + i],
+            None => base,
+        };
+        return format!("{}{}", origin, uri);
+    }
+    match base.rfind('/') {
+        Some(i) => format!("{}{}", &base[..=i], uri),
+        None => uri.to_string(),
+    }
+}
+
+/// `KEY=value,OTHER="quoted, value"` to a map, keys kept as written.
+fn attributes(value: &str) -> HashMap<String, String> {
+    let mut map = HashMap::new();
+    let mut rest = value.trim();
+    while let Some(eq) = rest.find('=') {
+        let key = rest[..eq].trim().to_string();
+        rest = &rest[eq + 1..];
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            let v = &quoted[..end];
+            rest = quoted.get(end + 1..).unwrap_or("");
+            v
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            let v = &rest[..end];
+            rest = &rest[end..];
+            v
+        };
+        map.insert(key, value.to_string());
+        rest = rest.trim_start_matches(',');
+    }
+    map
+}
+
+/// `0x` followed by 32 hex digits.
+fn parse_iv(value: &str) -> Option<[u8; 16]> {
+    let hex = value
+        .trim()
+        .trim_start_matches("0x")
+        .trim_start_matches("0X");
+    if hex.len() != 32 {
+        return None;
+    }
+    let mut iv = [0u8; 16];
+    for (i, byte) in iv.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(iv)
+}
+
+fn sequence_iv(sequence: u64) -> [u8; 16] {
+    let mut iv = [0u8; 16];
+    iv[8..].copy_from_slice(&sequence.to_be_bytes());
+    iv
+}
+
+pub fn is_master(text: &str) -> bool {
+    text.contains("#EXT-X-STREAM-INF")
+}
+
+pub fn parse_master(text: &str, base: &str) -> Vec<Variant> {
+    let mut variants = vec![];
+    let mut pending: Option<HashMap<String, String>> = None;
+    for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if let Some(attrs) = line.strip_prefix("#EXT-X-STREAM-INF:") {
+            pending = Some(attributes(attrs));
+        } else if line.starts_with('#') {
+            continue;
+        } else if let Some(attrs) = pending.take() {
+            let resolution = attrs.get("RESOLUTION").and_then(|r| {
+                let (w, h) = r.split_once('x')?;
+                Some((w.parse().ok()?, h.parse().ok()?))
+            });
+            variants.push(Variant {
+                bandwidth: attrs
+                    .get("BANDWIDTH")
+                    .and_then(|b| b.parse().ok())
+                    .unwrap_or(0),
+                resolution,
+                uri: resolve(base, line),
+            });
+        }
+    }
+    variants
+}
+
+pub fn parse_media(text: &str, base: &str) -> CommandResult<MediaPlaylist> {
+    let mut playlist = MediaPlaylist::default();
+    let mut sequence = 0_u64;
+    let mut key: Option<Key> = None;
+    let mut duration = 0_f64;
+    for line in text.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        if let Some(v) = line.strip_prefix("#EXTINF:") {
+            duration = v
+                .split(',')
+                .next()
+                .unwrap_or("")
+                .trim()
+                .parse()
+                .unwrap_or(0_f64);
+        } else if let Some(v) = line.strip_prefix("#EXT-X-MEDIA-SEQUENCE:") {
+            sequence = v.trim().parse().unwrap_or(0);
+        } else if let Some(attrs) = line.strip_prefix("#EXT-X-KEY:") {
+            let attrs = attributes(attrs);
+            key = match attrs.get("METHOD").map(String::as_str) {
+                None | Some("NONE") => None,
+                Some("AES-128") => Some(Key {
+                    uri: resolve(
+                        base,
+                        attrs
+                            .get("URI")
+                            .ok_or_else(|| CommandError::Parse("Key without uri.".to_string()))?,
+                    ),
+                    iv: attrs.get("IV").and_then(|iv| parse_iv(iv)),
+                }),
+                Some(m) => {
+                    return Err(CommandError::Parse(format!(
+                        "Unsupported stream encryption {}.",
+                        m
+                    )))
+                }
+            };
+        } else if let Some(attrs) = line.strip_prefix("#EXT-X-MAP:") {
+            playlist.init = attributes(attrs).get("URI").map(|u| resolve(base, u));
+        } else if !line.starts_with('#') {
+            playlist.segments.push(Segment {
+                uri: resolve(base, line),
+                sequence,
+                duration,
+                key: key.clone(),
+            });
+            sequence += 1;
+        }
+    }
+    Ok(playlist)
+}
+
+/// Downloads keep the whole picture, so "auto" means the best variant.
+pub fn pick(variants: &[Variant], quality: Quality) -> Option<&Variant> {
+    match quality {
+        Quality::Lowest => variants.iter().min_by_key(|v| v.bandwidth),
+        Quality::Auto | Quality::Highest => variants.iter().max_by_key(|v| v.bandwidth),
+    }
+}
+
+fn decrypt(data: &[u8], key: &[u8], iv: &[u8; 16]) -> CommandResult<Vec<u8>> {
+    Aes128CbcDec::new_from_slices(key, iv)
+        .map_err(|_| CommandError::Parse("Invalid stream key.".to_string()))?
+        .decrypt_padded_vec_mut::<Pkcs7>(data)
+        .map_err(|_| CommandError::Parse("Failed to decrypt a segment.".to_string()))
+}
+
+/// Busy or failing servers are worth another try, anything else refused
+/// won't change.
+fn status_error(status: reqwest::StatusCode, url: &str) -> CommandError {
+    let message = format!("{} for {}", status, url);
+    if status.is_server_error() || status == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        CommandError::Network(message)
+    } else {
+        CommandError::NotFound(message)
+    }
+}
+
+async fn get(client: &reqwest::Client, url: &str) -> CommandResult<Vec<u8>> {
+    let res = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| CommandError::Network(e.to_string()))?;
+    if !res.status().is_success() {
+        return Err(status_error(res.status(), url));
+    }
+    Ok(res
+        .bytes()
+        .await
+        .map_err(|e| CommandError::Network(e.to_string()))?
+        .to_vec())
+}
+
+async fn text(client: &reqwest::Client, url: &str) -> CommandResult<String> {
+    String::from_utf8(get(client, url).await?)
+        .map_err(|_| CommandError::Parse(format!("{} is not a playlist.", url)))
+}
+
+async fn fetch_segment(
+    client: &reqwest::Client,
+    segment: &Segment,
+    keys: &HashMap<String, Vec<u8>>,
+) -> CommandResult<Vec<u8>> {
+    let data = get(client, &segment.uri).await?;
+    match &segment.key {
+        Some(key) => decrypt(
+            &data,
+            &keys[&key.uri],
+            &key.iv.unwrap_or_else(|| sequence_iv(segment.sequence)),
+        ),
+        None => Ok(data),
+    }
+}
+
+/// Segments fetched at once. They are still written in order.
+fn concurrency() -> usize {
+    num_cpus::get().clamp(2, 8)
+}
+
+/// The media playlist `url` points to, picking a variant of a master
+/// playlist by `quality`.
+pub async fn media_playlist(
+    client: &reqwest::Client,
+    url: &str,
+    quality: Quality,
+) -> CommandResult<MediaPlaylist> {
+    let body = text(client, url).await?;
+    if !is_master(&body) {
+        return parse_media(&body, url);
+    }
+    let variants = parse_master(&body, url);
+    let variant =
+        pick(&variants, quality).ok_or_else(|| CommandError::not_found("stream variant"))?;
+    parse_media(&text(client, &variant.uri).await?, &variant.uri)
+}
+
+async fn write_segments(
+    client: &reqwest::Client,
+    playlist: &MediaPlaylist,
+    file: &mut File,
+    path: &Path,
+    index: &mut LocalIndex,
+    progress: &mut DownloadProgress,
+    on_progress: &(dyn Fn(&DownloadProgress) + Sync),
+) -> CommandResult<()> {
+    let io_err = |e: std::io::Error| CommandError::Io(e.to_string());
+    let mut keys: HashMap<String, Vec<u8>> = HashMap::new();
+    for key in playlist.segments.iter().filter_map(|s| s.key.as_ref()) {
+        if !keys.contains_key(&key.uri) {
+            keys.insert(key.uri.clone(), get(client, &key.uri).await?);
+        }
+    }
+    if progress.done == 0 {
+        if let Some(init) = &playlist.init {
+            let data = get(client, init).await?;
+            file.write_all(&data).map_err(io_err)?;
+            index.init = Some(data.len() as u64);
+            progress.bytes += data.len() as u64;
+        }
+    }
+    let keys = &keys;
+    let mut segments = stream::iter(
+        playlist
+            .segments
+            .iter()
+            .skip(progress.done)
+            .map(|s| async move {
+                Ok::<_, CommandError>((s.duration, fetch_segment(client, s, keys).await?))
+            }),
+    )
+    .buffered(concurrency());
+    while let Some(segment) = segments.next().await {
+        let written = segment.and_then(|(duration, data)| {
+            file.write_all(&data).map_err(io_err)?;
+            Ok((duration, data.len() as u64))
+        });
+        let (duration, size) = match written {
+            Ok(segment) => segment,
+            Err(e) => {
+                // keep what made it for the next attempt
+                if let Err(e) = index.write(path, false) {
+                    println!("Failed to save the download index. {}", e);
+                }
+                return Err(e);
+            }
+        };
+        index.segments.push((duration, size));
+        progress.done += 1;
+        progress.bytes += size;
+        if progress.done % INDEX_CHECKPOINT == 0 {
+            index.write(path, false)?;
+        }
+        on_progress(progress);
+    }
+    index.write(path, true)
+}
+
+/// Saves `playlist` to `path` as one file, the segments concatenated,
+/// carrying on after `from`. On failure the file is left as its index
+/// describes it, ready to be resumed.
+async fn save(
+    client: &reqwest::Client,
+    playlist: &MediaPlaylist,
+    path: &Path,
+    id: &str,
+    from: Resume,
+    on_progress: &(dyn Fn(&DownloadProgress) + Sync),
+) -> CommandResult<u64> {
+    if playlist.segments.is_empty() {
+        return Err(CommandError::not_found("segment"));
+    }
+    let io_err =
+        |e: std::io::Error| CommandError::Io(format!("Failed to write {}. {}", path.display(), e));
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(io_err)?;
+    }
+    // carry on from the last checkpoint of the index, start over when the
+    // file or its index is gone, or the playlist changed under us
+    let on_disk = path.metadata().map_or(0, |m| m.len());
+    let mut index = LocalIndex::read(path).unwrap_or_default();
+    index.segments.truncate(from.done);
+    let from = Resume {
+        done: index.segments.len(),
+        bytes: index.bytes(),
+    };
+    let from = if from.done > 0 && from.done <= playlist.segments.len() && on_disk >= from.bytes {
+        from
+    } else {
+        index = LocalIndex::default();
+        Resume::default()
+    };
+    let mut file = if from.done == 0 {
+        File::create(path).map_err(io_err)?
+    } else {
+        let file = OpenOptions::new().append(true).open(path).map_err(io_err)?;
+        file.set_len(from.bytes).map_err(io_err)?;
+        file
+    };
+    let mut progress = DownloadProgress {
+        id: id.to_string(),
+        path: path.display().to_string(),
+        done: from.done,
+        total: playlist.segments.len(),
+        bytes: from.bytes,
+    };
+    on_progress(&progress);
+    write_segments(
+        client,
+        playlist,
+        &mut file,
+        path,
+        &mut index,
+        &mut progress,
+        on_progress,
+    )
+    .await
+    .map_err(|e| e.context("Failed to download the stream."))?;
+    Ok(progress.bytes)
+}
+
+/// Saves the HLS stream at `url` next to `path`, named by `container_path`,
+/// carrying on after `from`. Returns the file and its size.
+pub async fn download_from(
+    client: &reqwest::Client,
+    url: &str,
+    quality: Quality,
+    path: &Path,
+    id: &str,
+    from: Resume,
+    on_progress: &(dyn Fn(&DownloadProgress) + Sync),
+) -> CommandResult<(PathBuf, u64)> {
+    let playlist = media_playlist(client, url, quality).await?;
+    let path = container_path(path, &playlist);
+    let bytes = save(client, &playlist, &path, id, from, on_progress).await?;
+    Ok((path, bytes))
+}
+
+/// `download_from` the start, leaving no partial file behind on failure.
+pub async fn download(
+    client: &reqwest::Client,
+    url: &str,
+    quality: Quality,
+    path: &Path,
+    id: &str,
+    on_progress: &(dyn Fn(&DownloadProgress) + Sync),
+) -> CommandResult<(PathBuf, u64)> {
+    let playlist = media_playlist(client, url, quality).await?;
+    let path = container_path(path, &playlist);
+    let res = save(client, &playlist, &path, id, Resume::default(), on_progress).await;
+    if res.is_err() {
+        for file in [path.clone(), LocalIndex::path(&path)] {
+            if file.exists() {
+                if let Err(e) = std::fs::remove_file(&file) {
+                    println!("Failed to remove partial download. {}", e);
+                }
+            }
+        }
+    }
+    Ok((path, res?))
+}
+
+/// `crunchyview download <playlist url> <file> [lowest|highest]`, for any
+/// HLS stream the client can reach (a local static server works too).
+pub async fn cli(args: &[String]) -> CommandResult<()> {
+    let (url, path) = match args {
+        [url, path, ..] => (url, Path::new(path)),
+        _ => {
+            return Err(CommandError::Parse(
+                "Usage: crunchyview download <playlist url> <file> [lowest|highest]".to_string(),
+            ))
+        }
+    };
+    let quality = match args.get(2).map(String::as_str) {
+        Some("lowest") => Quality::Lowest,
+        _ => Quality::Highest,
+    };
+    let bar = ProgressBar::new(0);
+    bar.set_style(
+        ProgressStyle::with_template("{bar:40} {pos}/{len} segments {elapsed}")
+            .unwrap_or_else(|_| ProgressStyle::default_bar()),
+    );
+    let (path, bytes) = download(
+        &reqwest::Client::new(),
+        url,
+        quality,
+        path,
+        url,
+        &|p: &DownloadProgress| {
+            bar.set_length(p.total as u64);
+            bar.set_position(p.done as u64);
+        },
+    )
+    .await?;
+    bar.finish_and_clear();
+    println!("Saved {} bytes to {}.", bytes, path.display());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use aes::cipher::BlockEncryptMut;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    type Aes128CbcEnc = cbc::Encryptor<aes::Aes128>;
+
+    const KEY: [u8; 16] = *b"0123456789abcdef";
+
+    fn encrypt(data: &[u8], iv: &[u8; 16]) -> Vec<u8> {
+        Aes128CbcEnc::new_from_slices(&KEY, iv)
+            .unwrap()
+            .encrypt_padded_vec_mut::<Pkcs7>(data)
+    }
+
+    /// Serves `files` by path, 404 for anything else.
+    fn serve(files: HashMap<String, Vec<u8>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let mut header = String::new();
+                while reader.read_line(&mut header).unwrap() > 2 {
+                    header.clear();
+                }
+                let path = line.split_whitespace().nth(1).unwrap_or("");
+                let (status, body) = match files.get(path) {
+                    Some(body) => (200, body.clone()),
+                    None => (404, vec![]),
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    status,
+                    body.len()
+                )
+                .unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+        base
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "crunchyview-download-{}-{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn resolves_uris() {
+        let base = "https://cdn.example/a/b/master.m3u8?token=1";
+        assert_eq!(resolve(base, "https://other/x.ts"), "https://other/x.ts");
+        assert_eq!(resolve(base, "/root.ts"), "https://cdn.example/root.ts");
+        assert_eq!(
+            resolve(base, "c/seg.ts?x=2"),
+            "https://cdn.example/a/b/c/seg.ts?x=2"
+        );
+        assert_eq!(resolve("master.m3u8", "seg.ts"), "seg.ts");
+    }
+
+    #[test]
+    fn parses_master_playlists() {
+        let text = "#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=500000,RESOLUTION=1280x720,CODECS=\"avc1.4d401f,mp4a.40.2\"
+high/index.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=100000
+https://other.example/low.m3u8
+";
+        assert!(is_master(text));
+        let variants = parse_master(text, "https://cdn.example/master.m3u8");
+        assert_eq!(
+            variants,
+            vec![
+                Variant {
+                    bandwidth: 500000,
+                    resolution: Some((1280, 720)),
+                    uri: "https://cdn.example/high/index.m3u8".to_string(),
+                },
+                Variant {
+                    bandwidth: 100000,
+                    resolution: None,
+                    uri: "https://other.example/low.m3u8".to_string(),
+                },
+            ]
+        );
+        assert_eq!(pick(&variants, Quality::Lowest), Some(&variants[1]));
+        assert_eq!(pick(&variants, Quality::Auto), Some(&variants[0]));
+    }
+
+    #[test]
+    fn parses_media_playlists() {
+        let text = "#EXTM3U
+#EXT-X-MEDIA-SEQUENCE:5
+#EXT-X-MAP:URI=\"init.mp4\"
+#EXT-X-KEY:METHOD=AES-128,URI=\"key.bin\",IV=0x000102030405060708090a0b0c0d0e0f
+#EXTINF:6.006,
+seg5.m4s
+#EXT-X-KEY:METHOD=NONE
+#EXTINF:4.5,
+seg6.m4s
+#EXT-X-ENDLIST
+";
+        let playlist = parse_media(text, "https://cdn.example/v/index.m3u8").unwrap();
+        assert!(!is_master(text));
+        assert_eq!(
+            playlist.init.as_deref(),
+            Some("https://cdn.example/v/init.mp4")
+        );
+        assert_eq!(playlist.extension(), "mp4");
+        let iv: Vec<u8> = (0..16).collect();
+        assert_eq!(
+            playlist.segments,
+            vec![
+                Segment {
+                    uri: "https://cdn.example/v/seg5.m4s".to_string(),
+                    sequence: 5,
+                    duration: 6.006,
+                    key: Some(Key {
+                        uri: "https://cdn.example/v/key.bin".to_string(),
+                        iv: Some(iv.try_into().unwrap()),
+                    }),
+                },
+                Segment {
+                    uri: "https://cdn.example/v/seg6.m4s".to_string(),
+                    sequence: 6,
+                    duration: 4.5,
+                    key: None,
+                },
+            ]
+        );
+
+        let sample_aes = "#EXT-X-KEY:METHOD=SAMPLE-AES,URI=\"k\"\n#EXTINF:1,\ns.ts\n";
+        assert!(matches!(
+            parse_media(sample_aes, "https://cdn.example/"),
+            Err(CommandError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn decrypts_segments() {
+        let data = b"not quite a transport stream".to_vec();
+        let iv = sequence_iv(7);
+        assert_eq!(iv[15], 7);
+        assert_eq!(decrypt(&encrypt(&data, &iv), &KEY, &iv).unwrap(), data);
+        assert!(decrypt(&encrypt(&data, &iv), b"fedcba9876543210", &iv).is_err());
+        assert!(decrypt(&data, &KEY[..8], &iv).is_err());
+    }
+
+    #[test]
+    fn only_busy_servers_are_retried() {
+        let url = "https://cdn.example/seg.ts";
+        for status in [429, 500, 503] {
+            let status = reqwest::StatusCode::from_u16(status).unwrap();
+            assert!(matches!(
+                status_error(status, url),
+                CommandError::Network(_)
+            ));
+        }
+        for status in [403, 404, 410] {
+            let status = reqwest::StatusCode::from_u16(status).unwrap();
+            assert!(matches!(
+                status_error(status, url),
+                CommandError::NotFound(_)
+            ));
+        }
+    }
+
+    #[test]
+    fn index_round_trip() {
+        let video = temp_dir("index").join("episode.mp4");
+        let index = LocalIndex {
+            init: Some(1200),
+            segments: vec![(6.006, 1000), (4.5, 800)],
+        };
+        index.write(&video, true).unwrap();
+        assert_eq!(LocalIndex::read(&video), Some(index.clone()));
+        assert_eq!(index.bytes(), 3000);
+        let text = index.render("episode.mp4", true);
+        assert!(text.contains("#EXT-X-MAP:URI=\"episode.mp4\",BYTERANGE=\"1200@0\""));
+        assert!(text.contains("#EXT-X-BYTERANGE:800@2200"));
+        assert!(text.ends_with("#EXT-X-ENDLIST\n"));
+        assert!(!index.render("episode.mp4", false).contains("ENDLIST"));
+    }
+
+    #[test]
+    fn container_follows_the_stream() {
+        let ts = MediaPlaylist::default();
+        let fmp4 = MediaPlaylist {
+            init: Some("init.mp4".to_string()),
+            segments: vec![],
+        };
+        let path = Path::new("/d/ep.ts");
+        assert_eq!(container_path(path, &ts), Path::new("/d/ep.ts"));
+        assert_eq!(container_path(path, &fmp4), Path::new("/d/ep.mp4"));
+        assert_eq!(
+            container_path(Path::new("/d/ep"), &fmp4),
+            Path::new("/d/ep.mp4")
+        );
+        assert_eq!(
+            container_path(Path::new("/d/ep.mkv"), &fmp4),
+            Path::new("/d/ep.mkv")
+        );
+    }
+
+    #[tokio::test]
+    async fn downloads_from_a_local_server() {
+        let plain: Vec<Vec<u8>> = (0..3_u8).map(|i| vec![i; 100 + i as usize]).collect();
+        let mut files = HashMap::new();
+        files.insert(
+            "/master.m3u8".to_string(),
+            b"#EXTM3U
+#EXT-X-STREAM-INF:BANDWIDTH=100
+low.m3u8
+#EXT-X-STREAM-INF:BANDWIDTH=900
+hls/high.m3u8
+"
+            .to_vec(),
+        );
+        // the first segment with its own iv, the others with their sequence
+        files.insert(
+            "/hls/high.m3u8".to_string(),
+            b"#EXTM3U
+#EXT-X-MEDIA-SEQUENCE:1
+#EXT-X-KEY:METHOD=AES-128,URI=\"/key.bin\",IV=0x0f0e0d0c0b0a09080706050403020100
+#EXTINF:6,
+seg1.ts
+#EXT-X-KEY:METHOD=AES-128,URI=\"/key.bin\"
+#EXTINF:6,
+seg2.ts
+#EXTINF:3.5,
+seg3.ts
+#EXT-X-ENDLIST
+"
+            .to_vec(),
+        );
+        files.insert("/key.bin".to_string(), KEY.to_vec());
+        let own_iv = [15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0];
+        files.insert("/hls/seg1.ts".to_string(), encrypt(&plain[0], &own_iv));
+        files.insert(
+            "/hls/seg2.ts".to_string(),
+            encrypt(&plain[1], &sequence_iv(2)),
+        );
+        files.insert(
+            "/hls/seg3.ts".to_string(),
+            encrypt(&plain[2], &sequence_iv(3)),
+        );
+        files.insert(
+            "/fmp4.m3u8".to_string(),
+            b"#EXTM3U
+#EXT-X-MAP:URI=\"init.mp4\"
+#EXTINF:2,
+a.m4s
+#EXTINF:2,
+b.m4s
+"
+            .to_vec(),
+        );
+        files.insert("/init.mp4".to_string(), b"init".to_vec());
+        files.insert("/a.m4s".to_string(), b"first".to_vec());
+        files.insert("/b.m4s".to_string(), b"second".to_vec());
+        files.insert(
+            "/missing.m3u8".to_string(),
+            b"#EXTM3U\n#EXTINF:2,\na.m4s\n#EXTINF:2,\ngone.m4s\n".to_vec(),
+        );
+        let base = serve(files);
+        let client = reqwest::Client::new();
+        let dir = temp_dir("e2e");
+        let whole: Vec<u8> = plain.concat();
+
+        let reports = std::sync::Mutex::new(vec![]);
+        let (path, bytes) = download(
+            &client,
+            &format!("{}/master.m3u8", base),
+            Quality::Highest,
+            &dir.join("ep.ts"),
+            "ep",
+            &|p: &DownloadProgress| reports.lock().unwrap().push(p.clone()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(path, dir.join("ep.ts"));
+        assert_eq!(bytes, whole.len() as u64);
+        assert_eq!(std::fs::read(&path).unwrap(), whole);
+        let index = LocalIndex::read(&path).unwrap();
+        assert_eq!(index.segments, vec![(6_f64, 100), (6_f64, 101), (3.5, 102)]);
+        let reports = reports.into_inner().unwrap();
+        assert_eq!(reports.len(), 4);
+        assert_eq!(reports[3].done, 3);
+        assert_eq!(reports[3].path, path.display().to_string());
+
+        // resuming after the first segment fetches the other two again
+        let reports = std::sync::Mutex::new(vec![]);
+        let (_, bytes) = download_from(
+            &client,
+            &format!("{}/master.m3u8", base),
+            Quality::Highest,
+            &path,
+            "ep",
+            Resume {
+                done: 1,
+                bytes: 100,
+            },
+            &|p: &DownloadProgress| reports.lock().unwrap().push(p.done),
+        )
+        .await
+        .unwrap();
+        assert_eq!(reports.into_inner().unwrap(), vec![1, 2, 3]);
+        assert_eq!(bytes, whole.len() as u64);
+        assert_eq!(std::fs::read(&path).unwrap(), whole);
+
+        let (path, _) = download(
+            &client,
+            &format!("{}/fmp4.m3u8", base),
+            Quality::Highest,
+            &dir.join("ep2.ts"),
+            "ep2",
+            &|_: &DownloadProgress| {},
+        )
+        .await
+        .unwrap();
+        assert_eq!(path, dir.join("ep2.mp4"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"initfirstsecond");
+        assert_eq!(LocalIndex::read(&path).unwrap().init, Some(4));
+
+        // a failed download leaves nothing behind
+        let res = download(
+            &client,
+            &format!("{}/missing.m3u8", base),
+            Quality::Highest,
+            &dir.join("ep3.ts"),
+            "ep3",
+            &|_: &DownloadProgress| {},
+        )
+        .await;
+        assert!(matches!(res, Err(CommandError::NotFound(_))));
+        assert!(!dir.join("ep3.ts").exists());
+        assert!(!dir.join("ep3.m3u8").exists());
+    }
+}
@@ -0,0 +1,110 @@
+use crunchyroll_rs::error::CrunchyrollError;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+/// What every command returns on failure. It is serialized as
+/// `{"kind": "...", "message": "..."}` so the frontend can branch on `kind`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", content = "message")]
+pub enum CommandError {
+    NotLoggedIn,
+    /// Wrong credentials, expired tokens, locked vaults...
+    Auth(String),
+    NotFound(String),
+    Network(String),
+    Geoblocked(String),
+    PremiumRequired(String),
+    /// An answer (or a subtitle file) that could not be understood.
+    Parse(String),
+    /// Reading or writing local files failed.
+    Io(String),
+}
+
+pub type CommandResult<T> = Result<T, CommandError>;
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::NotLoggedIn => write!(f, "You are not logged in."),
+            CommandError::Auth(m) => write!(f, "Authentication failed. {}", m),
+            CommandError::NotFound(m) => write!(f, "Not found. {}", m),
+            CommandError::Network(m) => write!(f, "Network error. {}", m),
+            CommandError::Geoblocked(m) => write!(f, "Not available in your region. {}", m),
+            CommandError::PremiumRequired(m) => write!(f, "Premium is required. {}", m),
+            CommandError::Parse(m) => write!(f, "Failed to parse. {}", m),
+            CommandError::Io(m) => write!(f, "Failed to access local files. {}", m),
+        }
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+impl CommandError {
+    pub fn not_found(what: &str) -> CommandError {
+        CommandError::NotFound(format!("No {} found.", what))
+    }
+
+    /// Prefixes the message with what was being done, keeping the kind.
+    pub fn context(self, what: &str) -> CommandError {
+        let wrap = |m: String| format!("{} {}", what, m);
+        match self {
+            CommandError::NotLoggedIn => CommandError::NotLoggedIn,
+            CommandError::Auth(m) => CommandError::Auth(wrap(m)),
+            CommandError::NotFound(m) => CommandError::NotFound(wrap(m)),
+            CommandError::Network(m) => CommandError::Network(wrap(m)),
+            CommandError::Geoblocked(m) => CommandError::Geoblocked(wrap(m)),
+            CommandError::PremiumRequired(m) => CommandError::PremiumRequired(wrap(m)),
+            CommandError::Parse(m) => CommandError::Parse(wrap(m)),
+            CommandError::Io(m) => CommandError::Io(wrap(m)),
+        }
+    }
+
+    /// For a refused stream request, tells a missing subscription from a
+    /// region block with what the episode says about itself.
+    pub fn refused(e: CrunchyrollError, premium_only: bool) -> CommandError {
+        match status(&e) {
+            Some(403) if premium_only => CommandError::PremiumRequired(e.to_string()),
+            Some(403) => CommandError::Geoblocked(e.to_string()),
+            _ => e.into(),
+        }
+    }
+}
+
+/// The http status the api answered a failed request with, if it answered.
+fn status(e: &CrunchyrollError) -> Option<u16> {
+    match e {
+        CrunchyrollError::Request(context) => context.value.as_ref().map(|s| s.as_u16()),
+        _ => None,
+    }
+}
+
+impl From<CrunchyrollError> for CommandError {
+    fn from(e: CrunchyrollError) -> Self {
+        let message = e.to_string();
+        match (&e, status(&e)) {
+            (CrunchyrollError::Authentication(_), _) | (_, Some(401 | 403)) => {
+                CommandError::Auth(message)
+            }
+            (CrunchyrollError::Input(_), _) | (_, Some(404 | 410)) => {
+                CommandError::NotFound(message)
+            }
+            (CrunchyrollError::Decode(_) | CrunchyrollError::Internal(_), _) => {
+                CommandError::Parse(message)
+            }
+            // no answer, rate limits, server errors and bot protection, worth retrying
+            _ => CommandError::Network(message),
+        }
+    }
+}
+
+impl From<anyhow::Error> for CommandError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast::<CrunchyrollError>() {
+            Ok(e) => e.into(),
+            Err(e) => match e.downcast::<CommandError>() {
+                Ok(e) => e,
+                Err(e) => CommandError::Io(e.to_string()),
+            },
+        }
+    }
+}
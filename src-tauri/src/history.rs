@@ -0,0 +1,155 @@
+use crate::storage;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How many episodes are remembered before the oldest ones are dropped.
+const MAX_ENTRIES: usize = 500;
+
+#[derive(Serialize, Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct HistoryEntry {
+    pub episode_id: String,
+    pub series_id: String,
+    pub title: String,
+    pub series_title: String,
+    /// Unix time of the last update, in seconds.
+    pub timestamp: u64,
+    /// Playback position in seconds.
+    pub position: f64,
+    pub duration: f64,
+}
+
+/// Local watch history, most recently watched first, backed by a json file
+/// in the data dir.
+pub struct History {
+    path: PathBuf,
+    entries: Vec<HistoryEntry>,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::open(storage::data_dir().join("history.json"))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl History {
+    /// An empty history saved to `path`.
+    pub fn open(path: PathBuf) -> History {
+        History {
+            path,
+            entries: vec![],
+        }
+    }
+
+    pub fn load() -> History {
+        let mut history = History::default();
+        history.entries = storage::load_json(&history.path).unwrap_or_default();
+        history
+    }
+
+    fn save(&self) -> Result<()> {
+        storage::save_json(&self.path, &self.entries)
+    }
+
+    /// Moves the entry for `episode_id` to the front, creating it if needed.
+    fn touch(&mut self, episode_id: &str) -> &mut HistoryEntry {
+        let entry = match self.entries.iter().position(|e| e.episode_id == episode_id) {
+            Some(i) => self.entries.remove(i),
+            None => HistoryEntry {
+                episode_id: episode_id.to_string(),
+                ..Default::default()
+            },
+        };
+        self.entries.insert(0, entry);
+        self.entries.truncate(MAX_ENTRIES);
+        let first = &mut self.entries[0];
+        first.timestamp = now();
+        first
+    }
+
+    /// Records that an episode was opened, keeping any saved position.
+    pub fn viewed(
+        &mut self,
+        episode_id: &str,
+        series_id: &str,
+        title: &str,
+        series_title: &str,
+    ) -> Result<()> {
+        let entry = self.touch(episode_id);
+        entry.series_id = series_id.to_string();
+        entry.title = title.to_string();
+        entry.series_title = series_title.to_string();
+        self.save()
+    }
+
+    pub fn progress(&mut self, episode_id: &str, position: f64, duration: f64) -> Result<()> {
+        let entry = self.touch(episode_id);
+        entry.position = position;
+        if duration > 0_f64 {
+            entry.duration = duration;
+        }
+        self.save()
+    }
+
+    pub fn get(&self, episode_id: &str) -> Option<&HistoryEntry> {
+        self.entries.iter().find(|e| e.episode_id == episode_id)
+    }
+
+    /// Takes in entries coming from elsewhere (e.g. the account), keeping
+    /// their timestamps and the newest first order.
+    pub fn merge(&mut self, incoming: Vec<HistoryEntry>) -> Result<()> {
+        for mut entry in incoming {
+            if let Some(i) = self
+                .entries
+                .iter()
+                .position(|e| e.episode_id == entry.episode_id)
+            {
+                let old = self.entries.remove(i);
+                if entry.series_id.is_empty() {
+                    entry.series_id = old.series_id;
+                    entry.title = old.title;
+                    entry.series_title = old.series_title;
+                }
+                if entry.duration <= 0_f64 {
+                    entry.duration = old.duration;
+                }
+            }
+            let at = self
+                .entries
+                .iter()
+                .position(|e| e.timestamp < entry.timestamp)
+                .unwrap_or(self.entries.len());
+            self.entries.insert(at, entry);
+        }
+        self.entries.truncate(MAX_ENTRIES);
+        self.save()
+    }
+
+    /// Where to resume `episode_id`, if it was left somewhere in the middle.
+    pub fn playhead(&self, episode_id: &str) -> Option<f64> {
+        let entry = self.get(episode_id)?;
+        let finished = entry.duration > 0_f64 && entry.position > entry.duration * 0.95;
+        if entry.position < 5_f64 || finished {
+            None
+        } else {
+            Some(entry.position)
+        }
+    }
+
+    pub fn recent(&self, limit: usize) -> Vec<HistoryEntry> {
+        self.entries.iter().take(limit).cloned().collect()
+    }
+
+    pub fn clear(&mut self) -> Result<()> {
+        self.entries.clear();
+        self.save()
+    }
+}
@@ -0,0 +1,317 @@
+use crate::cache::DiskCache;
+use crate::error::{CommandError, CommandResult};
+use crate::storage;
+use crate::ViewerContext;
+use crunchyroll_rs::common::Image;
+use crunchyroll_rs::media::Images;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::Mutex;
+use tauri::http::{Request, Response, ResponseBuilder};
+use tauri::{AppHandle, Manager};
+
+/// One size of an image.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImageVariant {
+    pub width: u32,
+    pub height: u32,
+    pub url: String,
+}
+
+/// Every size available for each kind of artwork, smallest first, so the
+/// frontend can build a `srcset` and pick what fits the layout.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImageSet {
+    pub thumbnail: Vec<ImageVariant>,
+    pub poster_tall: Vec<ImageVariant>,
+    pub poster_wide: Vec<ImageVariant>,
+    pub promo_image: Vec<ImageVariant>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageKind {
+    Thumbnail,
+    PosterTall,
+    PosterWide,
+    PromoImage,
+}
+
+/// Fallback order for series artwork.
+pub const SERIES_ORDER: [ImageKind; 4] = [
+    ImageKind::Thumbnail,
+    ImageKind::PosterWide,
+    ImageKind::PromoImage,
+    ImageKind::PosterTall,
+];
+
+/// Fallback order for episode artwork.
+pub const EPISODE_ORDER: [ImageKind; 4] = [
+    ImageKind::Thumbnail,
+    ImageKind::PosterTall,
+    ImageKind::PosterWide,
+    ImageKind::PromoImage,
+];
+
+/// The api nests sizes in a list per picture; only the first picture is used.
+fn variants(images: Option<Vec<Vec<Image>>>) -> Vec<ImageVariant> {
+    let mut sizes: Vec<ImageVariant> = images
+        .and_then(|pics| pics.into_iter().find(|p| !p.is_empty()))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|i| ImageVariant {
+            width: i.width,
+            height: i.height,
+            url: i.source,
+        })
+        .collect();
+    sizes.sort_by(|l, r| l.width.cmp(&r.width).then(l.height.cmp(&r.height)));
+    sizes.dedup_by(|l, r| l.url == r.url);
+    sizes
+}
+
+impl ImageSet {
+    pub fn from_images(images: Option<Images>) -> ImageSet {
+        match images {
+            Some(i) => ImageSet {
+                thumbnail: variants(i.thumbnail),
+                poster_tall: variants(i.poster_tall),
+                poster_wide: variants(i.poster_wide),
+                promo_image: variants(i.promo_image),
+            },
+            None => ImageSet::default(),
+        }
+    }
+
+    pub fn kind(&self, kind: ImageKind) -> &[ImageVariant] {
+        match kind {
+            ImageKind::Thumbnail => &self.thumbnail,
+            ImageKind::PosterTall => &self.poster_tall,
+            ImageKind::PosterWide => &self.poster_wide,
+            ImageKind::PromoImage => &self.promo_image,
+        }
+    }
+
+    /// Keeps only `kinds`, e.g. the tall poster of a series for its seasons.
+    pub fn only(&self, kinds: &[ImageKind]) -> ImageSet {
+        let pick = |kind| {
+            if kinds.contains(&kind) {
+                self.kind(kind).to_vec()
+            } else {
+                vec![]
+            }
+        };
+        ImageSet {
+            thumbnail: pick(ImageKind::Thumbnail),
+            poster_tall: pick(ImageKind::PosterTall),
+            poster_wide: pick(ImageKind::PosterWide),
+            promo_image: pick(ImageKind::PromoImage),
+        }
+    }
+
+    /// The smallest image at least `width` wide, or the largest there is, of
+    /// the first kind with any. What the frontend's `ImageSet::pick` takes.
+    pub fn pick(&self, width: u32) -> Option<&ImageVariant> {
+        let sizes = SERIES_ORDER
+            .iter()
+            .map(|k| self.kind(*k))
+            .find(|v| !v.is_empty())?;
+        sizes
+            .iter()
+            .find(|v| v.width >= width)
+            .or_else(|| sizes.last())
+    }
+
+    /// The largest image of the first kind in `order` that has any.
+    pub fn largest(&self, order: &[ImageKind]) -> Option<String> {
+        order
+            .iter()
+            .find_map(|k| self.kind(*k).last())
+            .map(|v| v.url.clone())
+    }
+}
+
+pub const IMAGE_SCHEME: &str = "crunchyimg";
+
+/// Rough rendered widths, in step with the frontend's `GRID_IMAGE_WIDTH`
+/// and `BANNER_IMAGE_WIDTH`.
+pub const GRID_WIDTH: u32 = 320;
+pub const BANNER_WIDTH: u32 = 1280;
+
+/// Artwork fetched through `crunchyimg://`, kept between runs.
+pub struct ImageCache {
+    disk: Mutex<DiskCache>,
+    /// Urls being fetched in the background.
+    fetching: Mutex<HashSet<String>>,
+}
+
+impl ImageCache {
+    pub fn open(max_bytes: u64) -> ImageCache {
+        ImageCache {
+            disk: Mutex::new(DiskCache::open(
+                storage::cache_dir().join("images"),
+                max_bytes,
+            )),
+            fetching: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Marks `url` as being fetched, false when it already was.
+    fn start_fetch(&self, url: &str) -> bool {
+        match self.fetching.lock() {
+            Ok(mut fetching) => fetching.insert(url.to_string()),
+            Err(_) => false,
+        }
+    }
+
+    fn end_fetch(&self, url: &str) {
+        if let Ok(mut fetching) = self.fetching.lock() {
+            fetching.remove(url);
+        }
+    }
+
+    fn contains(&self, url: &str) -> bool {
+        self.disk.lock().map_or(false, |disk| disk.contains(url))
+    }
+
+    pub fn resize(&self, max_bytes: u64) -> CommandResult<()> {
+        self.disk
+            .lock()
+            .map_err(|e| CommandError::Io(e.to_string()))?
+            .set_max_bytes(max_bytes)
+            .map_err(CommandError::from)
+    }
+
+    pub fn clear(&self) -> CommandResult<()> {
+        self.disk
+            .lock()
+            .map_err(|e| CommandError::Io(e.to_string()))?
+            .clear()
+            .map_err(CommandError::from)
+    }
+}
+
+/// Guesses the type from the first bytes, cached files don't keep headers.
+fn mimetype(data: &[u8]) -> &'static str {
+    if data.starts_with(&[0xff, 0xd8, 0xff]) {
+        "image/jpeg"
+    } else if data.starts_with(b"\x89PNG") {
+        "image/png"
+    } else if data.len() > 12 && &data[8..12] == b"WEBP" {
+        "image/webp"
+    } else if data.starts_with(b"GIF8") {
+        "image/gif"
+    } else {
+        "application/octet-stream"
+    }
+}
+
+/// The cdn url inside `crunchyimg://localhost/<url>` (or
+/// `https://crunchyimg.localhost/<url>` on windows), as built by the
+/// frontend's `convertFileSrc`.
+fn source_url(uri: &str) -> Option<String> {
+    let (_, encoded) = uri.split_once("localhost/")?;
+    let url = percent_decode_str(encoded).decode_utf8().ok()?.to_string();
+    if url.starts_with("https://") {
+        Some(url)
+    } else {
+        None
+    }
+}
+
+async fn fetch(app: &AppHandle, url: &str) -> CommandResult<Vec<u8>> {
+    let cr = app
+        .state::<ViewerContext>()
+        .session
+        .lock()
+        .await
+        .clone()
+        .ok_or(CommandError::NotLoggedIn)?;
+    let res = cr
+        .client()
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| CommandError::Network(e.to_string()))?;
+    if !res.status().is_success() {
+        return Err(CommandError::NotFound(format!(
+            "{} for {}",
+            res.status(),
+            url
+        )));
+    }
+    Ok(res
+        .bytes()
+        .await
+        .map_err(|e| CommandError::Network(e.to_string()))?
+        .to_vec())
+}
+
+/// Fills the cache with `url` on the async runtime, once at a time per url.
+fn prefetch(app: &AppHandle, url: String) {
+    if !app.state::<ImageCache>().start_fetch(&url) {
+        return;
+    }
+    let app = app.clone();
+    tauri::async_runtime::spawn(async move {
+        let cache = app.state::<ImageCache>();
+        match fetch(&app, &url).await {
+            Ok(data) => {
+                let res = match cache.disk.lock() {
+                    Ok(mut disk) => disk.put(&url, &data),
+                    Err(e) => Err(anyhow::anyhow!("{}", e)),
+                };
+                if let Err(e) = res {
+                    println!("Failed to cache {}. {}", url, e);
+                }
+            }
+            Err(e) => println!("Failed to fetch {}. {}", url, e),
+        }
+        cache.end_fetch(&url);
+    });
+}
+
+/// Starts caching what the frontend will ask for when it shows `sets`
+/// `width` css pixels wide, at 1x and 2x.
+pub fn warm<'a>(app: &AppHandle, sets: impl IntoIterator<Item = &'a ImageSet>, width: u32) {
+    let cache = app.state::<ImageCache>();
+    for set in sets {
+        for variant in [set.pick(width), set.pick(width * 2)].into_iter().flatten() {
+            if !cache.contains(&variant.url) {
+                prefetch(app, variant.url.clone());
+            }
+        }
+    }
+}
+
+/// Handler for the `crunchyimg` scheme: answers from the disk cache, which
+/// the commands returning images fill with `warm`. The handler runs on the
+/// webview's thread, so a miss never waits for the cdn: it gets a 503 while
+/// the image is cached in the background for the next time.
+pub fn serve(app: &AppHandle, request: &Request) -> Result<Response, Box<dyn std::error::Error>> {
+    let url = match source_url(request.uri()) {
+        Some(url) => url,
+        None => return ResponseBuilder::new().status(400).body(vec![]),
+    };
+    let cached = app
+        .state::<ImageCache>()
+        .disk
+        .lock()
+        .map_err(|e| e.to_string())?
+        .get(&url);
+    match cached {
+        Some(data) => ResponseBuilder::new()
+            .status(200)
+            .mimetype(mimetype(&data))
+            .header("Cache-Control", "max-age=86400")
+            .body(data),
+        None => {
+            prefetch(app, url);
+            ResponseBuilder::new()
+                .status(503)
+                .header("Retry-After", "1")
+                .header("Cache-Control", "no-store")
+                .body(vec![])
+        }
+    }
+}
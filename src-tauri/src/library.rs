@@ -0,0 +1,400 @@
+use crate::download::LocalIndex;
+use crate::settings::SubtitleKind;
+use crate::storage;
+use crate::subs::{self, SubtitleFormat};
+use crate::ViewerContext;
+use anyhow::Result;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tauri::http::{Request, Response, ResponseBuilder};
+use tauri::{AppHandle, Manager};
+
+pub const LOCAL_SCHEME: &str = "crunchylocal";
+
+/// Most bytes sent for an open ended range, the player asks again.
+const MAX_CHUNK: u64 = 4 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct LocalSubtitle {
+    pub locale: String,
+    /// Entries saved before captions could be downloaded are subtitles.
+    #[serde(default)]
+    pub kind: SubtitleKind,
+    pub path: String,
+}
+
+/// A finished download and what is needed to play it without a session.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct LibraryEntry {
+    pub ep_id: String,
+    pub title: String,
+    pub series_id: String,
+    pub series_title: String,
+    pub number: i32,
+    pub desc: String,
+    /// The video, its index sits next to it.
+    pub path: String,
+    pub subtitles: Vec<LocalSubtitle>,
+    pub bytes: u64,
+    /// Unix time, in seconds.
+    pub added: u64,
+}
+
+impl LibraryEntry {
+    fn is_on_disk(&self) -> bool {
+        let video = Path::new(&self.path);
+        video.exists() && LocalIndex::path(video).exists()
+    }
+
+    pub fn subtitle(&self, locale: &str, kind: SubtitleKind) -> Option<PathBuf> {
+        self.subtitles
+            .iter()
+            .find(|s| s.locale == locale && s.kind == kind)
+            .map(|s| PathBuf::from(&s.path))
+    }
+}
+
+/// Downloaded episodes, backed by a json file in the data dir. Entries whose
+/// files were removed by hand are dropped on load.
+pub struct Library {
+    path: PathBuf,
+    entries: Vec<LibraryEntry>,
+}
+
+impl Default for Library {
+    fn default() -> Self {
+        Library {
+            path: storage::data_dir().join("library.json"),
+            entries: vec![],
+        }
+    }
+}
+
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl Library {
+    pub fn load() -> Library {
+        Library::open(Library::default().path)
+    }
+
+    pub fn open(path: PathBuf) -> Library {
+        let entries: Vec<LibraryEntry> = storage::load_json(&path).unwrap_or_default();
+        Library {
+            path,
+            entries: entries.into_iter().filter(|e| e.is_on_disk()).collect(),
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        storage::save_json(&self.path, &self.entries)
+    }
+
+    /// Grouped by series, in episode order.
+    pub fn entries(&self) -> Vec<LibraryEntry> {
+        let mut entries = self.entries.clone();
+        entries.sort_by(|a, b| {
+            a.series_title
+                .cmp(&b.series_title)
+                .then(a.number.cmp(&b.number))
+        });
+        entries
+    }
+
+    pub fn get(&self, ep_id: &str) -> Option<&LibraryEntry> {
+        self.entries.iter().find(|e| e.ep_id == ep_id)
+    }
+
+    /// The downloaded episode following `entry` in its series.
+    pub fn next(&self, entry: &LibraryEntry) -> Option<&LibraryEntry> {
+        self.entries
+            .iter()
+            .find(|e| e.series_id == entry.series_id && e.number == entry.number + 1)
+    }
+
+    /// Adds `entry`, replacing an older download of the same episode.
+    pub fn add(&mut self, entry: LibraryEntry) -> Result<()> {
+        self.entries.retain(|e| e.ep_id != entry.ep_id);
+        self.entries.push(entry);
+        self.save()
+    }
+
+    /// Forgets an episode and deletes its files.
+    pub fn remove(&mut self, ep_id: &str) -> Result<Option<LibraryEntry>> {
+        let index = match self.entries.iter().position(|e| e.ep_id == ep_id) {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        let entry = self.entries.remove(index);
+        let video = PathBuf::from(&entry.path);
+        let index_file = LocalIndex::path(&video);
+        let files = [video, index_file]
+            .into_iter()
+            .chain(entry.subtitles.iter().map(|s| PathBuf::from(&s.path)));
+        for file in files.filter(|f| f.exists()) {
+            std::fs::remove_file(&file)?;
+        }
+        self.save()?;
+        Ok(Some(entry))
+    }
+}
+
+/// `bytes=start-end`, `bytes=start-` or `bytes=-suffix` as an inclusive
+/// range within `len`. No header reads like `bytes=0-`.
+fn byte_range(header: Option<&str>, len: u64) -> Option<(u64, u64)> {
+    if len == 0 {
+        return None;
+    }
+    let (start, end) = match header.and_then(|h| h.trim().strip_prefix("bytes=")) {
+        Some(spec) => {
+            let (start, end) = spec.split(',').next()?.split_once('-')?;
+            match (start.trim(), end.trim()) {
+                ("", suffix) => (len.saturating_sub(suffix.parse().ok()?), len - 1),
+                (start, "") => {
+                    let start: u64 = start.parse().ok()?;
+                    (start, (start + MAX_CHUNK - 1).min(len - 1))
+                }
+                (start, end) => (start.parse().ok()?, end.parse::<u64>().ok()?.min(len - 1)),
+            }
+        }
+        None => (0, (MAX_CHUNK - 1).min(len - 1)),
+    };
+    if start > end {
+        None
+    } else {
+        Some((start, end))
+    }
+}
+
+fn read_range(path: &Path, start: u64, end: u64) -> std::io::Result<Vec<u8>> {
+    let mut file = File::open(path)?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut data = vec![0u8; (end - start + 1) as usize];
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// The route extension and mimetype of a downloaded video.
+fn container(video: &Path) -> (&'static str, &'static str) {
+    match video.extension().and_then(|e| e.to_str()) {
+        Some("mp4") => ("mp4", "video/mp4"),
+        _ => ("ts", "video/mp2t"),
+    }
+}
+
+fn serve_video(path: &Path, range: Option<&str>) -> Result<Response, Box<dyn std::error::Error>> {
+    let len = path.metadata()?.len();
+    let (_, mimetype) = container(path);
+    let (start, end) = match byte_range(range, len) {
+        Some(r) => r,
+        None => {
+            return ResponseBuilder::new()
+                .status(416)
+                .header("Content-Range", format!("bytes */{}", len))
+                .body(vec![])
+        }
+    };
+    let builder = ResponseBuilder::new()
+        .mimetype(mimetype)
+        .header("Accept-Ranges", "bytes");
+    // never the whole of a big file at once, the player asks for the rest
+    let builder = if range.is_none() && end + 1 == len {
+        builder.status(200)
+    } else {
+        builder
+            .status(206)
+            .header("Content-Range", format!("bytes {}-{}/{}", start, end, len))
+    };
+    builder.body(read_range(path, start, end)?)
+}
+
+/// Handler for the `crunchylocal` scheme. Downloads are served flat, as
+/// built by the frontend's `convertFileSrc`:
+///
+/// - `<ep_id>.m3u8`, the index the player loads
+/// - `<ep_id>.ts` or `<ep_id>.mp4`, the video, honouring range requests
+/// - `<ep_id>.<locale>.<ass|srt|vtt>`, a subtitle track converted on the fly
+///
+/// Nothing here needs a session.
+pub fn serve(app: &AppHandle, request: &Request) -> Result<Response, Box<dyn std::error::Error>> {
+    let not_found = || ResponseBuilder::new().status(404).body(vec![]);
+    let name = match request.uri().split_once("localhost/") {
+        Some((_, encoded)) => percent_decode_str(encoded.split('?').next().unwrap_or(""))
+            .decode_utf8()?
+            .to_string(),
+        None => return ResponseBuilder::new().status(400).body(vec![]),
+    };
+    let (ep_id, rest) = match name.split_once('.') {
+        Some(parts) => parts,
+        None => return not_found(),
+    };
+    let entry = match app.state::<ViewerContext>().library()?.get(ep_id) {
+        Some(entry) => entry.clone(),
+        None => return not_found(),
+    };
+    let video = PathBuf::from(&entry.path);
+    let (ext, _) = container(&video);
+    match rest {
+        "m3u8" => match LocalIndex::read(&video) {
+            Some(index) => ResponseBuilder::new()
+                .status(200)
+                .mimetype("application/vnd.apple.mpegurl")
+                .body(
+                    index
+                        .render(&format!("{}.{}", ep_id, ext), true)
+                        .into_bytes(),
+                ),
+            None => not_found(),
+        },
+        rest if rest == ext => {
+            let range = request.headers().get("range").and_then(|v| v.to_str().ok());
+            serve_video(&video, range)
+        }
+        track => {
+            // `<locale>[.cc].<ext>`, see `subs::track_name`
+            let (name, ext) = match track.rsplit_once('.') {
+                Some(parts) => parts,
+                None => return not_found(),
+            };
+            let (locale, kind) = subs::parse_track_name(name);
+            let format = match SubtitleFormat::from_path(Path::new(track)) {
+                Some(format) => format,
+                None => return not_found(),
+            };
+            let path = match entry.subtitle(locale, kind) {
+                Some(path) => path,
+                None => return not_found(),
+            };
+            let mimetype = if ext == "vtt" {
+                "text/vtt"
+            } else {
+                "text/plain"
+            };
+            ResponseBuilder::new()
+                .status(200)
+                .mimetype(mimetype)
+                .body(subs::read_local(&path, format)?.into_bytes())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "crunchyview-library-{}-{}",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// An entry whose video, index and subtitle exist in `dir`.
+    fn entry(dir: &Path, ep_id: &str, series_id: &str, number: i32) -> LibraryEntry {
+        let video = dir.join(format!("{}.ts", ep_id));
+        let subtitle = dir.join(format!("{}.en-US.ass", ep_id));
+        for file in [&video, &LocalIndex::path(&video), &subtitle] {
+            std::fs::write(file, b"data").unwrap();
+        }
+        LibraryEntry {
+            ep_id: ep_id.to_string(),
+            series_id: series_id.to_string(),
+            number,
+            path: video.to_string_lossy().to_string(),
+            subtitles: vec![LocalSubtitle {
+                locale: "en-US".to_string(),
+                kind: SubtitleKind::Subtitles,
+                path: subtitle.to_string_lossy().to_string(),
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(byte_range(None, 10), Some((0, 9)));
+        assert_eq!(byte_range(None, MAX_CHUNK * 2), Some((0, MAX_CHUNK - 1)));
+        assert_eq!(byte_range(Some("bytes=2-5"), 10), Some((2, 5)));
+        assert_eq!(byte_range(Some("bytes=2-50"), 10), Some((2, 9)));
+        assert_eq!(byte_range(Some("bytes=4-"), 10), Some((4, 9)));
+        assert_eq!(
+            byte_range(Some("bytes=0-"), MAX_CHUNK * 2),
+            Some((0, MAX_CHUNK - 1))
+        );
+        assert_eq!(byte_range(Some("bytes=-3"), 10), Some((7, 9)));
+        assert_eq!(byte_range(Some("bytes=-30"), 10), Some((0, 9)));
+        assert_eq!(byte_range(Some("bytes=1-2,5-6"), 10), Some((1, 2)));
+    }
+
+    #[test]
+    fn unsatisfiable_ranges() {
+        assert_eq!(byte_range(None, 0), None);
+        assert_eq!(byte_range(Some("bytes=0-"), 0), None);
+        assert_eq!(byte_range(Some("bytes=10-"), 10), None);
+        assert_eq!(byte_range(Some("bytes=5-2"), 10), None);
+        assert_eq!(byte_range(Some("bytes=a-2"), 10), None);
+        assert_eq!(byte_range(Some("items=0-2"), 10), Some((0, 9)));
+    }
+
+    #[test]
+    fn next_stays_in_the_series() {
+        let dir = dir("next");
+        let mut library = Library::open(dir.join("library.json"));
+        library.add(entry(&dir, "a1", "a", 1)).unwrap();
+        library.add(entry(&dir, "a3", "a", 3)).unwrap();
+        library.add(entry(&dir, "b2", "b", 2)).unwrap();
+        library.add(entry(&dir, "a2", "a", 2)).unwrap();
+
+        let next = |ep_id: &str| {
+            let entry = library.get(ep_id).unwrap();
+            library.next(entry).map(|e| e.ep_id.clone())
+        };
+        assert_eq!(next("a1"), Some("a2".to_string()));
+        assert_eq!(next("a2"), Some("a3".to_string()));
+        assert_eq!(next("a3"), None);
+        assert_eq!(next("b2"), None);
+    }
+
+    #[test]
+    fn remove_deletes_the_files() {
+        let dir = dir("remove");
+        let path = dir.join("library.json");
+        let mut library = Library::open(path.clone());
+        let kept = entry(&dir, "a1", "a", 1);
+        let removed = entry(&dir, "a2", "a", 2);
+        library.add(kept.clone()).unwrap();
+        library.add(removed.clone()).unwrap();
+
+        assert_eq!(library.remove("a2").unwrap(), Some(removed.clone()));
+        assert_eq!(library.remove("a2").unwrap(), None);
+        let video = PathBuf::from(&removed.path);
+        assert!(!video.exists());
+        assert!(!LocalIndex::path(&video).exists());
+        assert!(!Path::new(&removed.subtitles[0].path).exists());
+        assert!(Path::new(&kept.path).exists());
+        assert_eq!(Library::open(path).entries(), vec![kept]);
+    }
+
+    #[test]
+    fn load_drops_missing_files() {
+        let dir = dir("missing");
+        let path = dir.join("library.json");
+        let mut library = Library::open(path.clone());
+        let gone = entry(&dir, "a1", "a", 1);
+        library.add(gone.clone()).unwrap();
+        std::fs::remove_file(&gone.path).unwrap();
+        assert!(Library::open(path).entries().is_empty());
+    }
+}
@@ -2,15 +2,38 @@
     all(not(debug_assertions), target_os = "windows"),
     windows_subsystem = "windows"
 )]
-use anyhow::Result;
-use base64::encode;
+mod ass;
+mod cache;
+mod download;
+mod error;
+mod history;
+mod images;
+mod library;
+mod queue;
+mod session;
+mod settings;
+mod storage;
+mod subs;
+mod sync;
+mod vault;
+
+use ass::AssScript;
 use crunchyroll_rs::feed::RecommendationOptions;
+use crunchyroll_rs::list::WatchlistOptions;
 use crunchyroll_rs::media::MediaCollection;
 use crunchyroll_rs::search::QueryOptions;
 use crunchyroll_rs::{Crunchyroll, Episode, Media, Season, Series};
-use rsubs_lib::ssa;
+use error::{CommandError, CommandResult};
+use history::{History, HistoryEntry};
+use images::{ImageCache, ImageKind, ImageSet};
+use library::{Library, LibraryEntry, LocalSubtitle};
+use queue::{DownloadJob, DownloadQueue, DownloadRequest, Downloads};
 use serde::{Deserialize, Serialize};
+use settings::{Quality, Settings, SubtitleKind};
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use subs::{SubtitleCache, SubtitleFile, SubtitleFormat, SubtitleInfo, SubtitleOffsets};
+use sync::{ConflictPolicy, PendingQueue, PendingUpdate, SyncReport};
 use tauri::{CustomMenuItem, Menu, Submenu};
 use tauri::{Manager, State};
 use tokio::sync::Mutex;
@@ -19,184 +42,323 @@ use tokio::sync::Mutex;
 async fn login(
     username: &str,
     password: &str,
+    remember: Option<bool>,
+    passphrase: Option<String>,
+    app: tauri::AppHandle,
     crunchyroll: State<'_, ViewerContext>,
-) -> Result<String, String> {
+) -> CommandResult<String> {
     println!("Hello, {}!", username);
-    *crunchyroll.session.lock().await = Some(
-        match Crunchyroll::builder()
-            .login_with_credentials(username, password)
-            .await
-        {
-            Ok(cr) => cr,
-            Err(_e) => {
-                println!("{}", _e);
-                return Err(format!("Failed to log in.{}", _e));
-            }
-        },
-    );
-    let sess = crunchyroll.clone();
-    let aid = sess
-        .session
-        .lock()
-        .await
-        .clone()
-        .expect("Failed to log In.");
-    let aid2 = aid
-        .account()
+    let cr = match Crunchyroll::builder()
+        .login_with_credentials(username, password)
         .await
-        .expect("Failed get account details.")
-        .email;
+    {
+        Ok(cr) => cr,
+        Err(e) => {
+            println!("{}", e);
+            return Err(CommandError::from(e).context("Failed to log in."));
+        }
+    };
+    *crunchyroll.session.lock().await = Some(cr.clone());
+    *crunchyroll.account.lock().await = Some(username.to_string());
+    logged_in(&app);
+    if let Err(e) = session::save(username, &cr).await {
+        println!("Failed to store session. {}", e);
+    }
+    if remember.unwrap_or(false) {
+        let secrets = vault::Secrets {
+            username: username.to_string(),
+            password: password.to_string(),
+        };
+        if let Err(e) = vault::store(&secrets, passphrase.as_deref()) {
+            println!("Failed to remember login. {}", e);
+        }
+    }
+    let email = match cr.account().await {
+        Ok(account) => account.email,
+        Err(e) => {
+            println!("Failed get account details. {}", e);
+            String::new()
+        }
+    };
     Ok(format!(
         "Welcome {}, you have logged in. {} ",
-        username, aid2
+        username, email
     ))
 }
 
 #[tauri::command]
-async fn login_anon(crunchyroll: State<'_, ViewerContext>) -> Result<String, String> {
+async fn login_anon(
+    app: tauri::AppHandle,
+    crunchyroll: State<'_, ViewerContext>,
+) -> CommandResult<String> {
     println!("Hello, Anon!!");
     *crunchyroll.session.lock().await =
         Some(match Crunchyroll::builder().login_anonymously().await {
             Ok(cr) => cr,
-            Err(_e) => {
-                println!("{}", _e);
-                return Err(format!("Failed to log in.{}", _e));
+            Err(e) => {
+                println!("{}", e);
+                return Err(CommandError::from(e).context("Failed to log in."));
             }
         });
+    *crunchyroll.account.lock().await = None;
+    logged_in(&app);
     Ok(format!("Welcome {}, you have logged in.", "Anon"))
 }
 
+#[tauri::command]
+async fn vault_unlock(
+    passphrase: Option<String>,
+    app: tauri::AppHandle,
+    crunchyroll: State<'_, ViewerContext>,
+) -> CommandResult<String> {
+    let secrets = match vault::unlock(passphrase.as_deref()) {
+        Ok(s) => s,
+        Err(e) => {
+            println!("{}", e);
+            return Err(CommandError::Auth(format!(
+                "Failed to unlock saved login. {}",
+                e
+            )));
+        }
+    };
+    login(
+        &secrets.username,
+        &secrets.password,
+        None,
+        None,
+        app,
+        crunchyroll,
+    )
+    .await
+}
+
+#[tauri::command]
+async fn vault_forget() -> CommandResult<String> {
+    vault::forget().map_err(|e| CommandError::from(e).context("Failed to remove saved login."))?;
+    Ok("Saved login removed.".to_string())
+}
+
+#[tauri::command]
+async fn vault_status() -> CommandResult<vault::VaultStatus> {
+    Ok(vault::status())
+}
+
+/// The active session, for commands that talk to the api.
+async fn current_session(crunchyroll: &ViewerContext) -> CommandResult<Crunchyroll> {
+    crunchyroll
+        .session
+        .lock()
+        .await
+        .clone()
+        .ok_or(CommandError::NotLoggedIn)
+}
+
+/// The active session when it belongs to an account; anonymous sessions have
+/// no watchlist or playheads.
+async fn account_session(crunchyroll: &ViewerContext) -> CommandResult<Crunchyroll> {
+    if crunchyroll.account.lock().await.is_none() {
+        return Err(CommandError::NotLoggedIn);
+    }
+    current_session(crunchyroll).await
+}
+
+/// Replaces the active session with the one saved for `username`.
+async fn activate_saved(crunchyroll: &ViewerContext, username: &str) -> CommandResult<()> {
+    if session::load(username).is_none() {
+        return Err(CommandError::NotFound(format!(
+            "No saved session for {}.",
+            username
+        )));
+    }
+    let cr = match session::restore(username).await {
+        Ok(cr) => cr,
+        Err(e) => {
+            println!("{}", e);
+            return Err(CommandError::from(e).context("Failed to restore session."));
+        }
+    };
+    // the refresh token rotates on every login, keep the new one
+    if let Err(e) = session::save(username, &cr).await {
+        println!("Failed to store session. {}", e);
+    }
+    *crunchyroll.session.lock().await = Some(cr);
+    *crunchyroll.account.lock().await = Some(username.to_string());
+    Ok(())
+}
+
+/// Logs in with the refresh token saved by a previous `login`, unless a
+/// session is already active (e.g. restored silently at startup).
+async fn restore_saved(crunchyroll: &ViewerContext) -> CommandResult<String> {
+    // held for the whole restore so the startup restore and the frontend's
+    // `restore_session` never log in twice
+    let _restoring = crunchyroll.restoring.lock().await;
+    if crunchyroll.session.lock().await.is_some() {
+        return Ok("You are already logged in.".to_string());
+    }
+    let username =
+        session::last_username().ok_or_else(|| CommandError::not_found("saved session"))?;
+    activate_saved(crunchyroll, &username).await?;
+    Ok(format!("Welcome back {}, you have logged in.", username))
+}
+
+#[tauri::command]
+async fn restore_session(
+    app: tauri::AppHandle,
+    crunchyroll: State<'_, ViewerContext>,
+) -> CommandResult<String> {
+    let res = restore_saved(&crunchyroll).await?;
+    logged_in(&app);
+    Ok(res)
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct AccountList {
+    pub active: Option<String>,
+    pub saved: Vec<String>,
+}
+
+#[tauri::command]
+async fn list_accounts(crunchyroll: State<'_, ViewerContext>) -> CommandResult<AccountList> {
+    Ok(AccountList {
+        active: crunchyroll.account.lock().await.clone(),
+        saved: session::saved_usernames(),
+    })
+}
+
+#[tauri::command]
+async fn switch_account(
+    username: String,
+    app: tauri::AppHandle,
+    crunchyroll: State<'_, ViewerContext>,
+) -> CommandResult<String> {
+    activate_saved(&crunchyroll, &username).await?;
+    logged_in(&app);
+    Ok(format!("Switched to {}.", username))
+}
+
+/// Drops the active session. With `forget` the account is also removed from
+/// the saved accounts, otherwise it stays available to `switch_account`.
+#[tauri::command]
+async fn logout(
+    forget: Option<bool>,
+    crunchyroll: State<'_, ViewerContext>,
+) -> CommandResult<String> {
+    crunchyroll.signed_in.store(false, Ordering::SeqCst);
+    *crunchyroll.session.lock().await = None;
+    let account = crunchyroll.account.lock().await.take();
+    let res = match (&account, forget.unwrap_or(false)) {
+        (Some(username), true) => session::remove(username),
+        _ => session::clear_last(),
+    };
+    if let Err(e) = res {
+        println!("Failed to update saved sessions. {}", e);
+    }
+    Ok(format!(
+        "Goodbye {}, you have logged out.",
+        account.unwrap_or_else(|| "Anon".to_string())
+    ))
+}
+
 #[derive(Serialize, Debug, Deserialize, Clone, Default, PartialEq, Eq)]
 pub struct SearchResult {
     pub name: String,
     pub id: String,
     pub desc: String,
+    /// The largest image of the preferred kind, for quick use.
     pub img: Option<String>,
+    pub images: ImageSet,
+}
+
+impl SearchResult {
+    fn new(name: String, id: String, desc: String, images: ImageSet, order: &[ImageKind]) -> Self {
+        SearchResult {
+            name,
+            id,
+            desc,
+            img: images.largest(order),
+            images,
+        }
+    }
+}
+
+/// Starts caching the artwork of `results` before the frontend asks for it.
+fn warmed(app: &tauri::AppHandle, results: Vec<SearchResult>, width: u32) -> Vec<SearchResult> {
+    images::warm(app, results.iter().map(|r| &r.images), width);
+    results
 }
 
 #[tauri::command]
 async fn search_crunchy(
     series_name: &str,
+    app: tauri::AppHandle,
     crunchyroll: State<'_, ViewerContext>,
-) -> Result<Vec<SearchResult>, String> {
-    let ses = &(*crunchyroll);
-    let aid = ses.session.lock().await;
-    let aid2 = if aid.as_ref().is_some() {
-        Ok(aid.as_ref().expect("Failed login"))
-    } else {
-        Err("Not Logged in.")
-    };
-    if aid2.is_err() {
-        return Err("You are not logged in.".to_string());
-    }
-    let result: Vec<Media<Series>>;
-    let query_res = aid2?
+) -> CommandResult<Vec<SearchResult>> {
+    let cr = current_session(&crunchyroll).await?;
+    let query_res = cr
         .query(series_name, QueryOptions::default())
         .await
-        .expect("Failed to Query");
-    result = query_res.series.expect("Failed to find a series").items;
-    let mut a: Vec<SearchResult> = vec![];
-    for r in result {
-        a.push(SearchResult {
-            name: r.title,
-            id: r.id,
-            desc: r.description,
-            img: Some(if let Some(image) = r.images {
-                if let Some(pic) = image.thumbnail {
-                    let mut p: Vec<String> = vec![];
-                    for mut i in pic {
-                        i.sort_by(|l, j| j.height.cmp(&l.height));
-                        p.push(i.first().unwrap().clone().source);
-                    }
-                    p.first().unwrap().to_string()
-                } else if let Some(pic) = image.poster_wide {
-                    let mut p: Vec<String> = vec![];
-                    for mut i in pic {
-                        i.sort_by(|l, j| j.height.cmp(&l.height));
-                        p.push(i.first().unwrap().clone().source);
-                    }
-                    p.first().unwrap().to_string()
-                } else if let Some(pic) = image.promo_image {
-                    let mut p: Vec<String> = vec![];
-                    for mut i in pic {
-                        i.sort_by(|l, j| j.height.cmp(&l.height));
-                        p.push(i.first().unwrap().clone().source);
-                    }
-                    p.first().unwrap().to_string()
-                } else if let Some(pic) = image.poster_tall {
-                    let mut p: Vec<String> = vec![];
-                    for mut i in pic {
-                        i.sort_by(|l, j| j.height.cmp(&l.height));
-                        p.push(i.first().unwrap().clone().source);
-                    }
-                    p.first().unwrap().to_string()
-                } else {
-                    "".to_string()
-                }
-            } else {
-                "".to_string()
-            }),
-        });
-    }
-
-    Ok(a)
+        .map_err(|e| CommandError::from(e).context("Failed to search."))?;
+    let result: Vec<Media<Series>> = match query_res.series {
+        Some(s) => s.items,
+        None => return Ok(vec![]),
+    };
+    Ok(warmed(&app, series_results(result), images::BANNER_WIDTH))
 }
+
 #[tauri::command]
 async fn get_seasons(
     series_name: &str,
+    app: tauri::AppHandle,
     crunchyroll: State<'_, ViewerContext>,
-) -> Result<Vec<SearchResult>, String> {
-    let ses = &(*crunchyroll);
-    let aid = ses.session.lock().await;
-    let aid2 = if aid.as_ref().is_some() {
-        Ok(aid.as_ref().unwrap())
-    } else {
-        Err("Not Logged in.")
-    };
-    if aid2.is_err() {
-        return Err("You are not logged in.".to_string());
-    }
-    let result: Vec<Media<Season>>;
-    let query_res: Media<Series> = aid2?.media_from_id(series_name).await.unwrap();
-    result = query_res.seasons().await.unwrap();
-    let mut a: Vec<SearchResult> = vec![];
-    for r in result {
-        let i: Option<String> = if let Some(pic) = query_res.images.clone() {
-            let a = pic.poster_tall.filter(|x| !x.is_empty()).map(|x| {
-                let mut p: Vec<String> = vec![];
-                for mut i in x {
-                    i.sort_by(|l, j| j.height.cmp(&l.height));
-                    p.push(i.first().unwrap().clone().source);
-                }
-                p.join(" ")
-            });
-            if a.is_some() {
-                a
-            } else {
-                Some("".to_string())
-            }
-        } else {
-            Some("".to_string())
-        };
-        a.push(SearchResult {
-            name: r.title,
-            id: r.id,
-            desc: r.description,
-            img: i,
+) -> CommandResult<Vec<SearchResult>> {
+    let cr = current_session(&crunchyroll).await?;
+    let query_res: Media<Series> = cr
+        .media_from_id(series_name)
+        .await
+        .map_err(|e| CommandError::from(e).context("Failed to find the series."))?;
+    let mut result: Vec<Media<Season>> = query_res
+        .seasons()
+        .await
+        .map_err(|e| CommandError::from(e).context("Failed to get the seasons."))?;
+    // seasons in the preferred audio go first, keeping the api's order otherwise
+    let audio = crunchyroll.settings.lock().await.audio_locale.clone();
+    if !audio.is_empty() {
+        result.sort_by_key(|s| {
+            !s.metadata
+                .audio_locales
+                .iter()
+                .any(|l| l.to_string() == audio)
         });
     }
-    Ok(a)
+    // seasons have no artwork of their own, they all get the series poster
+    let poster = ImageSet::from_images(query_res.images.clone()).only(&[ImageKind::PosterTall]);
+    images::warm(&app, [&poster], images::GRID_WIDTH);
+    Ok(result
+        .into_iter()
+        .map(|r| {
+            SearchResult::new(
+                r.title,
+                r.id,
+                r.description,
+                poster.clone(),
+                &[ImageKind::PosterTall],
+            )
+        })
+        .collect())
 }
 
 #[derive(Serialize, Debug, Deserialize, Clone, Default, PartialEq, Eq)]
 pub struct EpisodeResult {
     pub title: String,
     pub id: String,
+    pub series_id: String,
     pub number: i32,
     pub desc: String,
     pub url: String,
+    pub streams: Vec<StreamVariant>,
+    pub audio: Vec<AudioVersion>,
     pub img: String,
-    pub subs: HashMap<String, String>,
+    pub subs: Vec<SubtitleInfo>,
     pub nep: Option<Vec<Ep>>,
 }
 
@@ -206,52 +368,155 @@ pub struct Ep {
     pub ep_type: String,
 }
 
-#[derive(Serialize, Debug, Deserialize, Clone, Default, PartialEq, Eq)]
-pub struct PlayResult {
-    pub audio_locale: String,
-    pub subtitles: HashMap<String, Sub>,
-    pub streams: HashMap<String, HashMap<String, Strems>>,
-    pub QoS: Qos,
+#[derive(Serialize, Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamProtocol {
+    AdaptiveHls,
+    AdaptiveDash,
+    DownloadHls,
 }
 
-#[derive(Serialize, Debug, Deserialize, Clone, Default, PartialEq, Eq)]
-pub struct Sub {
-    pub locale: String,
+/// One way to play an episode: a protocol, optionally with subtitles burnt
+/// into the picture.
+#[derive(Serialize, Debug, Deserialize, Clone, PartialEq, Eq)]
+pub struct StreamVariant {
+    /// Empty for the stream without hardsubs.
+    pub hardsub_locale: String,
+    pub hardsub_label: String,
+    pub protocol: StreamProtocol,
     pub url: String,
-    pub format: String,
 }
 
-#[derive(Serialize, Debug, Deserialize, Clone, Default, PartialEq, Eq)]
-pub struct Strems {
-    pub hardsub_locale: String,
-    pub url: String,
-    pub vcodec: String,
+/// Every variant of a stream, the softsub ones first then by hardsub name.
+fn stream_variants(
+    variants: &HashMap<crunchyroll_rs::Locale, crunchyroll_rs::media::Variants>,
+) -> Vec<StreamVariant> {
+    let mut list = vec![];
+    for (locale, v) in variants {
+        let (hardsub_locale, hardsub_label) = match locale {
+            crunchyroll_rs::Locale::Custom(l) if l.is_empty() => (String::new(), String::new()),
+            l => (l.to_string(), l.to_human_readable()),
+        };
+        let protocols = [
+            (StreamProtocol::AdaptiveHls, &v.adaptive_hls),
+            (StreamProtocol::AdaptiveDash, &v.adaptive_dash),
+            (StreamProtocol::DownloadHls, &v.download_hls),
+        ];
+        list.extend(protocols.into_iter().filter_map(|(protocol, variant)| {
+            variant.as_ref().map(|variant| StreamVariant {
+                hardsub_locale: hardsub_locale.clone(),
+                hardsub_label: hardsub_label.clone(),
+                protocol,
+                url: variant.url.clone(),
+            })
+        }));
+    }
+    list.sort_by(|a, b| {
+        (!a.hardsub_locale.is_empty(), &a.hardsub_label)
+            .cmp(&(!b.hardsub_locale.is_empty(), &b.hardsub_label))
+    });
+    list
 }
 
+/// The same episode in another audio language.
 #[derive(Serialize, Debug, Deserialize, Clone, Default, PartialEq, Eq)]
-pub struct Qos {
-    pub region: String,
-    pub cloudFrontRequestId: String,
-    pub lambdaRunTime: i32,
+pub struct AudioVersion {
+    pub locale: String,
+    pub label: String,
+    pub ep_id: String,
 }
 
-#[tauri::command]
-async fn get_recs(crunchyroll: State<'_, ViewerContext>) -> Result<Vec<SearchResult>, String> {
-    let ses = &(*crunchyroll);
-    let aid = ses.session.lock().await;
-    let aid2 = if aid.as_ref().is_some() {
-        Ok(aid.as_ref().expect("Failed login"))
-    } else {
-        Err("Not Logged in.")
+/// What the player starts with: softsub first thanks to the ordering of
+/// `stream_variants`, hardsubs only when there is nothing else.
+fn playable_url(streams: &[StreamVariant]) -> CommandResult<String> {
+    Ok(streams
+        .iter()
+        .find(|s| s.protocol == StreamProtocol::AdaptiveHls)
+        .ok_or_else(|| CommandError::not_found("playable stream"))?
+        .url
+        .clone())
+}
+
+/// Dubs are separate seasons with the same number, so the versions of an
+/// episode are the episodes with its number in those seasons.
+async fn audio_versions(cr: &Crunchyroll, episode: &Media<Episode>) -> Vec<AudioVersion> {
+    let seasons = match cr
+        .media_from_id::<Series>(&episode.metadata.series_id)
+        .await
+    {
+        Ok(series) => series.seasons().await,
+        Err(e) => Err(e),
     };
-    if aid2.is_err() {
-        return Err("You are not logged in.".to_string());
-    }
+    let seasons = match seasons {
+        Ok(seasons) => seasons,
+        Err(e) => {
+            println!("Failed to get the audio versions. {}", e);
+            return vec![];
+        }
+    };
+    let lookups = seasons
+        .into_iter()
+        .filter(|s| s.metadata.season_number == episode.metadata.season_number)
+        .map(|s| async move { s.episodes().await });
+    let mut versions: Vec<AudioVersion> = futures_util::future::join_all(lookups)
+        .await
+        .into_iter()
+        .filter_map(|res| match res {
+            Ok(episodes) => Some(episodes),
+            Err(e) => {
+                println!("Failed to get the audio versions. {}", e);
+                None
+            }
+        })
+        .flatten()
+        .filter(|e| e.metadata.episode_number == episode.metadata.episode_number)
+        .map(|e| AudioVersion {
+            locale: e.metadata.audio_locale.to_string(),
+            label: e.metadata.audio_locale.to_human_readable(),
+            ep_id: e.id,
+        })
+        .collect();
+    versions.sort_by(|a, b| a.label.cmp(&b.label));
+    versions.dedup_by(|a, b| a.locale == b.locale);
+    versions
+}
+
+fn series_results(result: Vec<Media<Series>>) -> Vec<SearchResult> {
+    result
+        .into_iter()
+        .map(|r| {
+            SearchResult::new(
+                r.title,
+                r.id,
+                r.description,
+                ImageSet::from_images(r.images),
+                &images::SERIES_ORDER,
+            )
+        })
+        .collect()
+}
+
+fn episode_result(r: Media<Episode>) -> SearchResult {
+    SearchResult::new(
+        r.title,
+        r.id,
+        r.description,
+        ImageSet::from_images(r.images),
+        &images::EPISODE_ORDER,
+    )
+}
+
+#[tauri::command]
+async fn get_recs(
+    app: tauri::AppHandle,
+    crunchyroll: State<'_, ViewerContext>,
+) -> CommandResult<Vec<SearchResult>> {
+    let cr = current_session(&crunchyroll).await?;
     let mut result: Vec<Media<Series>> = vec![];
-    let query_res = aid2?
+    let query_res = cr
         .recommendations(RecommendationOptions::default())
         .await
-        .expect("Failed to Query");
+        .map_err(|e| CommandError::from(e).context("Failed to get recommendations."))?;
     for i in query_res.items.iter() {
         let resul2t: Media<Series> = match i {
             MediaCollection::Series(s) => s.clone(),
@@ -259,51 +524,64 @@ async fn get_recs(crunchyroll: State<'_, ViewerContext>) -> Result<Vec<SearchRes
         };
         result.push(resul2t);
     }
-    let mut a: Vec<SearchResult> = vec![];
-    for r in result {
-        a.push(SearchResult {
-            name: r.title,
-            id: r.id,
-            desc: r.description,
-            img: Some(if let Some(image) = r.images {
-                if let Some(pic) = image.thumbnail {
-                    let mut p: Vec<String> = vec![];
-                    for mut i in pic {
-                        i.sort_by(|l, j| j.height.cmp(&l.height));
-                        p.push(i.first().unwrap().clone().source);
-                    }
-                    p.first().unwrap().to_string()
-                } else if let Some(pic) = image.poster_wide {
-                    let mut p: Vec<String> = vec![];
-                    for mut i in pic {
-                        i.sort_by(|l, j| j.height.cmp(&l.height));
-                        p.push(i.first().unwrap().clone().source);
-                    }
-                    p.first().unwrap().to_string()
-                } else if let Some(pic) = image.promo_image {
-                    let mut p: Vec<String> = vec![];
-                    for mut i in pic {
-                        i.sort_by(|l, j| j.height.cmp(&l.height));
-                        p.push(i.first().unwrap().clone().source);
-                    }
-                    p.first().unwrap().to_string()
-                } else if let Some(pic) = image.poster_tall {
-                    let mut p: Vec<String> = vec![];
-                    for mut i in pic {
-                        i.sort_by(|l, j| j.height.cmp(&l.height));
-                        p.push(i.first().unwrap().clone().source);
-                    }
-                    p.first().unwrap().to_string()
-                } else {
-                    "".to_string()
-                }
-            } else {
-                "".to_string()
-            }),
-        });
-    }
+    Ok(warmed(&app, series_results(result), images::BANNER_WIDTH))
+}
 
-    Ok(a)
+#[tauri::command]
+async fn get_watchlist(
+    app: tauri::AppHandle,
+    crunchyroll: State<'_, ViewerContext>,
+) -> CommandResult<Vec<SearchResult>> {
+    let cr = account_session(&crunchyroll).await?;
+    let entries = cr
+        .watchlist(WatchlistOptions::default())
+        .await
+        .map_err(|e| CommandError::from(e).context("Failed to get the watchlist."))?;
+    let result: Vec<Media<Series>> = entries
+        .into_iter()
+        .filter_map(|e| match e.panel {
+            MediaCollection::Series(s) => Some(s),
+            _ => None,
+        })
+        .collect();
+    Ok(warmed(&app, series_results(result), images::GRID_WIDTH))
+}
+
+/// Looks up a series for the watchlist commands.
+async fn watchlist_series(
+    series_id: &str,
+    crunchyroll: &ViewerContext,
+) -> CommandResult<Media<Series>> {
+    let cr = account_session(crunchyroll).await?;
+    cr.media_from_id(series_id)
+        .await
+        .map_err(|e| CommandError::from(e).context("Failed to find the series."))
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn add_to_watchlist(
+    series_id: String,
+    crunchyroll: State<'_, ViewerContext>,
+) -> CommandResult<String> {
+    let series = watchlist_series(&series_id, &crunchyroll).await?;
+    series
+        .add_to_watchlist()
+        .await
+        .map_err(|e| CommandError::from(e).context("Failed to update the watchlist."))?;
+    Ok(format!("Added {} to the watchlist.", series.title))
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn remove_from_watchlist(
+    series_id: String,
+    crunchyroll: State<'_, ViewerContext>,
+) -> CommandResult<String> {
+    let series = watchlist_series(&series_id, &crunchyroll).await?;
+    series
+        .remove_from_watchlist()
+        .await
+        .map_err(|e| CommandError::from(e).context("Failed to update the watchlist."))?;
+    Ok(format!("Removed {} from the watchlist.", series.title))
 }
 
 #[tauri::command(rename_all = "snake_case")]
@@ -311,21 +589,33 @@ async fn view_episode(
     ep_id: String,
     ep_type: String,
     crunchyroll: State<'_, ViewerContext>,
-) -> Result<EpisodeResult, String> {
+) -> CommandResult<EpisodeResult> {
     let ses = &(*crunchyroll);
-    let aid = ses.session.lock().await;
-    let aid2 = if aid.as_ref().is_some() {
-        Ok(aid.as_ref().unwrap())
-    } else {
-        Err("Not Logged in.")
-    };
-    if aid2.is_err() {
-        return Err("You are not logged in.".to_string());
+    let cr = current_session(ses).await?;
+    let query_res: Media<Episode> = cr
+        .media_from_id(ep_id.clone())
+        .await
+        .map_err(|e| CommandError::from(e).context("Failed to find the episode."))?;
+    if let Err(e) = ses.history.lock().await.viewed(
+        &query_res.id,
+        &query_res.metadata.series_id,
+        &query_res.title,
+        &query_res.metadata.series_title,
+    ) {
+        println!("Failed to update watch history. {}", e);
     }
-    let query_res: Media<Episode> = aid2?.media_from_id(ep_id.clone()).await.unwrap();
-    let next_ep = query_res.season().await.unwrap().episodes().await.unwrap();
+    // a missing next episode should not keep the current one from playing
+    let next_ep = match query_res.season().await {
+        Ok(season) => season.episodes().await.unwrap_or_else(|e| {
+            println!("Failed to get the next episode. {}", e);
+            vec![]
+        }),
+        Err(e) => {
+            println!("Failed to get the next episode. {}", e);
+            vec![]
+        }
+    };
     let nepp = next_ep
-        .clone()
         .into_iter()
         .filter(|x| x.metadata.episode_number == query_res.metadata.episode_number + 1)
         .collect::<Vec<Media<Episode>>>();
@@ -336,66 +626,869 @@ async fn view_episode(
             ep_type: String::from("secondary"),
         });
     }
-    let mut subs: HashMap<String, String> = HashMap::new();
-    let a = query_res.streams().await.unwrap();
-    for item in a.clone().subtitles {
-        let a = "data:text/vtt;base64,".to_string()
-            + &encode(
-                (ssa::parse(
-                    aid2?
-                        .client()
-                        .get(item.1.url.to_string())
-                        .send()
-                        .await
-                        .unwrap()
-                        .text()
-                        .await
-                        .unwrap(),
-                )
-                .unwrap()
-                .to_vtt()
-                .to_string())
-                .as_bytes(),
-            );
-        subs.insert(item.0.to_human_readable(), a);
-    }
-    let b = query_res.streams().await.unwrap();
-    for item in b.closed_captions {
-        let a = "data:text/vtt;base64,".to_string()
-            + &encode(
-                (aid2?
-                    .client()
-                    .get(item.1.url.to_string())
-                    .send()
-                    .await
-                    .unwrap()
-                    .text()
-                    .await
-                    .unwrap())
-                .as_bytes(),
-            );
-        subs.insert(item.0.to_human_readable(), a);
-    }
-    let url = b
-        .variants
-        .get(&crunchyroll_rs::Locale::Custom("".to_string()))
-        .unwrap()
-        .clone()
-        .adaptive_hls
-        .unwrap()
-        .url;
+    let b = query_res.streams().await.map_err(|e| {
+        CommandError::refused(e, query_res.metadata.is_premium_only)
+            .context("Failed to get the streams.")
+    })?;
+    let subs = subs::tracks(b.subtitles.keys(), b.closed_captions.keys());
+    let audio = audio_versions(&cr, &query_res).await;
+    let streams = stream_variants(&b.variants);
+    let url = playable_url(&streams)?;
     Ok(EpisodeResult {
         title: query_res.title,
         id: query_res.id,
+        series_id: query_res.metadata.series_id,
         number: query_res.metadata.episode_number as i32,
         desc: query_res.description,
         url,
+        streams,
+        audio,
         subs,
         nep: Some(nep2),
         ..Default::default()
     })
 }
 
+/// The `locale` track of `kind` of `ep_id` in `format`, from the cache when
+/// possible, delayed by `offset_ms`. The cache keeps the unshifted file.
+async fn subtitle_data(
+    ep_id: &str,
+    locale: &str,
+    kind: SubtitleKind,
+    format: SubtitleFormat,
+    offset_ms: i64,
+    crunchyroll: &ViewerContext,
+    cache: &SubtitleCache,
+) -> CommandResult<String> {
+    let data = match cache.get(ep_id, locale, kind, format) {
+        Some(data) => data,
+        None => {
+            // downloaded tracks play without a session
+            let local = crunchyroll
+                .library()?
+                .get(ep_id)
+                .and_then(|e| e.subtitle(locale, kind));
+            if let Some(path) = local {
+                let data = subs::read_local(&path, format)?;
+                return Ok(subs::shift(&data, format, offset_ms));
+            }
+            let cr = current_session(crunchyroll).await?;
+            let (raw, from) = subs::fetch(&cr, ep_id, locale, kind)
+                .await
+                .map_err(|e| e.context("Failed to get subtitles."))?;
+            let data = subs::convert(raw, from, format)?;
+            cache.put(ep_id, locale, kind, format, &data);
+            data
+        }
+    };
+    Ok(subs::shift(&data, format, offset_ms))
+}
+
+/// Fetches one subtitle track of an episode, converted to `format` (WebVTT
+/// unless asked otherwise) and delayed by `offset_ms`.
+#[tauri::command(rename_all = "snake_case")]
+async fn get_subtitle(
+    ep_id: String,
+    locale: String,
+    kind: Option<SubtitleKind>,
+    format: Option<SubtitleFormat>,
+    offset_ms: Option<i64>,
+    crunchyroll: State<'_, ViewerContext>,
+    cache: State<'_, SubtitleCache>,
+) -> CommandResult<SubtitleFile> {
+    let format = format.unwrap_or_default();
+    let offset_ms = offset_ms.unwrap_or(0);
+    let data = subtitle_data(
+        &ep_id,
+        &locale,
+        kind.unwrap_or_default(),
+        format,
+        offset_ms,
+        &crunchyroll,
+        &cache,
+    )
+    .await?;
+    Ok(SubtitleFile {
+        locale,
+        format,
+        data,
+    })
+}
+
+/// The styled version of a subtitle track, for the player's overlay.
+#[tauri::command(rename_all = "snake_case")]
+async fn get_ass_script(
+    ep_id: String,
+    locale: String,
+    kind: Option<SubtitleKind>,
+    offset_ms: Option<i64>,
+    crunchyroll: State<'_, ViewerContext>,
+    cache: State<'_, SubtitleCache>,
+) -> CommandResult<AssScript> {
+    let data = subtitle_data(
+        &ep_id,
+        &locale,
+        kind.unwrap_or_default(),
+        SubtitleFormat::Ass,
+        offset_ms.unwrap_or(0),
+        &crunchyroll,
+        &cache,
+    )
+    .await?;
+    ass::parse(&data).map_err(|e| e.context("Failed to read styled subtitles."))
+}
+
+/// Saves one subtitle track of an episode to `path`, as the original ass or
+/// converted to srt/vtt. A folder as `path` gets a file named after the track.
+#[tauri::command(rename_all = "snake_case")]
+async fn download_subtitles(
+    ep_id: String,
+    locale: String,
+    kind: Option<SubtitleKind>,
+    format: Option<SubtitleFormat>,
+    path: String,
+    offset_ms: Option<i64>,
+    crunchyroll: State<'_, ViewerContext>,
+    cache: State<'_, SubtitleCache>,
+) -> CommandResult<String> {
+    let kind = kind.unwrap_or_default();
+    let format = format.unwrap_or(SubtitleFormat::Ass);
+    let mut path = std::path::PathBuf::from(path);
+    if path.is_dir() {
+        path.push(format!(
+            "{}.{}.{}",
+            ep_id,
+            subs::track_name(&locale, kind),
+            format.extension()
+        ));
+    }
+    let data = subtitle_data(
+        &ep_id,
+        &locale,
+        kind,
+        format,
+        offset_ms.unwrap_or(0),
+        &crunchyroll,
+        &cache,
+    )
+    .await?;
+    subs::write(&path, &data)?;
+    Ok(format!("Saved subtitles to {}.", path.display()))
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct DownloadReport {
+    pub path: String,
+    pub bytes: u64,
+    /// Subtitle files written next to the video.
+    pub subtitles: Vec<String>,
+    /// One message per subtitle track that could not be saved.
+    pub failed: Vec<String>,
+}
+
+/// Where a download of `ep_id` asked to go to `path` ends up: a folder gets
+/// a file named after the episode. `ep_id` is the resolved one, so a dub
+/// saved next to the original gets a file of its own.
+fn episode_path(path: &str, ep_id: &str) -> std::path::PathBuf {
+    let mut path = std::path::PathBuf::from(path);
+    if path.is_dir() {
+        path.push(format!("{}.ts", ep_id));
+    }
+    path
+}
+
+/// Saves an episode, the stream resolved like `view_episode` does. `audio`
+/// picks a dub by locale, `subs` are saved as ass files next to the video.
+/// Without `from` a failed download leaves nothing behind, with it the file
+/// is kept for the next attempt.
+async fn save_episode(
+    request: &DownloadRequest,
+    from: Option<download::Resume>,
+    crunchyroll: &ViewerContext,
+    cache: &SubtitleCache,
+    on_progress: &(dyn Fn(&download::DownloadProgress) + Sync),
+) -> CommandResult<DownloadReport> {
+    let cr = current_session(crunchyroll).await?;
+    let mut episode: Media<Episode> = cr
+        .media_from_id(&request.ep_id)
+        .await
+        .map_err(|e| CommandError::from(e).context("Failed to find the episode."))?;
+    if let Some(audio) = request.audio.as_ref().filter(|a| !a.is_empty()) {
+        let version = audio_versions(&cr, &episode)
+            .await
+            .into_iter()
+            .find(|v| &v.locale == audio)
+            .ok_or_else(|| CommandError::not_found(&format!("{} audio", audio)))?;
+        if version.ep_id != episode.id {
+            episode = cr
+                .media_from_id(&version.ep_id)
+                .await
+                .map_err(|e| CommandError::from(e).context("Failed to find the dub."))?;
+        }
+    }
+    let streams = episode.streams().await.map_err(|e| {
+        CommandError::refused(e, episode.metadata.is_premium_only)
+            .context("Failed to get the streams.")
+    })?;
+    let url = playable_url(&stream_variants(&streams.variants))?;
+    let path = episode_path(&request.path, &episode.id);
+    let path = path.as_path();
+    let client = cr.client();
+    let (path, bytes) = match from {
+        Some(from) => {
+            download::download_from(
+                &client,
+                &url,
+                request.quality,
+                path,
+                &request.ep_id,
+                from,
+                on_progress,
+            )
+            .await?
+        }
+        None => {
+            download::download(
+                &client,
+                &url,
+                request.quality,
+                path,
+                &request.ep_id,
+                on_progress,
+            )
+            .await?
+        }
+    };
+    let mut report = DownloadReport {
+        path: path.display().to_string(),
+        bytes,
+        ..Default::default()
+    };
+    let mut local_subs = vec![];
+    for locale in &request.subs {
+        let sub_path = path.with_extension(format!(
+            "{}.{}",
+            subs::track_name(locale, request.subs_kind),
+            SubtitleFormat::Ass.extension()
+        ));
+        let saved = match subtitle_data(
+            &episode.id,
+            locale,
+            request.subs_kind,
+            SubtitleFormat::Ass,
+            0,
+            crunchyroll,
+            cache,
+        )
+        .await
+        {
+            Ok(data) => subs::write(&sub_path, &data),
+            Err(e) => Err(e),
+        };
+        match saved {
+            Ok(()) => {
+                report.subtitles.push(sub_path.display().to_string());
+                local_subs.push(LocalSubtitle {
+                    locale: locale.clone(),
+                    kind: request.subs_kind,
+                    path: sub_path.display().to_string(),
+                });
+            }
+            Err(e) => report.failed.push(format!("{}: {}", locale, e)),
+        }
+    }
+    let entry = LibraryEntry {
+        ep_id: episode.id.clone(),
+        title: episode.title.clone(),
+        series_id: episode.metadata.series_id.clone(),
+        series_title: episode.metadata.series_title.clone(),
+        number: episode.metadata.episode_number as i32,
+        desc: episode.description.clone(),
+        path: report.path.clone(),
+        subtitles: local_subs,
+        bytes,
+        added: library::now(),
+    };
+    let added = crunchyroll
+        .library()
+        .and_then(|mut library| library.add(entry).map_err(CommandError::from));
+    if let Err(e) = added {
+        println!("Failed to add the episode to the library. {}", e);
+    }
+    Ok(report)
+}
+
+#[tauri::command]
+async fn list_offline(crunchyroll: State<'_, ViewerContext>) -> CommandResult<Vec<LibraryEntry>> {
+    Ok(crunchyroll.library()?.entries())
+}
+
+/// `view_episode` for a downloaded episode, from the library alone. The
+/// frontend plays `<id>.m3u8` through the `crunchylocal` scheme.
+#[tauri::command(rename_all = "snake_case")]
+async fn view_offline_episode(
+    ep_id: String,
+    crunchyroll: State<'_, ViewerContext>,
+) -> CommandResult<EpisodeResult> {
+    let (entry, next) = {
+        let library = crunchyroll.library()?;
+        let entry = library
+            .get(&ep_id)
+            .ok_or_else(|| CommandError::not_found("downloaded episode"))?;
+        (entry.clone(), library.next(entry).map(|e| e.ep_id.clone()))
+    };
+    if let Err(e) = crunchyroll.history.lock().await.viewed(
+        &entry.ep_id,
+        &entry.series_id,
+        &entry.title,
+        &entry.series_title,
+    ) {
+        println!("Failed to update watch history. {}", e);
+    }
+    let nep = next
+        .map(|ep_id| Ep {
+            ep_id,
+            ep_type: "offline".to_string(),
+        })
+        .into_iter()
+        .collect();
+    Ok(EpisodeResult {
+        title: entry.title.clone(),
+        id: entry.ep_id.clone(),
+        series_id: entry.series_id.clone(),
+        number: entry.number,
+        desc: entry.desc.clone(),
+        subs: entry
+            .subtitles
+            .iter()
+            .map(|s| SubtitleInfo {
+                locale: s.locale.clone(),
+                label: s.locale.clone(),
+                kind: s.kind,
+            })
+            .collect(),
+        nep: Some(nep),
+        ..Default::default()
+    })
+}
+
+/// Removes a downloaded episode and its files.
+#[tauri::command(rename_all = "snake_case")]
+async fn delete_offline(
+    ep_id: String,
+    crunchyroll: State<'_, ViewerContext>,
+) -> CommandResult<String> {
+    let entry = crunchyroll
+        .library()?
+        .remove(&ep_id)
+        .map_err(|e| CommandError::from(e).context("Failed to delete the download."))?
+        .ok_or_else(|| CommandError::not_found("downloaded episode"))?;
+    Ok(format!("Deleted {}.", entry.title))
+}
+
+/// Saves an episode to `path` right away, see `save_episode`. Progress goes
+/// out as `download::PROGRESS_EVENT`.
+#[tauri::command(rename_all = "snake_case")]
+async fn download_episode(
+    ep_id: String,
+    quality: Option<Quality>,
+    audio: Option<String>,
+    subs: Option<Vec<String>>,
+    subs_kind: Option<SubtitleKind>,
+    path: String,
+    app: tauri::AppHandle,
+    crunchyroll: State<'_, ViewerContext>,
+    cache: State<'_, SubtitleCache>,
+) -> CommandResult<DownloadReport> {
+    let quality = match quality {
+        Some(q) => q,
+        None => crunchyroll.settings.lock().await.quality,
+    };
+    let subs_kind = match subs_kind {
+        Some(k) => k,
+        None => crunchyroll.settings.lock().await.subtitle_kind,
+    };
+    let request = DownloadRequest {
+        path,
+        title: ep_id.clone(),
+        ep_id,
+        quality,
+        audio,
+        subs: subs.unwrap_or_default(),
+        subs_kind,
+    };
+    save_episode(
+        &request,
+        None,
+        &crunchyroll,
+        &cache,
+        &|progress: &download::DownloadProgress| {
+            if let Err(e) = app.emit_all(download::PROGRESS_EVENT, progress) {
+                println!("Failed to report download progress. {}", e);
+            }
+        },
+    )
+    .await
+}
+
+/// Saves the queue and sends it to the frontend.
+fn emit_queue(app: &tauri::AppHandle, queue: &mut DownloadQueue) {
+    if let Err(e) = queue.save() {
+        println!("Failed to save the download queue. {}", e);
+    }
+    report_queue(app, queue);
+}
+
+/// Sends the queue to the frontend without saving it, for progress reports.
+fn report_queue(app: &tauri::AppHandle, queue: &DownloadQueue) {
+    if let Err(e) = app.emit_all(queue::QUEUE_EVENT, queue.jobs()) {
+        println!("Failed to report the download queue. {}", e);
+    }
+}
+
+/// Starts queued downloads while there is room for them. Jobs need a
+/// session, without one they wait for the next login.
+fn pump_downloads(app: &tauri::AppHandle) -> CommandResult<()> {
+    let downloads = app.state::<Downloads>();
+    let mut queue = downloads.queue()?;
+    if app
+        .state::<ViewerContext>()
+        .signed_in
+        .load(Ordering::SeqCst)
+    {
+        let mut tasks = downloads.tasks()?;
+        while let Some((id, request, from)) = queue.start_next() {
+            let task = tauri::async_runtime::spawn(run_download(app.clone(), id, request, from));
+            tasks.insert(id, task);
+        }
+    }
+    emit_queue(app, &mut queue);
+    Ok(())
+}
+
+/// Starts the jobs that waited for a session.
+fn logged_in(app: &tauri::AppHandle) {
+    app.state::<ViewerContext>()
+        .signed_in
+        .store(true, Ordering::SeqCst);
+    if let Err(e) = pump_downloads(app) {
+        println!("Failed to start the download queue. {}", e);
+    }
+}
+
+/// One queued job, retried with backoff until it is done or given up on.
+async fn run_download(
+    app: tauri::AppHandle,
+    id: u64,
+    mut request: DownloadRequest,
+    mut from: download::Resume,
+) {
+    let downloads = app.state::<Downloads>();
+    loop {
+        let on_progress = |progress: &download::DownloadProgress| {
+            if let Ok(mut queue) = downloads.queue() {
+                queue.progress(id, progress);
+                if queue.save_due() {
+                    emit_queue(&app, &mut queue);
+                } else {
+                    report_queue(&app, &queue);
+                }
+            }
+        };
+        let res = save_episode(
+            &request,
+            Some(from),
+            &app.state::<ViewerContext>(),
+            &app.state::<SubtitleCache>(),
+            &on_progress,
+        )
+        .await;
+        let delay = match downloads.queue() {
+            Ok(mut queue) => {
+                let delay = queue.finished(id, &res);
+                emit_queue(&app, &mut queue);
+                delay
+            }
+            Err(_) => None,
+        };
+        let delay = match delay {
+            Some(delay) => delay,
+            None => break,
+        };
+        tokio::time::sleep(delay).await;
+        let next = match downloads.queue() {
+            Ok(mut queue) => queue.retry(id),
+            Err(_) => None,
+        };
+        match next {
+            Some((r, f)) => {
+                request = r;
+                from = f;
+            }
+            None => break,
+        }
+    }
+    if let Ok(mut tasks) = downloads.tasks() {
+        tasks.remove(&id);
+    }
+    if let Err(e) = pump_downloads(&app) {
+        println!("Failed to start the next download. {}", e);
+    }
+}
+
+/// Adds an episode to the download queue, see `download_episode` for the
+/// arguments.
+#[tauri::command(rename_all = "snake_case")]
+async fn queue_download(
+    ep_id: String,
+    title: Option<String>,
+    quality: Option<Quality>,
+    audio: Option<String>,
+    subs: Option<Vec<String>>,
+    subs_kind: Option<SubtitleKind>,
+    path: String,
+    app: tauri::AppHandle,
+    crunchyroll: State<'_, ViewerContext>,
+    downloads: State<'_, Downloads>,
+) -> CommandResult<String> {
+    let quality = match quality {
+        Some(q) => q,
+        None => crunchyroll.settings.lock().await.quality,
+    };
+    let subs_kind = match subs_kind {
+        Some(k) => k,
+        None => crunchyroll.settings.lock().await.subtitle_kind,
+    };
+    let title = title.unwrap_or_else(|| ep_id.clone());
+    downloads.queue()?.add(DownloadRequest {
+        path,
+        title: title.clone(),
+        ep_id,
+        quality,
+        audio,
+        subs: subs.unwrap_or_default(),
+        subs_kind,
+    });
+    pump_downloads(&app)?;
+    Ok(format!("Queued {}.", title))
+}
+
+#[tauri::command]
+async fn list_downloads(downloads: State<'_, Downloads>) -> CommandResult<Vec<DownloadJob>> {
+    Ok(downloads.queue()?.jobs())
+}
+
+/// Stops a download, what was written so far is kept for `resume_download`.
+#[tauri::command]
+async fn pause_download(
+    id: u64,
+    app: tauri::AppHandle,
+    downloads: State<'_, Downloads>,
+) -> CommandResult<()> {
+    {
+        let mut queue = downloads.queue()?;
+        if queue.pause(id)? {
+            if let Some(task) = downloads.tasks()?.remove(&id) {
+                task.abort();
+            }
+        }
+        emit_queue(&app, &mut queue);
+    }
+    pump_downloads(&app)
+}
+
+#[tauri::command]
+async fn resume_download(
+    id: u64,
+    app: tauri::AppHandle,
+    downloads: State<'_, Downloads>,
+) -> CommandResult<()> {
+    downloads.queue()?.resume(id)?;
+    pump_downloads(&app)
+}
+
+/// Stops a download and forgets it. Unfinished files are removed, finished
+/// ones stay.
+#[tauri::command]
+async fn cancel_download(
+    id: u64,
+    app: tauri::AppHandle,
+    downloads: State<'_, Downloads>,
+) -> CommandResult<()> {
+    {
+        let mut queue = downloads.queue()?;
+        let job = queue.cancel(id)?;
+        if let Some(task) = downloads.tasks()?.remove(&id) {
+            task.abort();
+        }
+        let path = std::path::Path::new(&job.request.path);
+        if job.state != queue::JobState::Done {
+            for file in [path.to_path_buf(), download::LocalIndex::path(path)] {
+                if file.exists() {
+                    if let Err(e) = std::fs::remove_file(&file) {
+                        println!("Failed to remove partial download. {}", e);
+                    }
+                }
+            }
+        }
+        emit_queue(&app, &mut queue);
+    }
+    pump_downloads(&app)
+}
+
+#[tauri::command]
+async fn clear_finished_downloads(
+    app: tauri::AppHandle,
+    downloads: State<'_, Downloads>,
+) -> CommandResult<()> {
+    let mut queue = downloads.queue()?;
+    queue.clear_finished();
+    emit_queue(&app, &mut queue);
+    Ok(())
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct SubtitleBatchReport {
+    /// Paths of the files written.
+    pub saved: Vec<String>,
+    /// One message per episode that could not be saved.
+    pub failed: Vec<String>,
+}
+
+/// `download_subtitles` for every episode of a season, into `dir`. Episodes
+/// without the track are reported and skipped.
+#[tauri::command(rename_all = "snake_case")]
+async fn download_season_subtitles(
+    season_id: String,
+    locale: String,
+    kind: Option<SubtitleKind>,
+    format: Option<SubtitleFormat>,
+    dir: String,
+    offset_ms: Option<i64>,
+    crunchyroll: State<'_, ViewerContext>,
+    cache: State<'_, SubtitleCache>,
+) -> CommandResult<SubtitleBatchReport> {
+    let kind = kind.unwrap_or_default();
+    let format = format.unwrap_or(SubtitleFormat::Ass);
+    let cr = current_session(&crunchyroll).await?;
+    let season: Media<Season> = cr
+        .media_from_id(season_id)
+        .await
+        .map_err(|e| CommandError::from(e).context("Failed to find the season."))?;
+    let episodes = season
+        .episodes()
+        .await
+        .map_err(|e| CommandError::from(e).context("Failed to get the episodes."))?;
+    let dir = std::path::PathBuf::from(dir);
+    let mut report = SubtitleBatchReport::default();
+    for ep in episodes {
+        let name = format!(
+            "{:02} {}.{}.{}",
+            ep.metadata.episode_number,
+            storage::file_stem(&ep.title),
+            subs::track_name(&locale, kind),
+            format.extension()
+        );
+        let path = dir.join(name);
+        let res = match subtitle_data(
+            &ep.id,
+            &locale,
+            kind,
+            format,
+            offset_ms.unwrap_or(0),
+            &crunchyroll,
+            &cache,
+        )
+        .await
+        {
+            Ok(data) => subs::write(&path, &data),
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(()) => report.saved.push(path.display().to_string()),
+            Err(e) => report
+                .failed
+                .push(format!("Episode {}: {}", ep.metadata.episode_number, e)),
+        }
+    }
+    Ok(report)
+}
+
+/// Converts a subtitle file from disk for the player, delayed by `offset_ms`.
+#[tauri::command(rename_all = "snake_case")]
+async fn load_subtitle_file(path: String, offset_ms: Option<i64>) -> CommandResult<SubtitleFile> {
+    let path = std::path::PathBuf::from(path);
+    let data = subs::read_local(&path, SubtitleFormat::Vtt)?;
+    Ok(SubtitleFile {
+        locale: path
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        format: SubtitleFormat::Vtt,
+        data: subs::shift(&data, SubtitleFormat::Vtt, offset_ms.unwrap_or(0)),
+    })
+}
+
+/// The settings of a fresh install, for the settings view's reset.
+#[tauri::command]
+async fn default_settings() -> CommandResult<Settings> {
+    Ok(Settings::default())
+}
+
+#[tauri::command]
+async fn get_settings(crunchyroll: State<'_, ViewerContext>) -> CommandResult<Settings> {
+    Ok(crunchyroll.settings.lock().await.clone())
+}
+
+/// Saves the settings, applies the ones the backend uses and tells every
+/// window through `settings::SETTINGS_EVENT`.
+#[tauri::command]
+async fn set_settings(
+    settings: Settings,
+    app: tauri::AppHandle,
+    crunchyroll: State<'_, ViewerContext>,
+    images: State<'_, ImageCache>,
+    subtitles: State<'_, SubtitleCache>,
+    downloads: State<'_, Downloads>,
+) -> CommandResult<Settings> {
+    let settings = settings.normalized();
+    settings
+        .save()
+        .map_err(|e| CommandError::from(e).context("Failed to save the settings."))?;
+    images.resize(settings.image_cache_bytes())?;
+    subtitles.resize(settings.subtitle_cache_bytes())?;
+    downloads.queue()?.set_parallel(settings.download_parallel);
+    pump_downloads(&app)?;
+    *crunchyroll.settings.lock().await = settings.clone();
+    if let Err(e) = app.emit_all(settings::SETTINGS_EVENT, &settings) {
+        println!("Failed to announce the new settings. {}", e);
+    }
+    Ok(settings)
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn get_subtitle_offset(
+    series_id: String,
+    crunchyroll: State<'_, ViewerContext>,
+) -> CommandResult<i64> {
+    Ok(crunchyroll.offsets.lock().await.get(&series_id))
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn set_subtitle_offset(
+    series_id: String,
+    offset_ms: i64,
+    crunchyroll: State<'_, ViewerContext>,
+) -> CommandResult<()> {
+    crunchyroll
+        .offsets
+        .lock()
+        .await
+        .set(&series_id, offset_ms)
+        .map_err(|e| CommandError::from(e).context("Failed to save the subtitle delay."))
+}
+
+#[tauri::command]
+async fn clear_image_cache(cache: State<'_, ImageCache>) -> CommandResult<String> {
+    cache
+        .clear()
+        .map_err(|e| e.context("Failed to clear the image cache."))?;
+    Ok("Image cache cleared.".to_string())
+}
+
+#[tauri::command]
+async fn clear_subtitle_cache(cache: State<'_, SubtitleCache>) -> CommandResult<String> {
+    cache
+        .clear()
+        .map_err(|e| e.context("Failed to clear the subtitle cache."))?;
+    Ok("Subtitle cache cleared.".to_string())
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn record_progress(
+    ep_id: String,
+    position: f64,
+    duration: Option<f64>,
+    crunchyroll: State<'_, ViewerContext>,
+) -> CommandResult<()> {
+    let account = crunchyroll.account.lock().await.clone();
+    if let Some(account) = account {
+        let mut pending = crunchyroll.pending.lock().await;
+        pending.push(
+            &account,
+            PendingUpdate {
+                episode_id: ep_id.clone(),
+                position,
+            },
+        );
+        if let Err(e) = pending.save() {
+            println!("Failed to queue playhead. {}", e);
+        }
+    }
+    crunchyroll
+        .history
+        .lock()
+        .await
+        .progress(&ep_id, position, duration.unwrap_or(0_f64))
+        .map_err(|e| CommandError::from(e).context("Failed to save progress."))
+}
+
+/// Exchanges playheads between the local history and the account. Local
+/// updates that can't be pushed (e.g. while offline) stay queued.
+#[tauri::command]
+async fn sync_playheads(
+    policy: Option<ConflictPolicy>,
+    crunchyroll: State<'_, ViewerContext>,
+) -> CommandResult<SyncReport> {
+    let cr = account_session(&crunchyroll).await?;
+    let account = crunchyroll
+        .account
+        .lock()
+        .await
+        .clone()
+        .ok_or(CommandError::NotLoggedIn)?;
+    sync::sync(
+        &cr,
+        &account,
+        &crunchyroll.history,
+        &crunchyroll.pending,
+        policy.unwrap_or_default(),
+    )
+    .await
+    .map_err(|e| CommandError::from(e).context("Failed to sync playheads."))
+}
+
+#[tauri::command(rename_all = "snake_case")]
+async fn get_playhead(
+    ep_id: String,
+    crunchyroll: State<'_, ViewerContext>,
+) -> CommandResult<Option<f64>> {
+    Ok(crunchyroll.history.lock().await.playhead(&ep_id))
+}
+
+#[tauri::command]
+async fn get_history(
+    limit: Option<usize>,
+    crunchyroll: State<'_, ViewerContext>,
+) -> CommandResult<Vec<HistoryEntry>> {
+    Ok(crunchyroll
+        .history
+        .lock()
+        .await
+        .recent(limit.unwrap_or(usize::MAX)))
+}
+
+#[tauri::command]
+async fn clear_history(crunchyroll: State<'_, ViewerContext>) -> CommandResult<String> {
+    crunchyroll
+        .history
+        .lock()
+        .await
+        .clear()
+        .map_err(|e| CommandError::from(e).context("Failed to clear watch history."))?;
+    Ok("Watch history cleared.".to_string())
+}
+
 #[derive(Serialize, Debug, Deserialize, Clone, Default, PartialEq, Eq)]
 pub struct epOrSeries {
     pub series_id: Option<String>,
@@ -406,30 +1499,20 @@ pub struct epOrSeries {
 async fn get_episodes(
     series_id: Option<String>,
     ep_id: Option<String>,
+    app: tauri::AppHandle,
     crunchyroll: State<'_, ViewerContext>,
-) -> Result<Vec<SearchResult>, String> {
-    let ses = &(*crunchyroll);
-    let aid = ses.session.lock().await;
-    let aid2 = if aid.as_ref().is_some() {
-        Ok(aid.as_ref().unwrap())
-    } else {
-        Err("Not Logged in.")
-    };
-    if aid2.is_err() {
-        return Err("You are not logged in.".to_string());
-    }
+) -> CommandResult<Vec<SearchResult>> {
+    let cr = current_session(&crunchyroll).await?;
     let result: Vec<Media<Episode>>;
-    let sr_ep: MediaCollection = if series_id.is_some() {
-        aid2?
-            .media_collection_from_id(series_id.unwrap())
-            .await
-            .unwrap()
-    } else {
-        aid2?
-            .media_collection_from_id(ep_id.unwrap())
-            .await
-            .unwrap()
+    let id = match (series_id, ep_id) {
+        (Some(id), _) => id,
+        (None, Some(id)) => id,
+        (None, None) => return Err(CommandError::not_found("series or episode id")),
     };
+    let sr_ep: MediaCollection = cr
+        .media_collection_from_id(id)
+        .await
+        .map_err(|e| CommandError::from(e).context("Failed to find the season."))?;
     let mut sr2: Option<Media<Season>> = None;
     let mut ep2: Option<Media<Episode>> = None;
     match sr_ep {
@@ -440,96 +1523,69 @@ async fn get_episodes(
         MediaCollection::Movie(_) => {}
     }
     let mut a: Vec<SearchResult> = vec![];
-    if sr2.is_some() {
-        let eps = sr2.unwrap().episodes().await;
-        result = eps.unwrap();
-        for r in result {
-            let i: Option<String> = if let Some(pic) = r.images.clone() {
-                let imgs = if let Some(a) = pic.thumbnail {
-                    a
-                } else if let Some(a) = pic.poster_tall {
-                    a
-                } else if let Some(a) = pic.poster_wide {
-                    a
-                } else if let Some(a) = pic.promo_image {
-                    a
-                } else {
-                    panic!("no pics");
-                };
-                let mut ba = Some("".to_string());
-                for mut pics in imgs {
-                    pics.sort_by(|l, j| j.height.cmp(&l.height));
-                    ba = Some(pics.first().unwrap().source.clone());
-                }
-                if ba.is_some() {
-                    ba
-                } else {
-                    Some("".to_string())
-                }
-            } else {
-                Some("".to_string())
-            };
-            a.push(SearchResult {
-                name: r.title,
-                id: r.id,
-                desc: r.description,
-                img: i,
-            });
-        }
-    } else if ep2.is_some() {
-        let ep3 = ep2.unwrap();
-        let i: Option<String> = if let Some(pic) = ep3.images.clone() {
-            let imgs = if let Some(a) = pic.thumbnail {
-                a
-            } else if let Some(a) = pic.poster_tall {
-                a
-            } else if let Some(a) = pic.poster_wide {
-                a
-            } else if let Some(a) = pic.promo_image {
-                a
-            } else {
-                panic!("no pics");
-            };
-            let mut ba = Some("".to_string());
-            for mut pics in imgs {
-                pics.sort_by(|l, j| j.height.cmp(&l.height));
-                ba = Some(pics.first().unwrap().source.clone());
-            }
-            if ba.is_some() {
-                ba
-            } else {
-                Some("".to_string())
-            }
-        } else {
-            Some("".to_string())
-        };
-        a.push(SearchResult {
-            name: ep3.title,
-            id: ep3.id,
-            desc: ep3.description,
-            img: i,
-        });
+    if let Some(season) = sr2 {
+        result = season
+            .episodes()
+            .await
+            .map_err(|e| CommandError::from(e).context("Failed to get the episodes."))?;
+        a.extend(result.into_iter().map(episode_result));
+    } else if let Some(ep3) = ep2 {
+        a.push(episode_result(ep3));
     }
-    Ok(a)
+    Ok(warmed(&app, a, images::GRID_WIDTH))
 }
 
 #[derive(Default)]
 pub struct ViewerContext {
     pub session: Mutex<Option<Crunchyroll>>,
+    /// Whether `session` holds one, for the download queue which can't wait
+    /// on the lock. Set by `logged_in` and `logout`.
+    pub signed_in: AtomicBool,
+    pub account: Mutex<Option<String>>,
+    pub restoring: Mutex<()>,
+    pub history: Mutex<History>,
+    pub pending: Mutex<PendingQueue>,
+    pub offsets: Mutex<SubtitleOffsets>,
+    pub settings: Mutex<Settings>,
+    /// A std mutex, the `crunchylocal` handler is not async.
+    pub library: std::sync::Mutex<Library>,
+}
+
+impl ViewerContext {
+    pub fn library(&self) -> CommandResult<std::sync::MutexGuard<'_, Library>> {
+        self.library
+            .lock()
+            .map_err(|e| CommandError::Io(e.to_string()))
+    }
 }
 
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("download") {
+        if let Err(e) = tauri::async_runtime::block_on(download::cli(&args[2..])) {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
     let quit = CustomMenuItem::new("quit".to_string(), "Quit");
     let fullscreen = CustomMenuItem::new("fullscreen".to_string(), "Toggle Fullscreen");
     let devtools = CustomMenuItem::new("devtools".to_string(), "Toggle devtools");
+    let settings_item = CustomMenuItem::new("settings".to_string(), "Settings");
+    let downloads_item = CustomMenuItem::new("downloads".to_string(), "Downloads");
+    let library_item = CustomMenuItem::new("library".to_string(), "Offline library");
     let submenu = Submenu::new(
         "File",
         Menu::new()
+            .add_item(settings_item)
+            .add_item(downloads_item)
+            .add_item(library_item)
             .add_item(fullscreen)
             .add_item(devtools)
             .add_item(quit),
     );
     let menu = Menu::new().add_submenu(submenu);
+    let settings = Settings::load();
     tauri::Builder::default()
         .setup(|app| {
             use tauri::GlobalShortcutManager;
@@ -547,16 +1603,50 @@ fn main() {
                         .set_fullscreen(decorated)
                         .unwrap();
                 })?;
+            let app3 = app.app_handle();
+            tauri::async_runtime::spawn(async move {
+                // unfinished downloads carry on once there is a session,
+                // without one they wait for the frontend's login
+                match restore_saved(&app3.state::<ViewerContext>()).await {
+                    Ok(_) => logged_in(&app3),
+                    Err(e) => println!("{}", e),
+                }
+            });
             Ok(())
         })
+        .register_uri_scheme_protocol(images::IMAGE_SCHEME, images::serve)
+        .register_uri_scheme_protocol(library::LOCAL_SCHEME, library::serve)
+        .manage(ImageCache::open(settings.image_cache_bytes()))
+        .manage(SubtitleCache::open(settings.subtitle_cache_bytes()))
+        .manage(Downloads::load(settings.download_parallel))
         .manage(ViewerContext {
-            session: Default::default(),
+            history: Mutex::new(History::load()),
+            pending: Mutex::new(PendingQueue::load()),
+            offsets: Mutex::new(SubtitleOffsets::load()),
+            settings: Mutex::new(settings),
+            library: std::sync::Mutex::new(Library::load()),
+            ..Default::default()
         })
         .menu(menu)
         .on_menu_event(|event| match event.menu_item_id() {
             "quit" => {
                 std::process::exit(0);
             }
+            "settings" => {
+                if let Err(e) = event.window().emit("open-settings", ()) {
+                    println!("Failed to open the settings. {}", e);
+                }
+            }
+            "downloads" => {
+                if let Err(e) = event.window().emit("open-downloads", ()) {
+                    println!("Failed to open the downloads. {}", e);
+                }
+            }
+            "library" => {
+                if let Err(e) = event.window().emit("open-library", ()) {
+                    println!("Failed to open the library. {}", e);
+                }
+            }
             "fullscreen" => {
                 if event.window().is_fullscreen().unwrap() {
                     event.window().set_fullscreen(false).unwrap();
@@ -576,11 +1666,48 @@ fn main() {
         .invoke_handler(tauri::generate_handler![
             login,
             login_anon,
+            restore_session,
+            list_accounts,
+            switch_account,
+            logout,
+            vault_unlock,
+            vault_forget,
+            vault_status,
             get_seasons,
             get_episodes,
             view_episode,
+            get_subtitle,
+            get_ass_script,
+            load_subtitle_file,
+            download_subtitles,
+            download_season_subtitles,
+            download_episode,
+            queue_download,
+            list_downloads,
+            pause_download,
+            resume_download,
+            cancel_download,
+            clear_finished_downloads,
+            list_offline,
+            view_offline_episode,
+            delete_offline,
+            get_settings,
+            default_settings,
+            set_settings,
+            get_subtitle_offset,
+            set_subtitle_offset,
+            clear_image_cache,
+            clear_subtitle_cache,
             search_crunchy,
-            get_recs
+            get_recs,
+            get_watchlist,
+            add_to_watchlist,
+            remove_from_watchlist,
+            record_progress,
+            get_playhead,
+            sync_playheads,
+            get_history,
+            clear_history
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
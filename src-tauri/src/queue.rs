@@ -0,0 +1,514 @@
+use crate::download::{DownloadProgress, Resume};
+use crate::error::{CommandError, CommandResult};
+use crate::settings::{Quality, SubtitleKind};
+use crate::storage;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tauri::async_runtime::JoinHandle;
+
+/// Emitted to the frontend with every `DownloadJob` after each change.
+pub const QUEUE_EVENT: &str = "download-queue";
+
+/// Attempts per job before it is marked failed.
+const MAX_ATTEMPTS: u32 = 5;
+const BACKOFF_BASE: Duration = Duration::from_secs(2);
+const BACKOFF_MAX: Duration = Duration::from_secs(120);
+/// Progress alone is written to disk at most this often.
+const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Downloads running at once when the settings do not say. Every download
+/// already fetches several segments at a time, so only half the cores.
+pub fn default_parallel() -> usize {
+    (num_cpus::get() / 2).clamp(1, 4)
+}
+
+/// What to download, as asked by the frontend.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DownloadRequest {
+    pub ep_id: String,
+    /// Shown in the downloads view.
+    pub title: String,
+    pub quality: Quality,
+    /// Dub locale, `None` for the episode as given.
+    pub audio: Option<String>,
+    /// Subtitle locales saved next to the video.
+    pub subs: Vec<String>,
+    /// Kind of the `subs` tracks.
+    #[serde(default)]
+    pub subs_kind: SubtitleKind,
+    /// The video file, or a folder until the first progress names the file
+    /// after the resolved episode.
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    Queued,
+    Running,
+    /// Waiting out the backoff after a failed attempt.
+    Retrying,
+    Paused,
+    Failed,
+    Done,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DownloadJob {
+    pub id: u64,
+    pub request: DownloadRequest,
+    pub state: JobState,
+    /// Segments written, in step with `bytes`.
+    pub done: usize,
+    pub total: usize,
+    pub bytes: u64,
+    pub attempts: u32,
+    pub error: Option<String>,
+    /// Bytes per second over the current attempt, 0 when not running.
+    #[serde(default)]
+    pub speed: f64,
+    #[serde(default)]
+    pub eta_secs: Option<u64>,
+}
+
+impl DownloadJob {
+    fn resume(&self) -> Resume {
+        Resume {
+            done: self.done,
+            bytes: self.bytes,
+        }
+    }
+
+    fn is_active(&self) -> bool {
+        matches!(self.state, JobState::Running | JobState::Retrying)
+    }
+}
+
+/// Where the current attempt of a job started, for its speed.
+struct Attempt {
+    started: Instant,
+    done: usize,
+    bytes: u64,
+}
+
+/// Download jobs, kept on disk so unfinished ones survive a restart. Only
+/// the bookkeeping lives here, running the jobs is up to the caller.
+pub struct DownloadQueue {
+    path: PathBuf,
+    jobs: Vec<DownloadJob>,
+    next_id: u64,
+    parallel: usize,
+    attempts: HashMap<u64, Attempt>,
+    saved: Option<Instant>,
+}
+
+impl DownloadQueue {
+    pub fn load(parallel: usize) -> DownloadQueue {
+        DownloadQueue::open(storage::data_dir().join("download_queue.json"), parallel)
+    }
+
+    pub fn open(path: PathBuf, parallel: usize) -> DownloadQueue {
+        let mut jobs: Vec<DownloadJob> = storage::load_json(&path).unwrap_or_default();
+        // whatever was running when the app closed starts again
+        for job in jobs.iter_mut().filter(|j| j.is_active()) {
+            job.state = JobState::Queued;
+            job.speed = 0_f64;
+            job.eta_secs = None;
+        }
+        DownloadQueue {
+            path,
+            next_id: jobs.iter().map(|j| j.id + 1).max().unwrap_or(1),
+            jobs,
+            parallel: parallel.max(1),
+            attempts: HashMap::new(),
+            saved: None,
+        }
+    }
+
+    pub fn save(&mut self) -> Result<()> {
+        storage::save_json(&self.path, &self.jobs)?;
+        self.saved = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Whether the progress made since the last `save` should be written.
+    pub fn save_due(&self) -> bool {
+        self.saved.map_or(true, |at| at.elapsed() >= SAVE_INTERVAL)
+    }
+
+    pub fn jobs(&self) -> Vec<DownloadJob> {
+        self.jobs.clone()
+    }
+
+    pub fn set_parallel(&mut self, parallel: usize) {
+        self.parallel = parallel.max(1);
+    }
+
+    fn job(&mut self, id: u64) -> Option<&mut DownloadJob> {
+        self.jobs.iter_mut().find(|j| j.id == id)
+    }
+
+    pub fn add(&mut self, request: DownloadRequest) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.jobs.push(DownloadJob {
+            id,
+            request,
+            state: JobState::Queued,
+            done: 0,
+            total: 0,
+            bytes: 0,
+            attempts: 0,
+            error: None,
+            speed: 0_f64,
+            eta_secs: None,
+        });
+        id
+    }
+
+    fn begin(&mut self, id: u64) -> Option<(DownloadRequest, Resume)> {
+        let job = self.job(id)?;
+        job.state = JobState::Running;
+        let attempt = Attempt {
+            started: Instant::now(),
+            done: job.done,
+            bytes: job.bytes,
+        };
+        let started = (job.request.clone(), job.resume());
+        self.attempts.insert(id, attempt);
+        Some(started)
+    }
+
+    /// Marks the oldest queued job running when there is room for it.
+    pub fn start_next(&mut self) -> Option<(u64, DownloadRequest, Resume)> {
+        if self.jobs.iter().filter(|j| j.is_active()).count() >= self.parallel {
+            return None;
+        }
+        let id = self.jobs.iter().find(|j| j.state == JobState::Queued)?.id;
+        let (request, from) = self.begin(id)?;
+        Some((id, request, from))
+    }
+
+    pub fn progress(&mut self, id: u64, progress: &DownloadProgress) {
+        let attempt = self.attempts.get(&id);
+        let job = match self.jobs.iter_mut().find(|j| j.id == id) {
+            Some(job) => job,
+            None => return,
+        };
+        // the extension follows the container once the stream is known
+        if !progress.path.is_empty() {
+            job.request.path = progress.path.clone();
+        }
+        job.done = progress.done;
+        job.total = progress.total;
+        job.bytes = progress.bytes;
+        if let Some(attempt) = attempt {
+            let secs = attempt.started.elapsed().as_secs_f64();
+            let segments = job.done.saturating_sub(attempt.done);
+            if secs > 0_f64 && segments > 0 {
+                job.speed = job.bytes.saturating_sub(attempt.bytes) as f64 / secs;
+                let left = job.total.saturating_sub(job.done);
+                job.eta_secs = Some((secs / segments as f64 * left as f64).round() as u64);
+            }
+        }
+    }
+
+    /// Records how an attempt ended. Returns how long to wait before trying
+    /// again, `None` when the job is over.
+    pub fn finished<T>(&mut self, id: u64, result: &CommandResult<T>) -> Option<Duration> {
+        self.attempts.remove(&id);
+        let job = self.job(id)?;
+        job.speed = 0_f64;
+        job.eta_secs = None;
+        match result {
+            Ok(_) => {
+                job.state = JobState::Done;
+                job.error = None;
+                None
+            }
+            // not the job's fault, it waits for the next login
+            Err(CommandError::NotLoggedIn) => {
+                job.state = JobState::Queued;
+                job.error = Some(CommandError::NotLoggedIn.to_string());
+                None
+            }
+            Err(e) => {
+                job.attempts += 1;
+                job.error = Some(e.to_string());
+                // only the network is worth waiting for
+                if matches!(e, CommandError::Network(_)) && job.attempts < MAX_ATTEMPTS {
+                    job.state = JobState::Retrying;
+                    Some(backoff(job.attempts))
+                } else {
+                    job.state = JobState::Failed;
+                    None
+                }
+            }
+        }
+    }
+
+    /// Starts the next attempt of a job that waited out its backoff, unless
+    /// it was paused or cancelled meanwhile.
+    pub fn retry(&mut self, id: u64) -> Option<(DownloadRequest, Resume)> {
+        if self.job(id)?.state != JobState::Retrying {
+            return None;
+        }
+        self.begin(id)
+    }
+
+    /// Returns whether the job was running, its task has to be stopped then.
+    pub fn pause(&mut self, id: u64) -> CommandResult<bool> {
+        let job = self
+            .job(id)
+            .ok_or_else(|| CommandError::not_found("download"))?;
+        let was_active = job.is_active();
+        if was_active || job.state == JobState::Queued {
+            job.state = JobState::Paused;
+            job.speed = 0_f64;
+            job.eta_secs = None;
+        }
+        self.attempts.remove(&id);
+        Ok(was_active)
+    }
+
+    /// Queues a paused or failed job again, it carries on where it stopped.
+    pub fn resume(&mut self, id: u64) -> CommandResult<()> {
+        let job = self
+            .job(id)
+            .ok_or_else(|| CommandError::not_found("download"))?;
+        if matches!(job.state, JobState::Paused | JobState::Failed) {
+            job.state = JobState::Queued;
+            job.attempts = 0;
+            job.error = None;
+        }
+        Ok(())
+    }
+
+    /// Drops a job from the queue and returns it.
+    pub fn cancel(&mut self, id: u64) -> CommandResult<DownloadJob> {
+        let index = self
+            .jobs
+            .iter()
+            .position(|j| j.id == id)
+            .ok_or_else(|| CommandError::not_found("download"))?;
+        self.attempts.remove(&id);
+        Ok(self.jobs.remove(index))
+    }
+
+    pub fn clear_finished(&mut self) {
+        self.jobs.retain(|j| j.state != JobState::Done);
+    }
+}
+
+/// 2s, 4s, 8s... up to two minutes.
+fn backoff(attempts: u32) -> Duration {
+    (BACKOFF_BASE * 2_u32.pow(attempts.saturating_sub(1).min(10))).min(BACKOFF_MAX)
+}
+
+/// The queue and the tasks running its jobs, managed by tauri. Lock the
+/// queue before the tasks when both are needed.
+pub struct Downloads {
+    queue: Mutex<DownloadQueue>,
+    tasks: Mutex<HashMap<u64, JoinHandle<()>>>,
+}
+
+impl Downloads {
+    pub fn load(parallel: usize) -> Downloads {
+        Downloads {
+            queue: Mutex::new(DownloadQueue::load(parallel)),
+            tasks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn queue(&self) -> CommandResult<MutexGuard<'_, DownloadQueue>> {
+        self.queue
+            .lock()
+            .map_err(|e| CommandError::Io(e.to_string()))
+    }
+
+    pub fn tasks(&self) -> CommandResult<MutexGuard<'_, HashMap<u64, JoinHandle<()>>>> {
+        self.tasks
+            .lock()
+            .map_err(|e| CommandError::Io(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(name: &str, parallel: usize) -> DownloadQueue {
+        let path = std::env::temp_dir().join(format!(
+            "crunchyview-queue-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        DownloadQueue::open(path, parallel)
+    }
+
+    fn request(ep_id: &str) -> DownloadRequest {
+        DownloadRequest {
+            ep_id: ep_id.to_string(),
+            title: ep_id.to_string(),
+            quality: Quality::Highest,
+            audio: None,
+            subs: vec![],
+            subs_kind: SubtitleKind::Subtitles,
+            path: format!("/downloads/{}.ts", ep_id),
+        }
+    }
+
+    fn state(queue: &DownloadQueue, id: u64) -> JobState {
+        queue.jobs.iter().find(|j| j.id == id).unwrap().state
+    }
+
+    fn network() -> CommandResult<()> {
+        Err(CommandError::Network("timed out".to_string()))
+    }
+
+    #[test]
+    fn runs_as_many_as_allowed_in_order() {
+        let mut queue = queue("order", 2);
+        let ids: Vec<u64> = ["a", "b", "c"]
+            .iter()
+            .map(|e| queue.add(request(e)))
+            .collect();
+        assert_eq!(queue.start_next().map(|(id, ..)| id), Some(ids[0]));
+        assert_eq!(queue.start_next().map(|(id, ..)| id), Some(ids[1]));
+        assert!(queue.start_next().is_none());
+        assert_eq!(queue.finished(ids[0], &Ok(())), None);
+        assert_eq!(state(&queue, ids[0]), JobState::Done);
+        assert_eq!(queue.start_next().map(|(id, ..)| id), Some(ids[2]));
+        queue.clear_finished();
+        assert_eq!(queue.jobs().len(), 2);
+    }
+
+    #[test]
+    fn retries_the_network_with_backoff() {
+        let mut queue = queue("retry", 1);
+        let id = queue.add(request("a"));
+        queue.start_next().unwrap();
+        let progress = DownloadProgress {
+            id: "a".to_string(),
+            path: "/downloads/a.mp4".to_string(),
+            done: 3,
+            total: 10,
+            bytes: 300,
+        };
+        queue.progress(id, &progress);
+
+        assert_eq!(queue.finished(id, &network()), Some(BACKOFF_BASE));
+        assert_eq!(state(&queue, id), JobState::Retrying);
+        // a retrying job still takes its slot
+        let other = queue.add(request("b"));
+        assert!(queue.start_next().is_none());
+
+        let (request, from) = queue.retry(id).unwrap();
+        assert_eq!(request.path, "/downloads/a.mp4");
+        assert_eq!(
+            from,
+            Resume {
+                done: 3,
+                bytes: 300
+            }
+        );
+        assert_eq!(state(&queue, id), JobState::Running);
+        assert_eq!(queue.finished(id, &network()), Some(BACKOFF_BASE * 2));
+
+        // anything else fails right away
+        queue.retry(id).unwrap();
+        let missing: CommandResult<()> = Err(CommandError::NotFound("gone".to_string()));
+        assert_eq!(queue.finished(id, &missing), None);
+        assert_eq!(state(&queue, id), JobState::Failed);
+        assert_eq!(queue.start_next().map(|(id, ..)| id), Some(other));
+    }
+
+    #[test]
+    fn waits_for_a_login() {
+        let mut queue = queue("login", 1);
+        let id = queue.add(request("a"));
+        queue.start_next().unwrap();
+        assert_eq!(queue.finished(id, &network()), Some(BACKOFF_BASE));
+        queue.retry(id).unwrap();
+
+        let logged_out: CommandResult<()> = Err(CommandError::NotLoggedIn);
+        assert_eq!(queue.finished(id, &logged_out), None);
+        assert_eq!(state(&queue, id), JobState::Queued);
+        assert_eq!(queue.jobs()[0].attempts, 1);
+        assert_eq!(queue.start_next().map(|(id, ..)| id), Some(id));
+    }
+
+    #[test]
+    fn gives_up_after_max_attempts() {
+        let mut queue = queue("give-up", 1);
+        let id = queue.add(request("a"));
+        queue.start_next().unwrap();
+        for _ in 1..MAX_ATTEMPTS {
+            assert!(queue.finished(id, &network()).is_some());
+            queue.retry(id).unwrap();
+        }
+        assert_eq!(queue.finished(id, &network()), None);
+        assert_eq!(state(&queue, id), JobState::Failed);
+
+        // resuming starts the count over, from where it stopped
+        queue.resume(id).unwrap();
+        assert_eq!(state(&queue, id), JobState::Queued);
+        assert_eq!(queue.jobs()[0].attempts, 0);
+        assert_eq!(queue.start_next().map(|(id, ..)| id), Some(id));
+    }
+
+    #[test]
+    fn pauses_resumes_and_cancels() {
+        let mut queue = queue("pause", 1);
+        let id = queue.add(request("a"));
+        let queued = queue.add(request("b"));
+        queue.start_next().unwrap();
+        assert!(queue.pause(id).unwrap());
+        assert!(!queue.pause(queued).unwrap());
+        assert_eq!(state(&queue, queued), JobState::Paused);
+        assert!(queue.start_next().is_none());
+
+        // a backoff running out after a pause doesn't restart the job
+        assert_eq!(queue.retry(id), None);
+        assert_eq!(state(&queue, id), JobState::Paused);
+
+        queue.resume(id).unwrap();
+        assert_eq!(queue.start_next().map(|(id, ..)| id), Some(id));
+        assert_eq!(queue.cancel(id).unwrap().request.ep_id, "a");
+        assert!(queue.cancel(id).is_err());
+        assert!(queue.pause(id).is_err());
+        assert_eq!(queue.jobs().len(), 1);
+    }
+
+    #[test]
+    fn running_jobs_are_queued_again_on_load() {
+        let mut queue = queue("load", 2);
+        let running = queue.add(request("a"));
+        let done = queue.add(request("b"));
+        queue.start_next().unwrap();
+        queue.start_next().unwrap();
+        queue.finished(done, &Ok(()));
+        assert!(queue.save_due());
+        queue.save().unwrap();
+        assert!(!queue.save_due());
+
+        let loaded = DownloadQueue::open(queue.path.clone(), 2);
+        assert_eq!(state(&loaded, running), JobState::Queued);
+        assert_eq!(state(&loaded, done), JobState::Done);
+        assert_eq!(loaded.next_id, done + 1);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        assert_eq!(backoff(1), Duration::from_secs(2));
+        assert_eq!(backoff(2), Duration::from_secs(4));
+        assert_eq!(backoff(4), Duration::from_secs(16));
+        assert_eq!(backoff(7), BACKOFF_MAX);
+        assert_eq!(backoff(u32::MAX), BACKOFF_MAX);
+    }
+}
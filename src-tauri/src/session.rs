@@ -0,0 +1,115 @@
+use crate::storage;
+use anyhow::{anyhow, Result};
+use crunchyroll_rs::crunchyroll::SessionToken;
+use crunchyroll_rs::Crunchyroll;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+/// What gets written to disk for a logged in user. Only the refresh token is
+/// kept, never the password.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SavedSession {
+    pub username: String,
+    pub token: StoredToken,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum StoredToken {
+    RefreshToken(String),
+    EtpRt(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct LastSession {
+    username: Option<String>,
+}
+
+fn sessions_dir() -> PathBuf {
+    storage::config_dir().join("sessions")
+}
+
+fn session_path(username: &str) -> PathBuf {
+    sessions_dir().join(format!("{}.json", storage::escape_name(username)))
+}
+
+/// Outside `sessions_dir`, no username can land on it.
+fn last_path() -> PathBuf {
+    storage::config_dir().join("last_session.json")
+}
+
+/// Stores the refresh token of `crunchyroll` for `username` and marks it as
+/// the session to restore on the next launch. Anonymous sessions are skipped.
+pub async fn save(username: &str, crunchyroll: &Crunchyroll) -> Result<()> {
+    let token = match crunchyroll.session_token().await {
+        SessionToken::RefreshToken(t) => StoredToken::RefreshToken(t),
+        SessionToken::EtpRt(t) => StoredToken::EtpRt(t),
+        SessionToken::Anonymous => return Ok(()),
+    };
+    // the refresh token is as good as the password
+    storage::save_private_json(
+        &session_path(username),
+        &SavedSession {
+            username: username.to_string(),
+            token,
+        },
+    )?;
+    storage::save_json(
+        &last_path(),
+        &LastSession {
+            username: Some(username.to_string()),
+        },
+    )
+}
+
+pub fn load(username: &str) -> Option<SavedSession> {
+    storage::load_json(&session_path(username))
+}
+
+/// The user whose session was saved most recently.
+pub fn last_username() -> Option<String> {
+    storage::load_json::<LastSession>(&last_path())?.username
+}
+
+/// Logs in again using the stored token of `username`.
+pub async fn restore(username: &str) -> Result<Crunchyroll> {
+    let saved = load(username).ok_or_else(|| anyhow!("No saved session for {}.", username))?;
+    let builder = Crunchyroll::builder();
+    let crunchyroll = match saved.token {
+        StoredToken::RefreshToken(t) => builder.login_with_refresh_token(t).await?,
+        StoredToken::EtpRt(t) => builder.login_with_etp_rt(t).await?,
+    };
+    Ok(crunchyroll)
+}
+
+/// Everyone with a stored session, sorted by name.
+pub fn saved_usernames() -> Vec<String> {
+    let mut names: Vec<String> = match fs::read_dir(sessions_dir()) {
+        Ok(dir) => dir
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.extension().map_or(false, |e| e == "json"))
+            .filter_map(|p| storage::load_json::<SavedSession>(&p))
+            .map(|s| s.username)
+            .collect(),
+        Err(_) => vec![],
+    };
+    names.sort();
+    names
+}
+
+/// Stops restoring anyone at startup, keeping the saved sessions around.
+pub fn clear_last() -> Result<()> {
+    storage::save_json(&last_path(), &LastSession::default())
+}
+
+pub fn remove(username: &str) -> Result<()> {
+    let path = session_path(username);
+    if path.exists() {
+        fs::remove_file(path)?;
+    }
+    if last_username().as_deref() == Some(username) {
+        clear_last()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,242 @@
+use crate::queue;
+use crate::storage;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::path::{Path, PathBuf};
+
+/// Bumped whenever a field changes meaning, `load` then has to convert
+/// older files.
+pub const SETTINGS_VERSION: u32 = 1;
+
+/// Emitted to the frontend with the new `Settings` after every change.
+pub const SETTINGS_EVENT: &str = "settings-changed";
+
+const MB: u64 = 1024 * 1024;
+/// Upper bounds of the cache sizes, in MB.
+const MAX_IMAGE_CACHE_MB: u64 = 16 * 1024;
+const MAX_SUBTITLE_CACHE_MB: u64 = 1024;
+
+/// The two kinds of subtitle tracks. An episode can offer both in one
+/// locale, so a track is only known by its locale and kind together.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SubtitleKind {
+    Subtitles,
+    Captions,
+}
+
+impl Default for SubtitleKind {
+    fn default() -> Self {
+        SubtitleKind::Subtitles
+    }
+}
+
+/// Starting quality of the player.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Quality {
+    Auto,
+    Lowest,
+    Highest,
+}
+
+impl Default for Quality {
+    fn default() -> Self {
+        Quality::Lowest
+    }
+}
+
+/// User preferences. Fields missing from the file keep their default.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct Settings {
+    pub version: u32,
+    /// Locale code like `ja-JP`, empty for no preference.
+    pub audio_locale: String,
+    /// Locale code of the track shown when an episode starts, empty to start
+    /// without subtitles.
+    pub subtitle_locale: String,
+    pub subtitle_kind: SubtitleKind,
+    pub quality: Quality,
+    /// 0 to 1.
+    pub volume: f64,
+    /// Start playing as soon as an episode is loaded.
+    pub autoplay: bool,
+    /// Text size relative to the default, for every subtitle kind.
+    pub subtitle_scale: f64,
+    /// Css color of plain subtitles and captions.
+    pub subtitle_color: String,
+    /// Dark box behind plain subtitles and captions.
+    pub subtitle_background: bool,
+    pub image_cache_mb: u64,
+    pub subtitle_cache_mb: u64,
+    /// How often the player refreshes its progress bar and overlays.
+    pub progress_tick_ms: u64,
+    /// Queued downloads running at once.
+    pub download_parallel: usize,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            version: SETTINGS_VERSION,
+            audio_locale: "ja-JP".to_string(),
+            subtitle_locale: "en-US".to_string(),
+            subtitle_kind: SubtitleKind::Subtitles,
+            quality: Quality::Lowest,
+            volume: 1_f64,
+            autoplay: false,
+            subtitle_scale: 1_f64,
+            subtitle_color: "#ffffff".to_string(),
+            subtitle_background: false,
+            image_cache_mb: 256,
+            subtitle_cache_mb: 64,
+            progress_tick_ms: 100,
+            download_parallel: queue::default_parallel(),
+        }
+    }
+}
+
+fn settings_path() -> PathBuf {
+    storage::config_dir().join("settings.json")
+}
+
+/// Reads `value` over the defaults one field at a time, so a field that
+/// doesn't parse (say an unknown quality) only loses itself.
+fn from_value(value: Value) -> Settings {
+    let fields = match value {
+        Value::Object(fields) => fields,
+        _ => return Settings::default(),
+    };
+    let mut settings = Settings::default();
+    for (key, field) in fields {
+        let mut candidate = match serde_json::to_value(&settings) {
+            Ok(candidate) => candidate,
+            Err(_) => break,
+        };
+        candidate[key.as_str()] = field;
+        match serde_json::from_value(candidate) {
+            Ok(read) => settings = read,
+            Err(e) => println!("Ignoring the {} setting. {}", key, e),
+        }
+    }
+    settings
+}
+
+fn read(path: &Path) -> Settings {
+    match storage::load_json(path) {
+        Some(value) => from_value(value).normalized(),
+        None => Settings::default(),
+    }
+}
+
+impl Settings {
+    pub fn load() -> Settings {
+        read(&settings_path())
+    }
+
+    pub fn save(&self) -> Result<()> {
+        storage::save_json(&settings_path(), self)
+    }
+
+    /// Keeps values the frontend sends within what the app can use.
+    pub fn normalized(mut self) -> Settings {
+        self.version = SETTINGS_VERSION;
+        self.volume = self.volume.clamp(0_f64, 1_f64);
+        self.subtitle_scale = self.subtitle_scale.clamp(0.5_f64, 3_f64);
+        self.image_cache_mb = self.image_cache_mb.clamp(16, MAX_IMAGE_CACHE_MB);
+        self.subtitle_cache_mb = self.subtitle_cache_mb.clamp(4, MAX_SUBTITLE_CACHE_MB);
+        self.progress_tick_ms = self.progress_tick_ms.clamp(50, 1000);
+        self.download_parallel = self.download_parallel.clamp(1, 16);
+        self
+    }
+
+    pub fn image_cache_bytes(&self) -> u64 {
+        self.image_cache_mb * MB
+    }
+
+    pub fn subtitle_cache_bytes(&self) -> u64 {
+        self.subtitle_cache_mb * MB
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn file(name: &str, value: &Value) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "crunchyview-settings-{}-{}.json",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, value.to_string()).unwrap();
+        path
+    }
+
+    #[test]
+    fn normalized_clamps() {
+        let settings = Settings {
+            volume: 2_f64,
+            subtitle_scale: 0_f64,
+            image_cache_mb: u64::MAX,
+            subtitle_cache_mb: 0,
+            progress_tick_ms: 5,
+            download_parallel: 0,
+            ..Default::default()
+        }
+        .normalized();
+        assert_eq!(settings.volume, 1_f64);
+        assert_eq!(settings.subtitle_scale, 0.5_f64);
+        assert_eq!(settings.image_cache_bytes(), MAX_IMAGE_CACHE_MB * MB);
+        assert_eq!(settings.subtitle_cache_bytes(), 4 * MB);
+        assert_eq!(settings.progress_tick_ms, 50);
+        assert_eq!(settings.download_parallel, 1);
+        assert_eq!(settings.clone().normalized(), settings);
+    }
+
+    #[test]
+    fn a_bad_field_only_loses_itself() {
+        let path = file(
+            "bad-field",
+            &json!({
+                "version": SETTINGS_VERSION,
+                "audio_locale": "de-DE",
+                "quality": "best",
+                "volume": "loud",
+                "autoplay": true,
+            }),
+        );
+        let settings = read(&path);
+        assert_eq!(settings.audio_locale, "de-DE");
+        assert!(settings.autoplay);
+        assert_eq!(settings.quality, Settings::default().quality);
+        assert_eq!(settings.volume, Settings::default().volume);
+    }
+
+    #[test]
+    fn missing_or_unreadable_files_are_the_defaults() {
+        let path = std::env::temp_dir().join("crunchyview-settings-missing.json");
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(read(&path), Settings::default());
+        assert_eq!(read(&file("array", &json!([1, 2]))), Settings::default());
+    }
+
+    #[test]
+    fn any_version_is_read_as_is() {
+        // no version field, or one from another build, keeps its values and
+        // gets the current version
+        for version in [None, Some(0), Some(SETTINGS_VERSION + 1)] {
+            let mut value = json!({ "subtitle_locale": "fr-FR", "image_cache_mb": 32 });
+            if let Some(version) = version {
+                value["version"] = json!(version);
+            }
+            let settings = read(&file("version", &value));
+            assert_eq!(settings.version, SETTINGS_VERSION);
+            assert_eq!(settings.subtitle_locale, "fr-FR");
+            assert_eq!(settings.image_cache_mb, 32);
+        }
+    }
+}
@@ -0,0 +1,126 @@
+use anyhow::Result;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+const APP_DIR: &str = "crunchyview";
+
+/// Directory for files that should survive restarts (sessions, settings...).
+pub fn config_dir() -> PathBuf {
+    dirs::config_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(APP_DIR)
+}
+
+/// Directory for data the app accumulates (watch history...).
+pub fn data_dir() -> PathBuf {
+    dirs::data_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(APP_DIR)
+}
+
+/// Directory for anything that can be thrown away and fetched again.
+pub fn cache_dir() -> PathBuf {
+    dirs::cache_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(APP_DIR)
+}
+
+/// Reads a json file, returning `None` if it is missing or unreadable.
+pub fn load_json<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    let raw = fs::read(path).ok()?;
+    match serde_json::from_slice(&raw) {
+        Ok(v) => Some(v),
+        Err(e) => {
+            println!("Ignoring unreadable file {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Writes `value` as json, going through a temporary file so a crash never
+/// leaves a half written file behind.
+pub fn save_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(value)?)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// `save_json` for secrets such as tokens, only readable by the current user
+/// on unix.
+pub fn save_private_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    write_private(&tmp, &serde_json::to_vec_pretty(value)?)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+/// Writes `data` to a file created with mode 0600, tightening the mode of a
+/// file that was already there.
+pub fn write_private(path: &Path, data: &[u8]) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        if path.exists() {
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+    }
+    options.open(path)?.write_all(data)?;
+    Ok(())
+}
+
+/// Turns a user supplied name into something safe to use as a file name.
+pub fn file_stem(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '@' || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Turns a user supplied name into a file name that only it maps to: bytes
+/// other than lowercase letters, digits, `@`, `.` and `-` are written as
+/// `%XX`, upper case included so case insensitive file systems keep them
+/// apart too.
+pub fn escape_name(name: &str) -> String {
+    let mut escaped = String::with_capacity(name.len());
+    for b in name.bytes() {
+        match b {
+            b'a'..=b'z' | b'0'..=b'9' | b'@' | b'.' | b'-' => escaped.push(b as char),
+            _ => escaped.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escaped_names_stay_apart() {
+        assert_eq!(escape_name("a.b-c@x.com"), "a.b-c@x.com");
+        assert_eq!(escape_name("a+b@x.com"), "a%2Bb@x.com");
+        assert_eq!(escape_name("a_b@x.com"), "a%5Fb@x.com");
+        assert_eq!(escape_name("Bob"), "%42ob");
+        assert_eq!(escape_name("a%2b"), "a%252b");
+        assert_eq!(escape_name("../x"), "..%2Fx");
+        assert_eq!(escape_name("é"), "%C3%A9");
+    }
+}
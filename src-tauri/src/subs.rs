@@ -0,0 +1,446 @@
+use crate::cache::DiskCache;
+use crate::error::{CommandError, CommandResult};
+use crate::settings::SubtitleKind;
+use crate::storage;
+use crunchyroll_rs::{Crunchyroll, Episode, Locale, Media};
+use rsubs_lib::{srt, ssa, vtt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SubtitleFormat {
+    Vtt,
+    Srt,
+    Ass,
+}
+
+impl Default for SubtitleFormat {
+    fn default() -> Self {
+        SubtitleFormat::Vtt
+    }
+}
+
+/// A subtitle track an episode offers, without its content.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SubtitleInfo {
+    /// The locale code, e.g. `en-US`, used with `kind` to ask for the track.
+    pub locale: String,
+    pub label: String,
+    pub kind: SubtitleKind,
+}
+
+/// How a track is named in file names and cache keys. Captions get a `.cc`
+/// suffix so they don't overwrite the subtitles of the same locale.
+pub fn track_name(locale: &str, kind: SubtitleKind) -> String {
+    match kind {
+        SubtitleKind::Subtitles => locale.to_string(),
+        SubtitleKind::Captions => format!("{}.cc", locale),
+    }
+}
+
+/// The locale and kind of a [`track_name`].
+pub fn parse_track_name(name: &str) -> (&str, SubtitleKind) {
+    match name.strip_suffix(".cc") {
+        Some(locale) => (locale, SubtitleKind::Captions),
+        None => (name, SubtitleKind::Subtitles),
+    }
+}
+
+/// A converted subtitle file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SubtitleFile {
+    pub locale: String,
+    pub format: SubtitleFormat,
+    pub data: String,
+}
+
+impl SubtitleFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            SubtitleFormat::Vtt => "vtt",
+            SubtitleFormat::Srt => "srt",
+            SubtitleFormat::Ass => "ass",
+        }
+    }
+
+    /// The format of a local file, from its name.
+    pub fn from_path(path: &Path) -> Option<SubtitleFormat> {
+        let ext = path.extension()?.to_str()?.to_lowercase();
+        match ext.as_str() {
+            "ass" | "ssa" => Some(SubtitleFormat::Ass),
+            "srt" => Some(SubtitleFormat::Srt),
+            "vtt" => Some(SubtitleFormat::Vtt),
+            _ => None,
+        }
+    }
+}
+
+/// Converted subtitles, so rewatching an episode skips the download and the
+/// conversion.
+pub struct SubtitleCache(pub Mutex<DiskCache>);
+
+impl SubtitleCache {
+    pub fn open(max_bytes: u64) -> SubtitleCache {
+        SubtitleCache(Mutex::new(DiskCache::open(
+            storage::cache_dir().join("subtitles"),
+            max_bytes,
+        )))
+    }
+
+    pub fn resize(&self, max_bytes: u64) -> CommandResult<()> {
+        self.0
+            .lock()
+            .map_err(|e| CommandError::Io(e.to_string()))?
+            .set_max_bytes(max_bytes)
+            .map_err(CommandError::from)
+    }
+
+    fn key(ep_id: &str, locale: &str, kind: SubtitleKind, format: SubtitleFormat) -> String {
+        format!(
+            "{}/{}.{}",
+            ep_id,
+            track_name(locale, kind),
+            format.extension()
+        )
+    }
+
+    pub fn get(
+        &self,
+        ep_id: &str,
+        locale: &str,
+        kind: SubtitleKind,
+        format: SubtitleFormat,
+    ) -> Option<String> {
+        let data = self
+            .0
+            .lock()
+            .ok()?
+            .get(&SubtitleCache::key(ep_id, locale, kind, format))?;
+        String::from_utf8(data).ok()
+    }
+
+    pub fn put(
+        &self,
+        ep_id: &str,
+        locale: &str,
+        kind: SubtitleKind,
+        format: SubtitleFormat,
+        data: &str,
+    ) {
+        let key = SubtitleCache::key(ep_id, locale, kind, format);
+        let res = match self.0.lock() {
+            Ok(mut cache) => cache.put(&key, data.as_bytes()),
+            Err(e) => Err(anyhow::anyhow!("{}", e)),
+        };
+        if let Err(e) = res {
+            println!("Failed to cache subtitles. {}", e);
+        }
+    }
+
+    pub fn clear(&self) -> CommandResult<()> {
+        self.0
+            .lock()
+            .map_err(|e| CommandError::Io(e.to_string()))?
+            .clear()
+            .map_err(CommandError::from)
+    }
+}
+
+fn parse_err<E: std::fmt::Display>(e: E) -> CommandError {
+    CommandError::Parse(format!("Failed to read subtitles. {}", e))
+}
+
+/// Converts subtitle text between formats.
+pub fn convert(raw: String, from: SubtitleFormat, to: SubtitleFormat) -> CommandResult<String> {
+    if from == to {
+        return Ok(raw);
+    }
+    Ok(match from {
+        SubtitleFormat::Ass => {
+            let file = ssa::parse(raw).map_err(parse_err)?;
+            match to {
+                SubtitleFormat::Vtt => file.to_vtt().to_string(),
+                SubtitleFormat::Srt => file.to_srt().to_string(),
+                SubtitleFormat::Ass => file.to_string(),
+            }
+        }
+        SubtitleFormat::Vtt => {
+            let file = vtt::parse(raw).map_err(parse_err)?;
+            match to {
+                SubtitleFormat::Srt => file.to_srt().to_string(),
+                SubtitleFormat::Ass => file.to_ass().to_string(),
+                SubtitleFormat::Vtt => file.to_string(),
+            }
+        }
+        SubtitleFormat::Srt => {
+            let file = srt::parse(raw).map_err(parse_err)?;
+            match to {
+                SubtitleFormat::Vtt => file.to_vtt().to_string(),
+                SubtitleFormat::Ass => file.to_ass().to_string(),
+                SubtitleFormat::Srt => file.to_string(),
+            }
+        }
+    })
+}
+
+/// `[h:]mm:ss.fff` (or `,fff`) to milliseconds.
+fn parse_time(value: &str) -> Option<i64> {
+    let mut secs = 0_f64;
+    for part in value.trim().split(':') {
+        secs = secs * 60_f64 + part.replace(',', ".").parse::<f64>().ok()?;
+    }
+    Some((secs * 1000_f64).round() as i64)
+}
+
+fn format_time(ms: i64, format: SubtitleFormat) -> String {
+    let ms = ms.max(0);
+    let (h, m, s, ms) = (ms / 3_600_000, ms / 60_000 % 60, ms / 1000 % 60, ms % 1000);
+    match format {
+        SubtitleFormat::Vtt => format!("{:02}:{:02}:{:02}.{:03}", h, m, s, ms),
+        SubtitleFormat::Srt => format!("{:02}:{:02}:{:02},{:03}", h, m, s, ms),
+        SubtitleFormat::Ass => format!("{}:{:02}:{:02}.{:02}", h, m, s, ms / 10),
+    }
+}
+
+/// Moves every cue of a converted file by `offset_ms`, later when positive.
+/// Cues pushed before the start are clamped to zero.
+pub fn shift(data: &str, format: SubtitleFormat, offset_ms: i64) -> String {
+    if offset_ms == 0 {
+        return data.to_string();
+    }
+    let moved = |t: &str| {
+        parse_time(t).map_or_else(|| t.to_string(), |ms| format_time(ms + offset_ms, format))
+    };
+    data.lines()
+        .map(|line| match format {
+            SubtitleFormat::Vtt | SubtitleFormat::Srt => match line.split_once("-->") {
+                Some((start, rest)) => {
+                    // vtt cue settings follow the end time
+                    let rest = rest.trim_start();
+                    let (end, settings) = rest.split_once(' ').unwrap_or((rest, ""));
+                    let mut line = format!("{} --> {}", moved(start), moved(end));
+                    if !settings.is_empty() {
+                        line = format!("{} {}", line, settings);
+                    }
+                    line
+                }
+                None => line.to_string(),
+            },
+            SubtitleFormat::Ass => match line.strip_prefix("Dialogue:") {
+                // Layer, Start, End, then the rest of the event
+                Some(event) => {
+                    let fields: Vec<&str> = event.splitn(4, ',').collect();
+                    if fields.len() == 4 {
+                        format!(
+                            "Dialogue:{},{},{},{}",
+                            fields[0],
+                            moved(fields[1]),
+                            moved(fields[2]),
+                            fields[3]
+                        )
+                    } else {
+                        line.to_string()
+                    }
+                }
+                None => line.to_string(),
+            },
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Subtitle delays picked in the player, per series, in milliseconds.
+pub struct SubtitleOffsets {
+    path: PathBuf,
+    offsets: HashMap<String, i64>,
+}
+
+impl Default for SubtitleOffsets {
+    fn default() -> Self {
+        SubtitleOffsets {
+            path: storage::data_dir().join("subtitle_offsets.json"),
+            offsets: HashMap::new(),
+        }
+    }
+}
+
+impl SubtitleOffsets {
+    pub fn load() -> SubtitleOffsets {
+        let mut offsets = SubtitleOffsets::default();
+        offsets.offsets = storage::load_json(&offsets.path).unwrap_or_default();
+        offsets
+    }
+
+    pub fn get(&self, series_id: &str) -> i64 {
+        self.offsets.get(series_id).copied().unwrap_or(0)
+    }
+
+    pub fn set(&mut self, series_id: &str, offset_ms: i64) -> anyhow::Result<()> {
+        if offset_ms == 0 {
+            self.offsets.remove(series_id);
+        } else {
+            self.offsets.insert(series_id.to_string(), offset_ms);
+        }
+        storage::save_json(&self.path, &self.offsets)
+    }
+}
+
+/// Writes a subtitle file, creating its folder if needed.
+pub fn write(path: &Path, data: &str) -> CommandResult<()> {
+    let io_err =
+        |e: std::io::Error| CommandError::Io(format!("Failed to write {}. {}", path.display(), e));
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir).map_err(io_err)?;
+    }
+    std::fs::write(path, data).map_err(io_err)
+}
+
+/// Reads a subtitle file picked by the user and converts it to `to`.
+pub fn read_local(path: &Path, to: SubtitleFormat) -> CommandResult<String> {
+    let from = SubtitleFormat::from_path(path).ok_or_else(|| {
+        CommandError::Parse(format!(
+            "{} is not an .ass, .ssa, .srt or .vtt file.",
+            path.display()
+        ))
+    })?;
+    let raw = std::fs::read_to_string(path)
+        .map_err(|e| CommandError::Io(format!("Failed to read {}. {}", path.display(), e)))?;
+    convert(raw.trim_start_matches('\u{feff}').to_string(), from, to)
+}
+
+/// The tracks offered by a stream, subtitles first then closed captions,
+/// sorted by name within each.
+pub fn tracks<'a>(
+    subtitles: impl Iterator<Item = &'a Locale>,
+    captions: impl Iterator<Item = &'a Locale>,
+) -> Vec<SubtitleInfo> {
+    let mut subs: Vec<SubtitleInfo> = subtitles
+        .map(|l| SubtitleInfo {
+            locale: l.to_string(),
+            label: l.to_human_readable(),
+            kind: SubtitleKind::Subtitles,
+        })
+        .collect();
+    subs.sort_by(|a, b| a.label.cmp(&b.label));
+    let mut cc: Vec<SubtitleInfo> = captions
+        .map(|l| SubtitleInfo {
+            locale: l.to_string(),
+            label: format!("{} [CC]", l.to_human_readable()),
+            kind: SubtitleKind::Captions,
+        })
+        .collect();
+    cc.sort_by(|a, b| a.label.cmp(&b.label));
+    subs.extend(cc);
+    subs
+}
+
+/// The url and format of the `locale` track of `kind` in a stream's track
+/// lists, subtitles come as ass, closed captions already as vtt.
+fn pick_track<'a, T: 'a>(
+    subtitles: impl IntoIterator<Item = (&'a Locale, &'a T)>,
+    captions: impl IntoIterator<Item = (&'a Locale, &'a T)>,
+    locale: &str,
+    kind: SubtitleKind,
+) -> Option<(&'a T, SubtitleFormat)> {
+    let (tracks, format): (Vec<(&Locale, &T)>, _) = match kind {
+        SubtitleKind::Subtitles => (subtitles.into_iter().collect(), SubtitleFormat::Ass),
+        SubtitleKind::Captions => (captions.into_iter().collect(), SubtitleFormat::Vtt),
+    };
+    tracks
+        .into_iter()
+        .find(|(l, _)| l.to_string() == locale)
+        .map(|(_, t)| (t, format))
+}
+
+/// Downloads the `locale` track of `kind` of `ep_id` as the api serves it.
+pub async fn fetch(
+    cr: &Crunchyroll,
+    ep_id: &str,
+    locale: &str,
+    kind: SubtitleKind,
+) -> CommandResult<(String, SubtitleFormat)> {
+    let episode: Media<Episode> = cr.media_from_id(ep_id).await?;
+    let streams = episode.streams().await?;
+    let (url, format) = match pick_track(&streams.subtitles, &streams.closed_captions, locale, kind)
+    {
+        Some((s, format)) => (s.url.to_string(), format),
+        None => {
+            let what = match kind {
+                SubtitleKind::Subtitles => format!("{} subtitles", locale),
+                SubtitleKind::Captions => format!("{} closed captions", locale),
+            };
+            return Err(CommandError::not_found(&what));
+        }
+    };
+    let raw = cr
+        .client()
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| CommandError::Network(e.to_string()))?
+        .text()
+        .await
+        .map_err(|e| CommandError::Network(e.to_string()))?;
+    Ok((raw, format))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_locale_tracks_stay_apart() {
+        let subtitles = [(Locale::en_US, "subtitles.ass")];
+        let captions = [(Locale::en_US, "captions.vtt")];
+        let pick = |kind| {
+            pick_track(
+                subtitles.iter().map(|(l, u)| (l, u)),
+                captions.iter().map(|(l, u)| (l, u)),
+                "en-US",
+                kind,
+            )
+        };
+        assert_eq!(
+            pick(SubtitleKind::Subtitles),
+            Some((&"subtitles.ass", SubtitleFormat::Ass))
+        );
+        assert_eq!(
+            pick(SubtitleKind::Captions),
+            Some((&"captions.vtt", SubtitleFormat::Vtt))
+        );
+        assert_eq!(
+            pick_track(
+                subtitles.iter().map(|(l, u)| (l, u)),
+                [],
+                "en-US",
+                SubtitleKind::Captions
+            ),
+            None
+        );
+
+        let tracks = tracks(
+            subtitles.iter().map(|(l, _)| l),
+            captions.iter().map(|(l, _)| l),
+        );
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].kind, SubtitleKind::Subtitles);
+        assert_eq!(tracks[1].kind, SubtitleKind::Captions);
+        assert_eq!(
+            SubtitleCache::key("ep", "en-US", SubtitleKind::Subtitles, SubtitleFormat::Vtt),
+            "ep/en-US.vtt"
+        );
+        assert_eq!(
+            SubtitleCache::key("ep", "en-US", SubtitleKind::Captions, SubtitleFormat::Vtt),
+            "ep/en-US.cc.vtt"
+        );
+        for kind in [SubtitleKind::Subtitles, SubtitleKind::Captions] {
+            assert_eq!(
+                parse_track_name(&track_name("en-US", kind)),
+                ("en-US", kind)
+            );
+        }
+    }
+}
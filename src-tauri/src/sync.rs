@@ -0,0 +1,456 @@
+use crate::history::{History, HistoryEntry};
+use crate::storage;
+use anyhow::Result;
+use async_trait::async_trait;
+use crunchyroll_rs::media::MediaCollection;
+use crunchyroll_rs::{Crunchyroll, Episode, Media};
+use futures_util::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use tokio::sync::Mutex;
+
+/// How many of the most recent episodes get compared with the account.
+const SYNC_WINDOW: usize = 20;
+/// Episode lookups running at once when pulling playheads.
+const LOOKUP_BATCH: usize = 8;
+
+/// Which position is kept when the local and the account playhead differ.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ConflictPolicy {
+    /// The position updated last wins.
+    Newest,
+    /// The position furthest into the episode wins.
+    Furthest,
+}
+
+impl Default for ConflictPolicy {
+    fn default() -> Self {
+        ConflictPolicy::Newest
+    }
+}
+
+impl ConflictPolicy {
+    pub fn prefers_remote(&self, local: &HistoryEntry, remote: &HistoryEntry) -> bool {
+        match self {
+            ConflictPolicy::Newest => remote.timestamp > local.timestamp,
+            ConflictPolicy::Furthest => remote.position > local.position,
+        }
+    }
+}
+
+/// A playhead that still has to reach the account.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PendingUpdate {
+    pub episode_id: String,
+    pub position: f64,
+}
+
+/// Playhead updates made while offline (or not synced yet), per account, kept
+/// on disk until the account they were made with accepts them.
+pub struct PendingQueue {
+    path: PathBuf,
+    updates: HashMap<String, Vec<PendingUpdate>>,
+}
+
+impl Default for PendingQueue {
+    fn default() -> Self {
+        PendingQueue::open(storage::data_dir().join("pending_playheads.json"))
+    }
+}
+
+impl PendingQueue {
+    /// An empty queue saved to `path`.
+    pub fn open(path: PathBuf) -> PendingQueue {
+        PendingQueue {
+            path,
+            updates: HashMap::new(),
+        }
+    }
+
+    /// Files from before the queue was per account hold a plain list, which
+    /// can't be told apart by account and is dropped.
+    pub fn load() -> PendingQueue {
+        let mut queue = PendingQueue::default();
+        queue.updates = storage::load_json(&queue.path).unwrap_or_default();
+        queue
+    }
+
+    pub fn save(&self) -> Result<()> {
+        storage::save_json(&self.path, &self.updates)
+    }
+
+    pub fn updates(&self, account: &str) -> Vec<PendingUpdate> {
+        self.updates.get(account).cloned().unwrap_or_default()
+    }
+
+    /// Queues `update`, replacing an older one for the same episode.
+    pub fn push(&mut self, account: &str, update: PendingUpdate) {
+        self.remove(account, &update.episode_id);
+        self.updates
+            .entry(account.to_string())
+            .or_default()
+            .push(update);
+    }
+
+    pub fn remove(&mut self, account: &str, episode_id: &str) {
+        if let Some(updates) = self.updates.get_mut(account) {
+            updates.retain(|u| u.episode_id != episode_id);
+            if updates.is_empty() {
+                self.updates.remove(account);
+            }
+        }
+    }
+
+    /// Drops `update` once the account has it, unless a newer position was
+    /// queued meanwhile.
+    fn acknowledge(&mut self, account: &str, update: &PendingUpdate) {
+        let current = self.updates.get(account).and_then(|updates| {
+            updates
+                .iter()
+                .find(|u| u.episode_id == update.episode_id)
+                .cloned()
+        });
+        if current.as_ref() == Some(update) {
+            self.remove(account, &update.episode_id);
+        }
+    }
+}
+
+/// The account side of the sync. Implemented for a crunchyroll-rs session;
+/// anything else implementing it (e.g. a local stand-in for the api) can be
+/// handed to [`sync`] instead.
+#[async_trait]
+pub trait PlayheadRemote: Send + Sync {
+    /// Episodes that can't be looked up are left out rather than failing the
+    /// whole pull.
+    async fn pull_playheads(&self, episode_ids: &[String]) -> Result<Vec<HistoryEntry>>;
+    /// The account's watch history, newest first.
+    async fn pull_history(&self, limit: usize) -> Result<Vec<HistoryEntry>>;
+    async fn push_playhead(&self, episode_id: &str, position: f64) -> Result<()>;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    pub pulled: usize,
+    pub pushed: usize,
+    pub pending: usize,
+}
+
+/// Pulls the account's playheads into `history` where `policy` prefers them,
+/// then pushes the updates queued for `account` that won. Updates the account
+/// refuses stay queued for the next sync.
+///
+/// Neither lock is held while the account is asked, so progress keeps being
+/// recorded during a slow sync.
+pub async fn sync<R: PlayheadRemote + ?Sized>(
+    remote: &R,
+    account: &str,
+    history: &Mutex<History>,
+    pending: &Mutex<PendingQueue>,
+    policy: ConflictPolicy,
+) -> Result<SyncReport> {
+    let mut ids: Vec<String> = history
+        .lock()
+        .await
+        .recent(SYNC_WINDOW)
+        .into_iter()
+        .map(|e| e.episode_id)
+        .collect();
+    for u in pending.lock().await.updates(account) {
+        if !ids.contains(&u.episode_id) {
+            ids.push(u.episode_id);
+        }
+    }
+    let mut incoming = remote.pull_history(SYNC_WINDOW).await?;
+    for p in remote.pull_playheads(&ids).await? {
+        if !incoming.iter().any(|e| e.episode_id == p.episode_id) {
+            incoming.push(p);
+        }
+    }
+    let pulled_count = {
+        let mut history = history.lock().await;
+        let mut pending = pending.lock().await;
+        let mut pulled = vec![];
+        for r in incoming {
+            let take = match history.get(&r.episode_id) {
+                Some(local) => policy.prefers_remote(local, &r),
+                None => true,
+            };
+            if take {
+                pending.remove(account, &r.episode_id);
+                pulled.push(r);
+            }
+        }
+        let count = pulled.len();
+        history.merge(pulled)?;
+        count
+    };
+
+    let mut pushed = 0;
+    let queued = pending.lock().await.updates(account);
+    for u in queued {
+        match remote.push_playhead(&u.episode_id, u.position).await {
+            Ok(_) => {
+                pushed += 1;
+                pending.lock().await.acknowledge(account, &u);
+            }
+            Err(e) => println!("Failed to push playhead for {}. {}", u.episode_id, e),
+        }
+    }
+    let pending = pending.lock().await;
+    pending.save()?;
+    Ok(SyncReport {
+        pulled: pulled_count,
+        pushed,
+        pending: pending.updates(account).len(),
+    })
+}
+
+async fn playhead_entry(cr: &Crunchyroll, episode_id: &str) -> Result<Option<HistoryEntry>> {
+    let episode: Media<Episode> = cr.media_from_id(episode_id).await?;
+    Ok(episode.playhead().await?.map(|p| HistoryEntry {
+        episode_id: episode.id.clone(),
+        series_id: episode.metadata.series_id.clone(),
+        title: episode.title.clone(),
+        series_title: episode.metadata.series_title.clone(),
+        timestamp: p.last_modified.timestamp() as u64,
+        position: p.playhead as f64,
+        duration: 0_f64,
+    }))
+}
+
+#[async_trait]
+impl PlayheadRemote for Crunchyroll {
+    async fn pull_playheads(&self, episode_ids: &[String]) -> Result<Vec<HistoryEntry>> {
+        let found: Vec<(&String, Result<Option<HistoryEntry>>)> =
+            futures_util::stream::iter(episode_ids)
+                .map(|id| async move { (id, playhead_entry(self, id).await) })
+                .buffer_unordered(LOOKUP_BATCH)
+                .collect()
+                .await;
+        let mut res = vec![];
+        for (id, entry) in found {
+            match entry {
+                Ok(entry) => res.extend(entry),
+                // e.g. an episode no longer available in this region
+                Err(e) => println!("Skipping the playhead of {}. {}", id, e),
+            }
+        }
+        Ok(res)
+    }
+
+    async fn pull_history(&self, limit: usize) -> Result<Vec<HistoryEntry>> {
+        let mut res = vec![];
+        let mut history = self.watch_history();
+        while let Some(entry) = history.next().await {
+            let entry = entry?;
+            if let MediaCollection::Episode(episode) = entry.panel {
+                res.push(HistoryEntry {
+                    episode_id: episode.id,
+                    series_id: episode.metadata.series_id,
+                    title: episode.title,
+                    series_title: episode.metadata.series_title,
+                    timestamp: entry.date_played.timestamp() as u64,
+                    position: entry.playhead as f64,
+                    duration: 0_f64,
+                });
+            }
+            if res.len() >= limit {
+                break;
+            }
+        }
+        Ok(res)
+    }
+
+    async fn push_playhead(&self, episode_id: &str, position: f64) -> Result<()> {
+        let episode: Media<Episode> = self.media_from_id(episode_id).await?;
+        episode.set_playhead(position as u32).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex as StdMutex};
+
+    /// The account as a tiny local http server: `GET /history`,
+    /// `GET /playhead/<id>` (404 for unknown episodes) and
+    /// `PUT /playhead/<id>/<position>` (500 for `broken`).
+    fn serve(
+        history: Vec<HistoryEntry>,
+        playheads: Vec<HistoryEntry>,
+        pushed: Arc<StdMutex<Vec<(String, f64)>>>,
+    ) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                // the headers, nothing here sends a body
+                let mut header = String::new();
+                while reader.read_line(&mut header).unwrap() > 2 {
+                    header.clear();
+                }
+                let mut parts = line.split_whitespace();
+                let (method, path) = (parts.next().unwrap(), parts.next().unwrap());
+                let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+                let (status, body) = match (method, segments.as_slice()) {
+                    ("GET", ["history"]) => (200, serde_json::to_string(&history).unwrap()),
+                    ("GET", ["playhead", id]) => {
+                        match playheads.iter().find(|p| p.episode_id == *id) {
+                            Some(p) => (200, serde_json::to_string(p).unwrap()),
+                            None => (404, String::new()),
+                        }
+                    }
+                    ("PUT", ["playhead", "broken", _]) => (500, String::new()),
+                    ("PUT", ["playhead", id, position]) => {
+                        pushed
+                            .lock()
+                            .unwrap()
+                            .push((id.to_string(), position.parse().unwrap()));
+                        (200, String::new())
+                    }
+                    _ => (400, String::new()),
+                };
+                write!(
+                    stream,
+                    "HTTP/1.1 {} X\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        base
+    }
+
+    struct HttpRemote {
+        base: String,
+        client: reqwest::Client,
+    }
+
+    #[async_trait]
+    impl PlayheadRemote for HttpRemote {
+        async fn pull_playheads(&self, episode_ids: &[String]) -> Result<Vec<HistoryEntry>> {
+            let mut res = vec![];
+            for id in episode_ids {
+                let url = format!("{}/playhead/{}", self.base, id);
+                match self.client.get(url).send().await?.error_for_status() {
+                    Ok(response) => res.push(serde_json::from_str(&response.text().await?)?),
+                    Err(e) => println!("Skipping the playhead of {}. {}", id, e),
+                }
+            }
+            Ok(res)
+        }
+
+        async fn pull_history(&self, limit: usize) -> Result<Vec<HistoryEntry>> {
+            let url = format!("{}/history", self.base);
+            let body = self.client.get(url).send().await?.text().await?;
+            let mut history: Vec<HistoryEntry> = serde_json::from_str(&body)?;
+            history.truncate(limit);
+            Ok(history)
+        }
+
+        async fn push_playhead(&self, episode_id: &str, position: f64) -> Result<()> {
+            let url = format!("{}/playhead/{}/{}", self.base, episode_id, position);
+            self.client.put(url).send().await?.error_for_status()?;
+            Ok(())
+        }
+    }
+
+    fn entry(episode_id: &str, timestamp: u64, position: f64) -> HistoryEntry {
+        HistoryEntry {
+            episode_id: episode_id.to_string(),
+            series_id: "series".to_string(),
+            timestamp,
+            position,
+            duration: 1400_f64,
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn syncs_with_a_local_stand_in() {
+        let dir = std::env::temp_dir().join(format!("crunchyview-sync-{}", std::process::id()));
+        let history = Mutex::new(History::open(dir.join("history.json")));
+        let pending = Mutex::new(PendingQueue::open(dir.join("pending.json")));
+        {
+            let mut history = history.lock().await;
+            history.progress("a", 10_f64, 1400_f64).unwrap();
+            history.progress("b", 50_f64, 1400_f64).unwrap();
+            let mut pending = pending.lock().await;
+            for (account, id, position) in
+                [("alice", "b", 50), ("alice", "broken", 5), ("bob", "a", 99)]
+            {
+                pending.push(
+                    account,
+                    PendingUpdate {
+                        episode_id: id.to_string(),
+                        position: position as f64,
+                    },
+                );
+            }
+        }
+        let far_ahead = u64::MAX / 2;
+        let pushed = Arc::new(StdMutex::new(vec![]));
+        let remote = HttpRemote {
+            base: serve(
+                vec![entry("c", 1, 300_f64)],
+                vec![entry("a", far_ahead, 800_f64)],
+                pushed.clone(),
+            ),
+            client: reqwest::Client::new(),
+        };
+
+        let report = sync(&remote, "alice", &history, &pending, ConflictPolicy::Newest)
+            .await
+            .unwrap();
+
+        // `b` and `broken` are unknown to the account and skipped, not fatal
+        assert_eq!(
+            report,
+            SyncReport {
+                pulled: 2,
+                pushed: 1,
+                pending: 1,
+            }
+        );
+        assert_eq!(*pushed.lock().unwrap(), vec![("b".to_string(), 50_f64)]);
+        let history = history.lock().await;
+        assert_eq!(history.get("a").unwrap().position, 800_f64);
+        assert_eq!(history.get("c").unwrap().position, 300_f64);
+        let pending = pending.lock().await;
+        assert_eq!(pending.updates("alice")[0].episode_id, "broken");
+        // another account's updates never reach this one
+        assert_eq!(pending.updates("bob")[0].position, 99_f64);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn acknowledge_keeps_newer_positions() {
+        let mut pending = PendingQueue::open(PathBuf::from("unused.json"));
+        let old = PendingUpdate {
+            episode_id: "a".to_string(),
+            position: 10_f64,
+        };
+        pending.push("alice", old.clone());
+        pending.push(
+            "alice",
+            PendingUpdate {
+                episode_id: "a".to_string(),
+                position: 20_f64,
+            },
+        );
+        pending.acknowledge("alice", &old);
+        assert_eq!(pending.updates("alice")[0].position, 20_f64);
+    }
+}
@@ -0,0 +1,149 @@
+use crate::storage;
+use anyhow::{anyhow, Result};
+use base64::{decode, encode};
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+
+const VAULT_VERSION: u8 = 2;
+
+/// The credentials kept behind "remember me".
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Secrets {
+    pub username: String,
+    pub password: String,
+}
+
+/// On-disk layout of the vault. Everything but the username and the key
+/// source is encrypted with XChaCha20-Poly1305, the key being derived with
+/// argon2 from either the user's passphrase or the device key.
+#[derive(Serialize, Deserialize)]
+struct VaultFile {
+    version: u8,
+    username: String,
+    passphrase: bool,
+    salt: String,
+    nonce: String,
+    data: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct VaultStatus {
+    pub exists: bool,
+    pub passphrase: bool,
+    pub username: Option<String>,
+}
+
+fn vault_path() -> PathBuf {
+    storage::config_dir().join("vault.json")
+}
+
+fn key_path() -> PathBuf {
+    storage::config_dir().join("vault.key")
+}
+
+/// Random key material used when no passphrase was given, kept in a file only
+/// the current user can read. It keeps the vault unreadable when copied
+/// alone, not from someone using the same account.
+fn device_key(create: bool) -> Result<Vec<u8>> {
+    let path = key_path();
+    match fs::read(&path) {
+        Ok(key) if key.len() == 32 => Ok(key),
+        Ok(_) => Err(anyhow!("Corrupted vault key.")),
+        Err(_) if create => {
+            let mut key = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut key);
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            storage::write_private(&path, &key)?;
+            Ok(key.to_vec())
+        }
+        Err(_) => Err(anyhow!("The vault key is missing, please log in again.")),
+    }
+}
+
+fn cipher(material: &[u8], salt: &[u8]) -> Result<XChaCha20Poly1305> {
+    let mut key = [0u8; 32];
+    argon2::Argon2::default()
+        .hash_password_into(material, salt, &mut key)
+        .map_err(|e| anyhow!("Failed to derive vault key. {}", e))?;
+    Ok(XChaCha20Poly1305::new(&key.into()))
+}
+
+/// Encrypts and stores `secrets`, replacing any previous vault.
+pub fn store(secrets: &Secrets, passphrase: Option<&str>) -> Result<()> {
+    let passphrase = passphrase.filter(|p| !p.is_empty());
+    let mut salt = [0u8; 16];
+    let mut nonce = [0u8; 24];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let material = match passphrase {
+        Some(p) => p.as_bytes().to_vec(),
+        None => device_key(true)?,
+    };
+    let data = cipher(&material, &salt)?
+        .encrypt(
+            XNonce::from_slice(&nonce),
+            serde_json::to_vec(secrets)?.as_ref(),
+        )
+        .map_err(|_| anyhow!("Failed to encrypt vault."))?;
+    storage::save_private_json(
+        &vault_path(),
+        &VaultFile {
+            version: VAULT_VERSION,
+            username: secrets.username.clone(),
+            passphrase: passphrase.is_some(),
+            salt: encode(salt),
+            nonce: encode(nonce),
+            data: encode(data),
+        },
+    )
+}
+
+pub fn unlock(passphrase: Option<&str>) -> Result<Secrets> {
+    let vault: VaultFile =
+        storage::load_json(&vault_path()).ok_or_else(|| anyhow!("No saved login."))?;
+    if vault.version != VAULT_VERSION {
+        return Err(anyhow!("Unsupported vault version {}.", vault.version));
+    }
+    let material = if vault.passphrase {
+        passphrase
+            .ok_or_else(|| anyhow!("A passphrase is required."))?
+            .as_bytes()
+            .to_vec()
+    } else {
+        device_key(false)?
+    };
+    let nonce = decode(vault.nonce)?;
+    if nonce.len() != 24 {
+        return Err(anyhow!("Corrupted vault."));
+    }
+    let data = cipher(&material, &decode(vault.salt)?)?
+        .decrypt(XNonce::from_slice(&nonce), decode(vault.data)?.as_ref())
+        .map_err(|_| anyhow!("Wrong passphrase or corrupted vault."))?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+pub fn status() -> VaultStatus {
+    match storage::load_json::<VaultFile>(&vault_path()) {
+        Some(v) => VaultStatus {
+            exists: true,
+            passphrase: v.passphrase,
+            username: Some(v.username),
+        },
+        None => VaultStatus::default(),
+    }
+}
+
+pub fn forget() -> Result<()> {
+    for path in [vault_path(), key_path()] {
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
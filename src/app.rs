@@ -1,13 +1,14 @@
 use serde::{Deserialize, Serialize};
 use serde_wasm_bindgen::to_value;
-use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fmt::Display;
+use std::rc::Rc;
 use std::time::Duration;
 use std::vec;
 use wasm_bindgen::{prelude::*, JsCast};
 use web_sys::HtmlElement;
 use web_sys::HtmlInputElement;
+use web_sys::HtmlSelectElement;
 use web_sys::HtmlTrackElement;
 use web_sys::HtmlVideoElement;
 use web_sys::TextTrackMode;
@@ -15,8 +16,12 @@ use yew::platform::spawn_local;
 use yew::platform::time::sleep;
 use yew::prelude::*;
 
+use crate::toast::{use_toaster, ToastLevel, ToastStack, Toaster, Toasts};
+
 const FIVE_SEC: Duration = Duration::from_secs(5);
 const ONE_SEC: Duration = Duration::from_secs(1);
+/// How far playback has to move before the position is saved again.
+const PROGRESS_SYNC_SECS: f64 = 5_f64;
 #[wasm_bindgen]
 extern "C" {
     #[wasm_bindgen(js_namespace = ["window", "__TAURI__", "tauri"])]
@@ -26,6 +31,17 @@ extern "C" {
     #[wasm_bindgen(js_namespace = ["window", "__TAURI__", "tauri"],js_name = invoke,catch)]
     async fn invoke_checked_no_arg(cmd: &str) -> Result<JsValue, JsValue>;
 
+    #[wasm_bindgen(js_namespace = ["window", "__TAURI__", "tauri"], js_name = convertFileSrc)]
+    fn convert_file_src(path: &str, protocol: &str) -> String;
+
+    #[wasm_bindgen(js_namespace = ["window", "__TAURI__", "dialog"], js_name = open, catch)]
+    async fn open_dialog(options: JsValue) -> Result<JsValue, JsValue>;
+    #[wasm_bindgen(js_namespace = ["window", "__TAURI__", "dialog"], js_name = save, catch)]
+    async fn save_dialog(options: JsValue) -> Result<JsValue, JsValue>;
+
+    #[wasm_bindgen(js_namespace = ["window", "__TAURI__", "event"], js_name = listen)]
+    async fn listen(event: &str, handler: &Closure<dyn FnMut(JsValue)>) -> JsValue;
+
     #[wasm_bindgen(js_namespace = console,js_name= log)]
     fn log_obj(s: JsValue);
     #[wasm_bindgen(js_namespace = console)]
@@ -51,6 +67,76 @@ pub async fn initialize_atomic_clocks() {
 struct LoginArgs<'a> {
     username: &'a str,
     password: &'a str,
+    remember: bool,
+    passphrase: Option<&'a str>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PassphraseArgs<'a> {
+    passphrase: Option<&'a str>,
+}
+
+#[derive(Serialize, Debug, Deserialize, Default, Clone, PartialEq, Eq)]
+pub struct VaultStatus {
+    pub exists: bool,
+    pub passphrase: bool,
+    pub username: Option<String>,
+}
+
+/// Mirrors the backend's `CommandError`, which every command rejects with.
+#[derive(Serialize, Debug, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "kind", content = "message")]
+pub enum CommandError {
+    NotLoggedIn,
+    Auth(String),
+    NotFound(String),
+    Network(String),
+    Geoblocked(String),
+    PremiumRequired(String),
+    Parse(String),
+    Io(String),
+    /// Anything tauri rejects before the command runs (bad arguments...).
+    Other(String),
+}
+
+impl CommandError {
+    pub fn from_js(c: JsValue) -> CommandError {
+        match serde_wasm_bindgen::from_value(c.clone()) {
+            Ok(e) => e,
+            Err(_) => CommandError::Other(c.as_string().unwrap_or_else(|| format!("{c:?}"))),
+        }
+    }
+}
+
+impl Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommandError::NotLoggedIn => write!(f, "Please log in first."),
+            CommandError::Auth(m) => write!(f, "Could not sign in. {m}"),
+            CommandError::NotFound(m) => write!(f, "Nothing found. {m}"),
+            CommandError::Network(m) => write!(f, "Crunchyroll could not be reached. {m}"),
+            CommandError::Geoblocked(m) => write!(f, "This is not available in your region. {m}"),
+            CommandError::PremiumRequired(m) => write!(f, "This needs a premium account. {m}"),
+            CommandError::Parse(m) => write!(f, "Got an answer that could not be read. {m}"),
+            CommandError::Io(m) => write!(f, "Could not access local files. {m}"),
+            CommandError::Other(m) => write!(f, "{m}"),
+        }
+    }
+}
+
+/// Logs a rejected `invoke_checked`.
+fn log_error(c: JsValue) {
+    log(&CommandError::from_js(c).to_string());
+}
+
+/// Logs `e` and shows it as a toast, with a retry button when `retry` is set.
+fn toast_error(toaster: &Toaster, e: CommandError, retry: Option<Callback<()>>) {
+    log(&e.to_string());
+    let level = match e {
+        CommandError::NotLoggedIn | CommandError::Network(_) => ToastLevel::Warn,
+        _ => ToastLevel::Error,
+    };
+    toaster.push(level, e.to_string(), retry);
 }
 
 #[derive(Serialize, Deserialize, Eq, PartialEq, Properties)]
@@ -67,12 +153,90 @@ pub struct LoginProps {
     pub on_logged_in: Callback<(), ()>,
 }
 
+#[derive(Serialize, Debug, Deserialize, Default, Clone, PartialEq, Eq)]
+pub struct ImageVariant {
+    pub width: u32,
+    pub height: u32,
+    pub url: String,
+}
+
+/// Every size of each kind of artwork, smallest first.
+#[derive(Serialize, Debug, Deserialize, Default, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ImageSet {
+    pub thumbnail: Vec<ImageVariant>,
+    pub poster_tall: Vec<ImageVariant>,
+    pub poster_wide: Vec<ImageVariant>,
+    pub promo_image: Vec<ImageVariant>,
+}
+
+/// Routes an artwork url through the backend's on-disk image cache.
+fn cached_image(url: &str) -> String {
+    convert_file_src(url, "crunchyimg")
+}
+
+/// Plays a downloaded episode through the backend's `crunchylocal` scheme,
+/// `name` is `<ep_id>.m3u8` or a file next to it.
+fn offline_src(name: &str) -> String {
+    convert_file_src(name, "crunchylocal")
+}
+
+/// Rough rendered width of a tile in the episode/season grids. The backend
+/// caches artwork ahead for these widths, keep `images::GRID_WIDTH` and
+/// `images::BANNER_WIDTH` in step.
+const GRID_IMAGE_WIDTH: u32 = 320;
+/// Rough rendered width of the artwork next to a search result.
+const BANNER_IMAGE_WIDTH: u32 = 1280;
+
+impl ImageSet {
+    /// The first kind with any image, in the same order the backend prefers.
+    fn sizes(&self) -> &[ImageVariant] {
+        [
+            &self.thumbnail,
+            &self.poster_wide,
+            &self.promo_image,
+            &self.poster_tall,
+        ]
+        .into_iter()
+        .find(|v| !v.is_empty())
+        .map(|v| v.as_slice())
+        .unwrap_or(&[])
+    }
+
+    /// The smallest image at least `width` wide, or the largest there is.
+    pub fn pick(&self, width: u32) -> Option<&ImageVariant> {
+        let sizes = self.sizes();
+        sizes
+            .iter()
+            .find(|v| v.width >= width)
+            .or_else(|| sizes.last())
+    }
+
+    /// Inline style for a background `width` css pixels wide. `image-set` is
+    /// the `srcset` of css backgrounds, it gets hidpi screens a sharper image.
+    pub fn background(&self, width: u32) -> String {
+        let (one, two) = match (self.pick(width), self.pick(width * 2)) {
+            (Some(one), Some(two)) => (cached_image(&one.url), cached_image(&two.url)),
+            _ => return String::new(),
+        };
+        format!(
+            "background-image: url(\"{one}\"); \
+             background-image: -webkit-image-set(url(\"{one}\") 1x, url(\"{two}\") 2x); \
+             background-image: image-set(url(\"{one}\") 1x, url(\"{two}\") 2x)"
+        )
+    }
+}
+
 #[derive(Serialize, Debug, Deserialize, Default, Clone, PartialEq, Eq, Properties)]
 pub struct SearchResult {
     pub name: String,
     pub id: String,
     pub desc: String,
+    #[prop_or_default]
     pub img: Option<String>,
+    #[serde(default)]
+    #[prop_or_default]
+    pub images: ImageSet,
 }
 #[function_component]
 pub fn Login(props: &LoginProps) -> Html {
@@ -84,17 +248,48 @@ pub fn Login(props: &LoginProps) -> Html {
     } = props;
     let login_input_ref = use_node_ref();
     let login_input_ref2 = use_node_ref();
+    let remember_ref = use_node_ref();
+    let passphrase_ref = use_node_ref();
+    let unlock_ref = use_node_ref();
+    let logged_in = *state;
     let state = use_state(|| *state);
-    let hidenotif = use_state(|| true);
+    {
+        // a session restored by `App` hides the form without going through it
+        let state = state.clone();
+        use_effect_with_deps(
+            move |logged_in| {
+                if *logged_in {
+                    state.set(true);
+                }
+                || {}
+            },
+            logged_in,
+        );
+    }
+    let toaster = use_toaster();
+    // bumped by the toast's retry button to run the same login again
+    let attempt = use_state(|| 0_u32);
     let username = use_state(|| username.clone());
     let password = use_state(|| password.clone());
+    let remember = use_state(|| false);
+    let passphrase = use_state(String::new);
     let login = {
         let username = username.clone();
         let password = password.clone();
+        let remember = remember.clone();
+        let passphrase = passphrase.clone();
         let login_input_ref = login_input_ref.clone();
         let login_input_ref2 = login_input_ref2.clone();
+        let remember_ref = remember_ref.clone();
+        let passphrase_ref = passphrase_ref.clone();
         Callback::from(move |e: KeyboardEvent| {
             if e.key() == "Enter" {
+                if let Some(input) = remember_ref.cast::<HtmlInputElement>() {
+                    remember.set(input.checked());
+                };
+                if let Some(input) = passphrase_ref.cast::<HtmlInputElement>() {
+                    passphrase.set(input.value());
+                };
                 if let Some(input) = login_input_ref.cast::<HtmlInputElement>() {
                     username.set(input.value());
                 };
@@ -104,14 +299,85 @@ pub fn Login(props: &LoginProps) -> Html {
             }
         })
     };
-    let login_msg = use_state(String::new);
+    let vault = use_state(VaultStatus::default);
+    {
+        let vault = vault.clone();
+        use_effect_with_deps(
+            move |_| {
+                spawn_local(async move {
+                    if let Ok(c) = invoke_checked_no_arg("vault_status").await {
+                        if let Ok(status) = serde_wasm_bindgen::from_value::<VaultStatus>(c) {
+                            vault.set(status);
+                        }
+                    }
+                });
+                || {}
+            },
+            (),
+        );
+    }
+    let unlock = {
+        let unlock_ref = unlock_ref.clone();
+        let toaster = toaster.clone();
+        let on_logged_in = on_logged_in.clone();
+        let state = state.clone();
+        Callback::from(move |_: MouseEvent| {
+            // a vault without a passphrase has no field, same as `App` unlocking it
+            let passphrase = unlock_ref
+                .cast::<HtmlInputElement>()
+                .map(|input| input.value());
+            let toaster = toaster.clone();
+            let on_logged_in = on_logged_in.clone();
+            let state = state.clone();
+            spawn_local(async move {
+                let new_msg = invoke_checked(
+                    "vault_unlock",
+                    to_value(&PassphraseArgs {
+                        passphrase: passphrase.as_deref(),
+                    })
+                    .unwrap(),
+                )
+                .await;
+                match new_msg {
+                    Ok(c) => {
+                        let mess: String = serde_wasm_bindgen::from_value(c).unwrap_or_default();
+                        toaster.info(mess);
+                        state.set(true);
+                        on_logged_in.emit(());
+                    }
+                    Err(c) => match CommandError::from_js(c) {
+                        CommandError::Auth(_) => toaster.error("Wrong passphrase.".to_string()),
+                        e => toast_error(&toaster, e, None),
+                    },
+                }
+            });
+        })
+    };
+    let forget = {
+        let vault = vault.clone();
+        let toaster = toaster.clone();
+        Callback::from(move |_: MouseEvent| {
+            let vault = vault.clone();
+            let toaster = toaster.clone();
+            spawn_local(async move {
+                match invoke_checked_no_arg("vault_forget").await {
+                    Ok(_) => vault.set(VaultStatus::default()),
+                    Err(c) => toast_error(&toaster, CommandError::from_js(c), None),
+                }
+            });
+        })
+    };
     {
-        let login_msg = login_msg.clone();
+        let toaster = toaster;
         let on_logged_in = on_logged_in.clone();
         let username = username;
         let username2 = username.clone();
         let password = password;
-        let hidenotif = hidenotif.clone();
+        let password2 = password.clone();
+        let remember = remember;
+        let passphrase = passphrase;
+        let attempt = attempt;
+        let attempt2 = *attempt;
         let state = state.clone();
         use_effect_with_deps(
             move |_| {
@@ -126,6 +392,8 @@ pub fn Login(props: &LoginProps) -> Html {
                                 to_value(&LoginArgs {
                                     username: &username,
                                     password: &password,
+                                    remember: *remember,
+                                    passphrase: Some(passphrase.as_str()).filter(|p| !p.is_empty()),
                                 })
                                 .unwrap(),
                             )
@@ -134,7 +402,16 @@ pub fn Login(props: &LoginProps) -> Html {
                         let b = match new_msg {
                             Ok(c) => Some(c),
                             Err(c) => {
-                                log(&c.as_string().unwrap());
+                                match CommandError::from_js(c) {
+                                    CommandError::Auth(_) => {
+                                        toaster.error("Wrong username or password.".to_string())
+                                    }
+                                    e => toast_error(
+                                        &toaster,
+                                        e,
+                                        Some(Callback::from(move |_| attempt.set(*attempt + 1))),
+                                    ),
+                                }
                                 None
                             }
                         };
@@ -144,12 +421,9 @@ pub fn Login(props: &LoginProps) -> Html {
                             match a {
                                 Ok(mess) => {
                                     log(&mess);
-                                    login_msg.set(mess);
+                                    toaster.info(mess);
                                     state.set(true);
-                                    hidenotif.set(false);
                                     on_logged_in.emit(());
-                                    initialize_atomic_clocks().await;
-                                    hidenotif.set(true);
                                 }
                                 Err(e) => log(&format!("{e}")),
                             }
@@ -159,7 +433,7 @@ pub fn Login(props: &LoginProps) -> Html {
 
                 || {}
             },
-            username2,
+            (username2, password2, attempt2),
         );
     }
     html! {<>
@@ -170,13 +444,24 @@ pub fn Login(props: &LoginProps) -> Html {
 
                                 <input class="login-input" autocomplete="password" type="password" ref={login_input_ref2}
                                     placeholder="Enter a password..." />
+                                  <label class="remember"><input type="checkbox" ref={remember_ref}/>{"Remember me"}</label>
+                                  <input class="login-input" type="password" ref={passphrase_ref}
+                                    placeholder="Vault passphrase (optional)..." />
                                   <button type="submit" class="loginbtn" >{"Log In"}</button>
                                </form>
+                               if vault.exists {
+                                <form class="login vault" action="javascript:void(0);">
+                                  <b>{format!("Saved login for {}", vault.username.clone().unwrap_or_default())}</b>
+                                  if vault.passphrase {
+                                    <input class="login-input" type="password" ref={unlock_ref}
+                                      placeholder="Enter the vault passphrase..." />
+                                  }
+                                  <button type="submit" class="loginbtn" onclick={unlock}>{"Unlock"}</button>
+                                  <button type="button" class="loginbtn" onclick={forget}>{"Forget"}</button>
+                                </form>
+                               }
 
                         }
-                    if *hidenotif{
-                    } else {
-                        <p id="loginotif"><b>{ &*login_msg }</b></p>}
                         </>
     }
 }
@@ -188,14 +473,109 @@ pub struct EpOrSeries {
     pub ep_id: Option<String>,
 }
 
+#[derive(Serialize, Deserialize)]
+struct SeasonSubtitleArgs<'a> {
+    season_id: &'a str,
+    locale: &'a str,
+    kind: &'a str,
+    format: &'a str,
+    dir: &'a str,
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct SubtitleBatchReport {
+    pub saved: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+#[derive(PartialEq, Properties)]
+pub struct SeasonSubtitlesProps {
+    pub season_id: String,
+}
+
+/// Saves the preferred subtitles of every episode of a season as ass files,
+/// into a folder picked by the user.
+#[function_component]
+pub fn SeasonSubtitles(props: &SeasonSubtitlesProps) -> Html {
+    let settings = use_settings();
+    let toaster = use_toaster();
+    let saving = use_state(|| false);
+    let onclick = {
+        let season_id = props.season_id.clone();
+        let saving = saving.clone();
+        Callback::from(move |_: MouseEvent| {
+            if settings.subtitle_locale.is_empty() {
+                toaster.info("Pick a subtitle language in the settings first.".to_string());
+                return;
+            }
+            let season_id = season_id.clone();
+            let settings = settings.clone();
+            let toaster = toaster.clone();
+            let saving = saving.clone();
+            spawn_local(async move {
+                let options = OpenDialogOptions {
+                    multiple: false,
+                    directory: true,
+                    filters: vec![],
+                };
+                let dir = match open_dialog(to_value(&options).unwrap()).await {
+                    Ok(picked) => match picked.as_string() {
+                        Some(dir) => dir,
+                        None => return,
+                    },
+                    Err(e) => return toast_error(&toaster, CommandError::from_js(e), None),
+                };
+                saving.set(true);
+                let res = invoke_checked(
+                    "download_season_subtitles",
+                    to_value(&SeasonSubtitleArgs {
+                        season_id: &season_id,
+                        locale: &settings.subtitle_locale,
+                        kind: &settings.subtitle_kind,
+                        format: "ass",
+                        dir: &dir,
+                    })
+                    .unwrap(),
+                )
+                .await;
+                saving.set(false);
+                match res.map(serde_wasm_bindgen::from_value::<SubtitleBatchReport>) {
+                    Ok(Ok(report)) if report.failed.is_empty() => toaster.info(format!(
+                        "Saved {} subtitle files to {}.",
+                        report.saved.len(),
+                        dir
+                    )),
+                    Ok(Ok(report)) => toaster.info(format!(
+                        "Saved {} subtitle files to {}, {} failed. {}",
+                        report.saved.len(),
+                        dir,
+                        report.failed.len(),
+                        report.failed.join(" ")
+                    )),
+                    Ok(Err(e)) => log(&format!("{e}")),
+                    Err(e) => toast_error(&toaster, CommandError::from_js(e), None),
+                }
+            });
+        })
+    };
+    html! {
+        <button type="button" class="btn" disabled={*saving} {onclick}>
+            {if *saving { "Saving subtitles..." } else { "Export subtitles" }}
+        </button>
+    }
+}
+
 #[function_component]
 pub fn Episodes(props: &SearchResult) -> Html {
     let SearchResult { id, .. } = props;
     let search_result: UseStateHandle<Vec<SearchResult>> = use_state(Vec::new);
+    let toaster = use_toaster();
+    let attempt = use_state(|| 0_u32);
     {
         let search_result = search_result.clone();
         let series_name = id.clone();
         let series_name2 = id.clone();
+        let attempt2 = *attempt;
         use_effect_with_deps(
             move |_| {
                 spawn_local(async move {
@@ -215,7 +595,11 @@ pub fn Episodes(props: &SearchResult) -> Html {
                     let b = match new_msg {
                         Ok(c) => Some(c),
                         Err(c) => {
-                            log(&c.as_string().unwrap());
+                            toast_error(
+                                &toaster,
+                                CommandError::from_js(c),
+                                Some(Callback::from(move |_| attempt.set(*attempt + 1))),
+                            );
                             None
                         }
                     };
@@ -234,7 +618,7 @@ pub fn Episodes(props: &SearchResult) -> Html {
                     }
                 });
             },
-            series_name2,
+            (series_name2, attempt2),
         );
     }
     let selected_episode: UseStateHandle<String> = use_state(String::new);
@@ -256,6 +640,7 @@ pub fn Episodes(props: &SearchResult) -> Html {
     let mut fin = vec![html!()];
     let srs = search_result;
     if scoped_sr.is_empty() {
+        fin.push(html! {<SeasonSubtitles season_id={id.clone()}/>});
         for i in srs.iter() {
             let y = i.clone();
             fin.push(html! {<div onclick={
@@ -269,9 +654,7 @@ pub fn Episodes(props: &SearchResult) -> Html {
             }
                         id={y.clone().name}
                         class="episode">
-                        if y.clone().img.is_some()
-                            { <b class="episodeName" style={"background: url(".to_owned()+y.clone().img.unwrap().split(' ').collect::<Vec<&str>>().first().unwrap().to_string().as_str()+")"}>{y.clone().name} </b>}
-                            else{<b>{y.clone().name} </b>}
+                        <b class="episodeName" style={y.images.background(GRID_IMAGE_WIDTH)}>{y.clone().name} </b>
                         </div>})
         }
     }
@@ -296,10 +679,13 @@ pub fn Episodes(props: &SearchResult) -> Html {
 pub struct EpisodeViewProps {
     title: String,
     id: String,
+    series_id: String,
     number: i32,
     desc: String,
-    subs: HashMap<String, String>,
+    subs: Vec<SubtitleInfo>,
     url: String,
+    streams: Vec<StreamVariant>,
+    audio: Vec<AudioVersion>,
     nep: Option<Vec<Ep>>,
 }
 
@@ -308,15 +694,360 @@ impl Default for EpisodeViewProps {
         EpisodeViewProps {
             title: String::new(),
             id: String::new(),
+            series_id: String::new(),
             number: 0,
             desc: String::new(),
-            subs: HashMap::new(),
+            subs: vec![],
             url: String::new(),
-            nep: Some(vec![Ep::default(); 0]),
+            streams: vec![],
+            audio: vec![],
+            nep: Some(vec![]),
+        }
+    }
+}
+
+/// A stream of an episode, `hardsub_locale` is empty for the one without
+/// hardsubs.
+#[derive(Serialize, Debug, Deserialize, Default, Clone, PartialEq, Eq)]
+pub struct StreamVariant {
+    pub hardsub_locale: String,
+    pub hardsub_label: String,
+    /// `adaptive_hls`, `adaptive_dash` or `download_hls`.
+    pub protocol: String,
+    pub url: String,
+}
+
+/// The same episode in another audio language.
+#[derive(Serialize, Debug, Deserialize, Default, Clone, PartialEq, Eq)]
+pub struct AudioVersion {
+    pub locale: String,
+    pub label: String,
+    pub ep_id: String,
+}
+
+/// Preferences stored by the backend, shared by `App` as a context and kept
+/// current through the `settings-changed` event. The backend always sends
+/// every field. The derived default is an empty stand-in until `get_settings`
+/// answers; the real defaults come from the backend's `default_settings`.
+#[derive(Serialize, Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Settings {
+    pub version: u32,
+    pub audio_locale: String,
+    pub subtitle_locale: String,
+    /// `subtitles` or `captions`.
+    pub subtitle_kind: String,
+    /// `auto`, `lowest` or `highest`.
+    pub quality: String,
+    pub volume: f64,
+    pub autoplay: bool,
+    pub subtitle_scale: f64,
+    pub subtitle_color: String,
+    pub subtitle_background: bool,
+    pub image_cache_mb: u64,
+    pub subtitle_cache_mb: u64,
+    pub progress_tick_ms: u64,
+    pub download_parallel: usize,
+}
+
+#[hook]
+fn use_settings() -> Settings {
+    use_context::<Settings>().unwrap_or_default()
+}
+
+#[derive(Serialize, Deserialize)]
+struct SettingsArgs {
+    settings: Settings,
+}
+
+/// What `listen` hands to its callback.
+#[derive(Deserialize)]
+struct TauriEvent<T> {
+    payload: T,
+}
+
+const SETTINGS_EVENT: &str = "settings-changed";
+
+/// Calls `onevent` with the payload of every `event` from the backend while
+/// the component is mounted.
+#[hook]
+fn use_tauri_event<T>(event: &'static str, onevent: Callback<T>)
+where
+    T: serde::de::DeserializeOwned + 'static,
+{
+    use_effect_with_deps(
+        move |_| {
+            // the unlisten function, or `true` once unmounted before it came
+            let unlisten: Rc<std::cell::RefCell<(Option<JsValue>, bool)>> = Rc::default();
+            let unlisten2 = unlisten.clone();
+            spawn_local(async move {
+                let handler = Closure::wrap(Box::new(move |e: JsValue| {
+                    if let Ok(e) = serde_wasm_bindgen::from_value::<TauriEvent<T>>(e) {
+                        onevent.emit(e.payload);
+                    }
+                }) as Box<dyn FnMut(JsValue)>);
+                let f = listen(event, &handler).await;
+                handler.forget();
+                let mut state = unlisten2.borrow_mut();
+                if state.1 {
+                    _ = f.unchecked_into::<js_sys::Function>().call0(&JsValue::NULL);
+                } else {
+                    state.0 = Some(f);
+                }
+            });
+            move || {
+                let mut state = unlisten.borrow_mut();
+                state.1 = true;
+                if let Some(f) = state.0.take() {
+                    _ = f.unchecked_into::<js_sys::Function>().call0(&JsValue::NULL);
+                }
+            }
+        },
+        (),
+    );
+}
+
+/// Languages offered in the settings, by locale code.
+const LANGUAGES: [(&str, &str); 11] = [
+    ("ja-JP", "Japanese"),
+    ("en-US", "English (US)"),
+    ("es-419", "Spanish (Latin America)"),
+    ("es-ES", "Spanish (Spain)"),
+    ("pt-BR", "Portuguese (Brazil)"),
+    ("fr-FR", "French"),
+    ("de-DE", "German"),
+    ("it-IT", "Italian"),
+    ("ru-RU", "Russian"),
+    ("ar-SA", "Arabic"),
+    ("hi-IN", "Hindi"),
+];
+
+/// A subtitle track of an episode, fetched once it is picked.
+#[derive(Serialize, Debug, Deserialize, Default, Clone, PartialEq, Eq)]
+pub struct SubtitleInfo {
+    pub locale: String,
+    pub label: String,
+    pub kind: String,
+}
+
+#[derive(Serialize, Debug, Deserialize, Default, Clone, PartialEq, Eq)]
+pub struct SubtitleFile {
+    pub locale: String,
+    pub format: String,
+    pub data: String,
+}
+
+/// A styled subtitle script as `get_ass_script` returns it, drawn over the
+/// video by `ass_overlay`.
+#[derive(Serialize, Debug, Deserialize, Default, Clone, PartialEq)]
+pub struct AssScript {
+    pub play_res_x: f64,
+    pub play_res_y: f64,
+    pub events: Vec<AssEvent>,
+}
+
+#[derive(Serialize, Debug, Deserialize, Default, Clone, PartialEq)]
+pub struct AssEvent {
+    pub start: f64,
+    pub end: f64,
+    pub layer: i32,
+    pub alignment: u8,
+    pub pos: Option<(f64, f64)>,
+    pub movement: Option<AssMove>,
+    /// Seconds of fade in and fade out.
+    pub fade: Option<(f64, f64)>,
+    pub margin_l: f64,
+    pub margin_r: f64,
+    pub margin_v: f64,
+    pub segments: Vec<AssSegment>,
+}
+
+/// `start` and `end` are seconds into the event, both 0 for all of it.
+#[derive(Serialize, Debug, Deserialize, Default, Clone, PartialEq)]
+pub struct AssMove {
+    pub from: (f64, f64),
+    pub to: (f64, f64),
+    pub start: f64,
+    pub end: f64,
+}
+
+impl AssEvent {
+    /// Where `\pos` or `\move` put the event at `now`.
+    fn position_at(&self, now: f64) -> Option<(f64, f64)> {
+        let m = match &self.movement {
+            Some(m) => m,
+            None => return self.pos,
+        };
+        let (start, end) = if m.end > m.start {
+            (m.start, m.end)
+        } else {
+            (0_f64, self.end - self.start)
+        };
+        let k = if end > start {
+            ((now - self.start - start) / (end - start)).clamp(0_f64, 1_f64)
+        } else {
+            1_f64
+        };
+        Some((
+            m.from.0 + (m.to.0 - m.from.0) * k,
+            m.from.1 + (m.to.1 - m.from.1) * k,
+        ))
+    }
+
+    /// 0 to 1, following `\fad`.
+    fn opacity_at(&self, now: f64) -> f64 {
+        let (fade_in, fade_out) = self.fade.unwrap_or_default();
+        let mut opacity = 1_f64;
+        if fade_in > 0_f64 {
+            opacity = opacity.min((now - self.start) / fade_in);
         }
+        if fade_out > 0_f64 {
+            opacity = opacity.min((self.end - now) / fade_out);
+        }
+        opacity.clamp(0_f64, 1_f64)
     }
 }
 
+#[derive(Serialize, Debug, Deserialize, Default, Clone, PartialEq)]
+pub struct AssSegment {
+    pub text: String,
+    pub font: String,
+    pub size: f64,
+    pub color: String,
+    pub outline_color: String,
+    pub outline: f64,
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SubtitleArgs<'a> {
+    ep_id: &'a str,
+    locale: &'a str,
+    /// `subtitles` or `captions`, a locale can have both.
+    kind: &'a str,
+    format: Option<&'a str>,
+    offset_ms: i64,
+}
+
+/// A subtitle file opened from disk, added to the player next to the
+/// episode's own tracks.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct LocalTrack {
+    pub label: String,
+    pub data: String,
+    /// The delay already applied to `data`.
+    pub offset_ms: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LocalSubtitleArgs<'a> {
+    path: &'a str,
+    offset_ms: i64,
+}
+
+#[derive(Serialize)]
+struct DialogFilter {
+    name: &'static str,
+    extensions: &'static [&'static str],
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SaveDialogOptions {
+    default_path: String,
+    filters: Vec<DialogFilter>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DownloadSubtitleArgs<'a> {
+    ep_id: &'a str,
+    locale: &'a str,
+    kind: &'a str,
+    format: &'a str,
+    path: &'a str,
+    offset_ms: i64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DownloadEpisodeArgs<'a> {
+    ep_id: &'a str,
+    quality: &'a str,
+    subs: Vec<String>,
+    subs_kind: &'a str,
+    path: &'a str,
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct DownloadProgress {
+    pub id: String,
+    pub done: usize,
+    pub total: usize,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct DownloadReport {
+    pub path: String,
+    pub bytes: u64,
+    pub subtitles: Vec<String>,
+    pub failed: Vec<String>,
+}
+
+const DOWNLOAD_PROGRESS_EVENT: &str = "download-progress";
+const DOWNLOAD_QUEUE_EVENT: &str = "download-queue";
+
+#[derive(Serialize, Deserialize)]
+struct QueueDownloadArgs<'a> {
+    ep_id: &'a str,
+    title: &'a str,
+    quality: &'a str,
+    subs: Vec<String>,
+    subs_kind: &'a str,
+    path: &'a str,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DownloadIdArgs {
+    id: u64,
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct DownloadRequest {
+    pub ep_id: String,
+    pub title: String,
+    pub path: String,
+}
+
+#[derive(Serialize, Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct DownloadJob {
+    pub id: u64,
+    pub request: DownloadRequest,
+    /// `queued`, `running`, `retrying`, `paused`, `failed` or `done`.
+    pub state: String,
+    pub done: usize,
+    pub total: usize,
+    pub bytes: u64,
+    pub attempts: u32,
+    pub error: Option<String>,
+    pub speed: f64,
+    pub eta_secs: Option<u64>,
+}
+
+#[derive(Serialize)]
+struct OpenDialogOptions {
+    multiple: bool,
+    directory: bool,
+    filters: Vec<DialogFilter>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SubtitleOffsetArgs<'a> {
+    series_id: &'a str,
+    offset_ms: i64,
+}
+
 #[derive(Serialize, Debug, Deserialize, Default, Clone, PartialEq, Eq, Properties)]
 #[serde(rename_all = "snake_case")]
 pub struct Ep {
@@ -395,42 +1126,65 @@ impl Hls {
 
 #[function_component]
 pub fn ViewEp(props: &Nep) -> Html {
-    let Nep {
-        ep_id,
-        ep_type: _,
-        cb,
-    } = props;
+    let Nep { ep_id, ep_type, cb } = props;
+    // downloaded episodes come from the library and need no session
+    let offline = ep_type == "offline";
     let is_loading = use_state(|| true);
     let current_view_ep = use_state(|| ep_id.to_string());
     let search_result: UseStateHandle<EpisodeViewProps> = use_state(|| EpisodeViewProps {
         ..Default::default()
     });
     let hls: UseStateHandle<Hls> = use_state(Hls::new);
+    let toaster = use_toaster();
+    let attempt = use_state(|| 0_u32);
+    let local_tracks: UseStateHandle<Vec<LocalTrack>> = use_state(Vec::new);
+    let settings = use_settings();
+    // url of the stream picked in the menu, empty for the default one
+    let current_stream = use_state(String::new);
     {
         let search_result2 = search_result.clone();
+        let local_tracks = local_tracks.clone();
+        let current_stream2 = current_stream.clone();
+        let toaster = toaster.clone();
         let series_name = current_view_ep;
         let is_loaded = is_loading.clone();
         let hls2 = hls.clone();
+        let attempt2 = *attempt;
         use_effect_with_deps(
-            move |series_name| {
+            move |(series_name, _)| {
                 let sr = series_name.clone();
                 spawn_local(async move {
                     if (sr).is_empty() {
                         return;
                     }
-                    let new_msg = invoke_checked(
-                        "view_episode",
-                        to_value(&Ep {
-                            ep_id: (sr).to_string(),
-                            ep_type: "main".to_string(),
-                        })
-                        .unwrap(),
-                    )
-                    .await;
+                    let new_msg = if offline {
+                        invoke_checked(
+                            "view_offline_episode",
+                            to_value(&EpIdArgs {
+                                ep_id: (sr).to_string(),
+                            })
+                            .unwrap(),
+                        )
+                        .await
+                    } else {
+                        invoke_checked(
+                            "view_episode",
+                            to_value(&Ep {
+                                ep_id: (sr).to_string(),
+                                ep_type: "main".to_string(),
+                            })
+                            .unwrap(),
+                        )
+                        .await
+                    };
                     let b = match new_msg {
                         Ok(c) => Some(c),
                         Err(c) => {
-                            log(&c.as_string().unwrap());
+                            toast_error(
+                                &toaster,
+                                CommandError::from_js(c),
+                                Some(Callback::from(move |_| attempt.set(*attempt + 1))),
+                            );
                             None
                         }
                     };
@@ -438,8 +1192,13 @@ pub fn ViewEp(props: &Nep) -> Html {
                         let a: Result<EpisodeViewProps, serde_wasm_bindgen::Error> =
                             serde_wasm_bindgen::from_value(bs);
                         match a {
-                            Ok(mess) => {
+                            Ok(mut mess) => {
                                 log(mess.id.as_str());
+                                if offline {
+                                    mess.url = offline_src(&format!("{}.m3u8", mess.id));
+                                }
+                                local_tracks.set(vec![]);
+                                current_stream2.set(String::new());
                                 search_result2.set(mess);
                                 is_loaded.set(false);
                             }
@@ -449,29 +1208,128 @@ pub fn ViewEp(props: &Nep) -> Html {
                 });
                 move || hls2.destroy()
             },
-            series_name,
+            (series_name, attempt2),
         );
     }
     let mut b = vec![html! {}];
     let mut c = vec![html! {}];
     let nep = search_result.nep.clone().unwrap();
-    for (_i, j) in nep.iter().enumerate() {
+    for j in nep.iter() {
         let j4 = j.clone();
         c.push(html! {<NextEps ep_id={j4.ep_id} ep_type={j4.ep_type} cb={cb.clone()}/>});
     }
+    // no src yet, `Controls` fetches a track when it gets picked
     for (iterar, item) in search_result.subs.iter().enumerate() {
         b.push(html! {<track id={"sub-".to_owned()+&iterar.to_string()}
-        label={item.0.to_string()}
-        kind="subtitles"
-        srclang={item.0.to_string()}
-        src={item.1.to_string()} type="text/plain" />});
+        label={item.label.clone()}
+        kind={item.kind.clone()}
+        srclang={item.locale.clone()} />});
+    }
+    // opened files come after the episode's tracks, keeping the ids in the
+    // same order as the video's text tracks
+    for (j, item) in local_tracks.iter().enumerate() {
+        b.push(
+            html! {<track id={format!("sub-{}", search_result.subs.len() + j)}
+            label={item.label.clone()}
+            kind="subtitles"
+            srclang="und"
+            data-offset={item.offset_ms.to_string()}
+            src={"data:text/vtt;base64,".to_string() + &base64::encode(item.data.as_bytes())} />},
+        );
+    }
+    let onlocaltrack = {
+        let local_tracks = local_tracks.clone();
+        Callback::from(move |track: LocalTrack| {
+            let mut tracks = (*local_tracks).clone();
+            tracks.push(track);
+            local_tracks.set(tracks);
+        })
+    };
+    // hls.js only plays the adaptive hls variants
+    let mut stream_list = vec![html!()];
+    for variant in search_result
+        .streams
+        .iter()
+        .filter(|v| v.protocol == "adaptive_hls")
+    {
+        let label = if variant.hardsub_locale.is_empty() {
+            "No hardsubs".to_string()
+        } else {
+            format!("Hardsub: {}", variant.hardsub_label)
+        };
+        let active = *current_stream == variant.url
+            || (current_stream.is_empty() && search_result.url == variant.url);
+        let onpick = {
+            let hls = hls.clone();
+            let current_stream = current_stream.clone();
+            let url = variant.url.clone();
+            Callback::from(move |_: MouseEvent| {
+                reload_stream(&hls, url.clone());
+                current_stream.set(url.clone());
+            })
+        };
+        stream_list.push(html! {<a onclick={onpick} class={classes!("quality", active.then_some("active"))}>{label}</a>});
+    }
+    let mut audio_list = vec![html!()];
+    for version in search_result.audio.iter() {
+        let active = version.ep_id == search_result.id;
+        let onpick = {
+            let hls = hls.clone();
+            let search_result = search_result.clone();
+            let current_stream = current_stream.clone();
+            let toaster = toaster.clone();
+            let ep_id = version.ep_id.clone();
+            Callback::from(move |_: MouseEvent| {
+                if ep_id == search_result.id {
+                    return;
+                }
+                let hls = hls.clone();
+                let search_result = search_result.clone();
+                let current_stream = current_stream.clone();
+                let toaster = toaster.clone();
+                let ep_id = ep_id.clone();
+                spawn_local(async move {
+                    let res = invoke_checked(
+                        "view_episode",
+                        to_value(&Ep {
+                            ep_id,
+                            ep_type: "main".to_string(),
+                        })
+                        .unwrap(),
+                    )
+                    .await
+                    .map_err(CommandError::from_js)
+                    .and_then(|c| {
+                        serde_wasm_bindgen::from_value::<EpisodeViewProps>(c)
+                            .map_err(|e| CommandError::Other(e.to_string()))
+                    });
+                    match res {
+                        Ok(episode) => {
+                            // the dub has its own subtitle tracks
+                            clear_tracks();
+                            reload_stream(&hls, episode.url.clone());
+                            current_stream.set(String::new());
+                            search_result.set(episode);
+                        }
+                        Err(e) => toast_error(&toaster, e, None),
+                    }
+                });
+            })
+        };
+        audio_list.push(html! {<a onclick={onpick} class={classes!("quality", active.then_some("active"))}>{version.label.clone()}</a>});
     }
     let tempaa = use_state(|| hls.loadLevel().to_string());
     let levels = use_state(Levs::default);
+    let current_id = search_result.id.clone();
+    let current_title = search_result.title.clone();
+    let series_id = search_result.series_id.clone();
+    let resume_at: UseStateHandle<Option<f64>> = use_state(|| None);
     {
         let is_loading2 = is_loading.clone();
         let levels = levels.clone();
         let hls2 = hls.clone();
+        let resume_at = resume_at.clone();
+        let settings = settings.clone();
         use_effect_with_deps(
             move |is_loading2| {
                 let hls3 = hls2.clone();
@@ -482,7 +1340,36 @@ pub fn ViewEp(props: &Nep) -> Html {
                         let hls5: &Hls = &hls4;
                         let sr = &search_result.url.clone();
                         let hls_inter = hls5.to_owned().init(sr.to_string());
-                        hls_inter.set_loadLevel(0);
+                        // -1 lets hls.js pick, the highest level is only known
+                        // once the manifest is in
+                        hls_inter.set_loadLevel(if settings.quality == "lowest" { 0 } else { -1 });
+                        if let Some(video) = main_video() {
+                            video.set_volume(settings.volume);
+                            if settings.autoplay {
+                                _ = video.play();
+                            }
+                        }
+                        let playhead = invoke_checked(
+                            "get_playhead",
+                            to_value(&EpIdArgs {
+                                ep_id: search_result.id.clone(),
+                            })
+                            .unwrap(),
+                        )
+                        .await;
+                        match playhead {
+                            Ok(c) => {
+                                if let Ok(Some(p)) =
+                                    serde_wasm_bindgen::from_value::<Option<f64>>(c)
+                                {
+                                    if let Some(video) = main_video() {
+                                        video.set_current_time(p);
+                                    }
+                                    resume_at.set(Some(p));
+                                }
+                            }
+                            Err(c) => log_error(c),
+                        }
                         second_tick().await;
                         second_tick().await;
                         second_tick().await;
@@ -491,6 +1378,9 @@ pub fn ViewEp(props: &Nep) -> Html {
                             serde_wasm_bindgen::from_value(hls_inter.levels());
                         let b = a.unwrap();
                         levels2.set(b.clone());
+                        if settings.quality == "highest" && !b.0.is_empty() {
+                            hls_inter.set_currentLevel(b.0.len() as i32 - 1);
+                        }
                         log(b.0.len().to_string().as_str());
                         hls3.set(hls_inter.to_owned());
                     });
@@ -524,7 +1414,33 @@ pub fn ViewEp(props: &Nep) -> Html {
         hls2.set(hls5.clone().set_level(tempa.parse::<i32>().unwrap()));})} class="quality">{levels.0.get(i as usize).unwrap()}</a>});
     }
 
-    let onplaypause = Callback::from(move |_| {
+    {
+        let shown = *resume_at;
+        let resume_at = resume_at.clone();
+        use_effect_with_deps(
+            move |shown| {
+                if shown.is_some() {
+                    spawn_local(async move {
+                        initialize_atomic_clocks().await;
+                        initialize_atomic_clocks().await;
+                        resume_at.set(None);
+                    });
+                }
+                || {}
+            },
+            shown,
+        );
+    }
+    let onstartover = {
+        let resume_at = resume_at.clone();
+        Callback::from(move |_: MouseEvent| {
+            if let Some(video) = main_video() {
+                video.set_current_time(0_f64);
+            }
+            resume_at.set(None);
+        })
+    };
+    let onplaypause = Callback::from(move |_| {
         let a = web_sys::window()
             .unwrap()
             .document()
@@ -560,7 +1476,11 @@ pub fn ViewEp(props: &Nep) -> Html {
         {b}
 
     </video>
-    <Controls lev_list={level_list} loading={*is_loading} playpausecb={onplaypause.clone()} progress={0_f64}/>
+    if let Some(p) = *resume_at {
+        <div class="resume-prompt"><b>{format!("Resumed at {}", format_time(p))}</b>
+        <button type="button" class="btn" onclick={onstartover}>{"Start over"}</button></div>
+    }
+    <Controls ep_id={current_id} title={current_title} series_id={series_id} onlocaltrack={onlocaltrack} lev_list={level_list} stream_list={stream_list} audio_list={audio_list} loading={*is_loading} playpausecb={onplaypause.clone()} progress={0_f64}/>
 
     </div>
     <div class="nextepsGrid"><b>{"Next Episodes :"}</b>{c}</div>
@@ -571,7 +1491,13 @@ pub fn ViewEp(props: &Nep) -> Html {
 
 #[derive(PartialEq, Properties)]
 pub struct ControlsProps {
+    ep_id: String,
+    title: String,
+    series_id: String,
+    onlocaltrack: Callback<LocalTrack>,
     lev_list: Vec<yew::virtual_dom::VNode>,
+    stream_list: Vec<yew::virtual_dom::VNode>,
+    audio_list: Vec<yew::virtual_dom::VNode>,
     loading: bool,
     playpausecb: Callback<MouseEvent>,
     progress: f64,
@@ -588,7 +1514,8 @@ pub fn Controls(props: &ControlsProps) -> Html {
     let prog2 = progress.clone();
     let buffprog = use_state(|| 0_f64);
     let buffprog2 = buffprog.clone();
-    let volume = use_state(|| 1_f64);
+    let settings = use_settings();
+    let volume = use_state(|| settings.volume);
     let vol2 = volume.clone();
     let onvaluechanged = Callback::from(move |ev: InputEvent| {
         vol2.set(
@@ -614,7 +1541,35 @@ pub fn Controls(props: &ControlsProps) -> Html {
                     .unwrap(),
             )
     });
+    let last_sent = use_mut_ref(|| 0_f64);
+    {
+        // keep the final position when the player goes away mid-episode
+        let last_sent = last_sent.clone();
+        use_effect_with_deps(
+            move |ep_id| {
+                let ep_id = ep_id.clone();
+                move || {
+                    if let Some(video) = main_video() {
+                        let ct = video.current_time();
+                        if !ep_id.is_empty() && !ct.is_nan() && ct != *last_sent.borrow() {
+                            send_progress(ep_id, ct, video.duration());
+                        }
+                    }
+                }
+            },
+            props.ep_id.clone(),
+        );
+    }
+    let ep_id = props.ep_id.clone();
     let progresscb = Callback::from(move |_| {
+        if let Some(video) = main_video() {
+            let ct = video.current_time();
+            let mut last = last_sent.borrow_mut();
+            if !ep_id.is_empty() && !ct.is_nan() && (ct - *last).abs() >= PROGRESS_SYNC_SECS {
+                *last = ct;
+                send_progress(ep_id.clone(), ct, video.duration());
+            }
+        }
         dtrig2.set(
             web_sys::window()
                 .unwrap()
@@ -705,10 +1660,11 @@ pub fn Controls(props: &ControlsProps) -> Html {
         }
     });
     let progcb3 = progresscb;
+    let tick = settings.progress_tick_ms as f64 / 1000_f64;
     use_effect(move || {
         let progcb2 = progcb3;
         spawn_local(async move {
-            tick_x(0.1_f64).await;
+            tick_x(tick).await;
             progcb2.emit(());
         });
     });
@@ -786,6 +1742,205 @@ pub fn Controls(props: &ControlsProps) -> Html {
     });
     let active_track = use_state(|| 0_u32);
     let as_set = use_state(|| false);
+    let ass_script: UseStateHandle<Option<Rc<AssScript>>> = use_state(|| None);
+    // the second language, shown smaller at the top of the frame
+    let secondary: UseStateHandle<Option<u32>> = use_state(|| None);
+    {
+        let ass_script = ass_script.clone();
+        let secondary = secondary.clone();
+        use_effect_with_deps(
+            move |_| {
+                ass_script.set(None);
+                secondary.set(None);
+                || {}
+            },
+            props.ep_id.clone(),
+        );
+    }
+    let toaster = use_toaster();
+    // subtitle delay in milliseconds, remembered per series
+    let offset = use_state(|| 0_i64);
+    let download: UseStateHandle<Option<DownloadProgress>> = use_state(|| None);
+    {
+        let download = download.clone();
+        use_tauri_event(
+            DOWNLOAD_PROGRESS_EVENT,
+            Callback::from(move |p: DownloadProgress| download.set(Some(p))),
+        );
+    }
+    {
+        let offset = offset.clone();
+        use_effect_with_deps(
+            move |series_id| {
+                let series_id = series_id.clone();
+                spawn_local(async move {
+                    if series_id.is_empty() {
+                        return;
+                    }
+                    let res = invoke_checked(
+                        "get_subtitle_offset",
+                        to_value(&SubtitleOffsetArgs {
+                            series_id: &series_id,
+                            offset_ms: 0,
+                        })
+                        .unwrap(),
+                    )
+                    .await;
+                    match res {
+                        Ok(c) => {
+                            let ms = serde_wasm_bindgen::from_value::<i64>(c).unwrap_or(0);
+                            resync_tracks(ms);
+                            offset.set(ms);
+                        }
+                        Err(c) => log_error(c),
+                    }
+                });
+                || {}
+            },
+            props.series_id.clone(),
+        );
+    }
+    let setoffset = {
+        let offset = offset.clone();
+        let series_id = props.series_id.clone();
+        let toaster = toaster.clone();
+        Callback::from(move |ms: i64| {
+            resync_tracks(ms);
+            offset.set(ms);
+            let series_id = series_id.clone();
+            let toaster = toaster.clone();
+            spawn_local(async move {
+                let args = SubtitleOffsetArgs {
+                    series_id: &series_id,
+                    offset_ms: ms,
+                };
+                if let Err(c) =
+                    invoke_checked("set_subtitle_offset", to_value(&args).unwrap()).await
+                {
+                    toast_error(&toaster, CommandError::from_js(c), None);
+                }
+            });
+        })
+    };
+    let ondelaydown = {
+        let setoffset = setoffset.clone();
+        let ms = *offset;
+        Callback::from(move |_: MouseEvent| setoffset.emit(ms - OFFSET_STEP_MS))
+    };
+    let ondelayup = {
+        let setoffset = setoffset.clone();
+        let ms = *offset;
+        Callback::from(move |_: MouseEvent| setoffset.emit(ms + OFFSET_STEP_MS))
+    };
+    let ondelayinput = Callback::from(move |ev: Event| {
+        let input = ev.target_dyn_into::<HtmlInputElement>().unwrap();
+        if let Ok(ms) = input.value().trim().parse::<i64>() {
+            setoffset.emit(ms);
+        }
+    });
+    let onopenfile = {
+        let offset = offset.clone();
+        let toaster = toaster.clone();
+        let onlocaltrack = props.onlocaltrack.clone();
+        Callback::from(move |_: MouseEvent| {
+            let offset_ms = *offset;
+            let toaster = toaster.clone();
+            let onlocaltrack = onlocaltrack.clone();
+            spawn_local(async move {
+                match open_subtitle_file(offset_ms).await {
+                    Ok(Some(track)) => onlocaltrack.emit(track),
+                    Ok(None) => {}
+                    Err(e) => toast_error(&toaster, e, None),
+                }
+            });
+        })
+    };
+    let ondownload = {
+        let toaster = toaster.clone();
+        let ep_id = props.ep_id.clone();
+        let settings = settings.clone();
+        let download = download.clone();
+        Callback::from(move |_: MouseEvent| {
+            if download.is_some() {
+                toaster.info("A download is already running.".to_string());
+                return;
+            }
+            // taken before the first progress arrives, a second click waits
+            download.set(Some(DownloadProgress::default()));
+            let ep_id = ep_id.clone();
+            let settings = settings.clone();
+            let toaster = toaster.clone();
+            let download = download.clone();
+            spawn_local(async move {
+                match download_episode(&ep_id, &settings).await {
+                    Ok(Some(report)) => {
+                        toaster.info(format!("Saved the episode to {}.", report.path));
+                        for failed in report.failed {
+                            toaster.warn(format!("Failed to save subtitles. {failed}"));
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => toast_error(&toaster, e, None),
+                }
+                download.set(None);
+            });
+        })
+    };
+    let onqueue = {
+        let toaster = toaster.clone();
+        let ep_id = props.ep_id.clone();
+        let title = props.title.clone();
+        let settings = settings.clone();
+        Callback::from(move |_: MouseEvent| {
+            let ep_id = ep_id.clone();
+            let title = title.clone();
+            let settings = settings.clone();
+            let toaster = toaster.clone();
+            spawn_local(async move {
+                match queue_download(&ep_id, &title, &settings).await {
+                    Ok(Some(message)) => toaster.info(message),
+                    Ok(None) => {}
+                    Err(e) => toast_error(&toaster, e, None),
+                }
+            });
+        })
+    };
+    let download_label = match &*download {
+        Some(p) if p.total > 0 => format!("{}%", p.done * 100 / p.total),
+        Some(_) => "…".to_string(),
+        None => "DL".to_string(),
+    };
+    let onsavesubs = {
+        let offset = offset.clone();
+        let toaster = toaster.clone();
+        let ep_id = props.ep_id.clone();
+        let as_set = as_set.clone();
+        let active_track = active_track.clone();
+        Callback::from(move |_: MouseEvent| {
+            // local files are already on disk
+            let track = track_element(*active_track)
+                .filter(|_| *as_set)
+                .map(|t| (t.srclang(), t.kind()))
+                .filter(|(l, _)| l != "und");
+            let (locale, kind) = match track {
+                Some(track) => track,
+                None => {
+                    toaster.info("Pick one of the episode's subtitle tracks first.".to_string());
+                    return;
+                }
+            };
+            let ep_id = ep_id.clone();
+            let offset_ms = *offset;
+            let toaster = toaster.clone();
+            spawn_local(async move {
+                match save_subtitles(&ep_id, &locale, &kind, offset_ms).await {
+                    Ok(Some(message)) => toaster.info(message),
+                    Ok(None) => {}
+                    Err(e) => toast_error(&toaster, e, None),
+                }
+            });
+        })
+    };
     let tracklist = web_sys::window()
         .unwrap()
         .document()
@@ -797,59 +1952,160 @@ pub fn Controls(props: &ControlsProps) -> Html {
         .text_tracks()
         .unwrap();
     let active_trackselect = active_track;
-    let settrack = Callback::from(move |e: MouseEvent| {
-        let target = e.target_dyn_into::<HtmlElement>().unwrap();
-        if *as_set {
-            web_sys::window()
-                .unwrap()
-                .document()
-                .unwrap()
-                .get_element_by_id(&("sub-".to_owned() + &(*active_trackselect).to_string()))
-                .unwrap()
-                .dyn_into::<HtmlTrackElement>()
-                .unwrap()
-                .track()
-                .unwrap()
-                .set_mode(TextTrackMode::Hidden);
-            web_sys::window()
-                .unwrap()
-                .document()
-                .unwrap()
-                .get_element_by_id(&("sub-".to_owned() + &target.id()))
-                .unwrap()
-                .dyn_into::<HtmlTrackElement>()
-                .unwrap()
-                .track()
-                .unwrap()
-                .set_mode(TextTrackMode::Showing);
-            active_trackselect.set(target.id().parse::<u32>().unwrap());
-        } else {
-            web_sys::window()
-                .unwrap()
-                .document()
-                .unwrap()
-                .get_element_by_id(&("sub-".to_owned() + &target.id()))
-                .unwrap()
-                .dyn_into::<HtmlTrackElement>()
-                .unwrap()
-                .track()
-                .unwrap()
-                .set_mode(TextTrackMode::Showing);
-            active_trackselect.set(target.id().parse::<u32>().unwrap());
+    let picktrack = {
+        let ep_id = props.ep_id.clone();
+        let ass_script = ass_script.clone();
+        let offset = offset.clone();
+        let secondary = secondary.clone();
+        let toaster = toaster.clone();
+        let as_set = as_set.clone();
+        let active_trackselect = active_trackselect.clone();
+        Callback::from(move |index: u32| {
+            if *as_set {
+                if let Some(track) = track_element(*active_trackselect).and_then(|t| t.track()) {
+                    track.set_mode(TextTrackMode::Hidden);
+                }
+            }
+            ass_script.set(None);
+            active_trackselect.set(index);
             as_set.set(true);
-        }
-    });
+            if *secondary == Some(index) {
+                secondary.set(None);
+            }
+            let element = match track_element(index) {
+                Some(element) => element,
+                None => return,
+            };
+            let ep_id = ep_id.clone();
+            let toaster = toaster.clone();
+            let ass_script = ass_script.clone();
+            let offset_ms = *offset;
+            spawn_local(async move {
+                // styled tracks are drawn by the overlay, keeping their
+                // positions and colors; captions are plain text anyway
+                if element.kind() == "subtitles" && element.src().is_empty() {
+                    match load_ass_script(&ep_id, &element.srclang(), &element.kind()).await {
+                        Ok(script) => {
+                            ass_script.set(Some(Rc::new(script)));
+                            return;
+                        }
+                        Err(e) => log(&format!("Falling back to plain subtitles. {e}")),
+                    }
+                }
+                if let Err(e) =
+                    load_track(&element, &ep_id, offset_ms, TextTrackMode::Showing).await
+                {
+                    toast_error(&toaster, e, None);
+                }
+            });
+        })
+    };
+    let settrack = {
+        let picktrack = picktrack.clone();
+        Callback::from(move |e: MouseEvent| {
+            let target = e.target_dyn_into::<HtmlElement>().unwrap();
+            picktrack.emit(target.id().parse::<u32>().unwrap());
+        })
+    };
+    {
+        // start each episode with the preferred track on
+        let settings = settings.clone();
+        use_effect_with_deps(
+            move |ep_id| {
+                if !ep_id.is_empty() {
+                    if let Some(index) = preferred_track(&settings) {
+                        picktrack.emit(index);
+                    }
+                }
+                || {}
+            },
+            props.ep_id.clone(),
+        );
+    }
+    let setsecondary = {
+        let ep_id = props.ep_id.clone();
+        let secondary = secondary.clone();
+        let offset = offset.clone();
+        let toaster = toaster.clone();
+        Callback::from(move |index: u32| {
+            // the primary already shows this language
+            if *as_set && *active_trackselect == index {
+                return;
+            }
+            if let Some(track) = secondary.and_then(track_element).and_then(|t| t.track()) {
+                track.set_mode(TextTrackMode::Disabled);
+            }
+            // picking the current one again turns it off
+            if *secondary == Some(index) {
+                secondary.set(None);
+                return;
+            }
+            secondary.set(Some(index));
+            let element = match track_element(index) {
+                Some(element) => element,
+                None => return,
+            };
+            let ep_id = ep_id.clone();
+            let toaster = toaster.clone();
+            let offset_ms = *offset;
+            spawn_local(async move {
+                // hidden tracks still update their active cues, which are
+                // drawn by the controls instead of the browser
+                if let Err(e) = load_track(&element, &ep_id, offset_ms, TextTrackMode::Hidden).await
+                {
+                    toast_error(&toaster, e, None);
+                }
+            });
+        })
+    };
     let mut tracks = vec![html! {}];
     for i in 0..tracklist.length() {
-        tracks.push(html!{<a onclick={settrack.clone()} id={i.to_string()} class="subtitle">{tracklist.get(i).unwrap().label()}</a>});
+        let onsecondary = {
+            let setsecondary = setsecondary.clone();
+            Callback::from(move |_: MouseEvent| setsecondary.emit(i))
+        };
+        tracks.push(html!{<div class="subtitle-row"><a onclick={settrack.clone()} id={i.to_string()} class="subtitle">{tracklist.get(i).unwrap().label()}</a>
+        <a onclick={onsecondary} class={classes!("subtitle-second", (*secondary == Some(i)).then_some("active"))} title="Show at the top as a second language">{"2nd"}</a></div>});
     }
+    let second_text = secondary.and_then(active_cue_text).unwrap_or_default();
+    let overlay = match &*ass_script {
+        Some(script) => ass_overlay(script, *offset, settings.subtitle_scale),
+        None => html! {},
+    };
+    // `::cue` only takes a few properties, the styled overlay gets the size
+    let cue_style = format!(
+        "#mainvideo::cue{{color:{};font-size:{}%;background-color:{};}} .secondary-subs{{color:{};font-size:{}em;}}",
+        settings.subtitle_color,
+        settings.subtitle_scale * 100_f64,
+        if settings.subtitle_background { "rgba(0,0,0,0.6)" } else { "transparent" },
+        settings.subtitle_color,
+        settings.subtitle_scale * 1.1_f64,
+    );
     let a = html! {<>
+        <style>{cue_style}</style>
+        {overlay}
+        if !second_text.is_empty() {
+            <div class="secondary-subs">{second_text}</div>
+        }
         <div id="video-controls" class="controls display-control">
               <button id="quality-btn" class="dropbtn btn-settings">
               <div id="quality" class="dropdown-content">{props.lev_list.clone()}</div></button>
+              <button id="stream-btn" class="dropbtn textbtn" title="Stream">{"HS"}
+              <div class="dropdown-content">{props.stream_list.clone()}</div></button>
+              <button id="audio-btn" class="dropbtn textbtn" title="Audio">{"Audio"}
+              <div class="dropdown-content">{props.audio_list.clone()}</div></button>
+              <button id="download-btn" class="dropbtn textbtn" title="Download">{download_label}
+              <div class="dropdown-content">
+              <a onclick={ondownload} class="subtitle">{"Save now…"}</a>
+              <a onclick={onqueue} class="subtitle">{"Add to downloads…"}</a></div></button>
               <button id="fs-btn" class="fs-button" onclick={onclickfs}></button>
               <button id="vol-btn" class="dropbtn volume"><input oninput={onvaluechanged} type="range"  min="0" max="1" step="0.01" value={volume.to_string()} class="volume-slider" id="volume"/></button>
-              <button id="language-btn" class="dropbtn ccbtn btn-settings"><div id="quality" class="dropdown-content">{tracks}</div></button>
+              <button id="language-btn" class="dropbtn ccbtn btn-settings"><div id="quality" class="dropdown-content">{tracks}
+              <a onclick={onopenfile} class="subtitle">{"Open subtitle file…"}</a>
+              <a onclick={onsavesubs} class="subtitle">{"Save subtitles…"}</a>
+              <div class="subtitle-delay"><a onclick={ondelaydown}>{"-"}</a>
+              <input type="number" step={OFFSET_STEP_MS.to_string()} value={offset.to_string()} onchange={ondelayinput} title="Subtitle delay (ms)"/>
+              <a onclick={ondelayup}>{"+"}</a></div></div></button>
             </div>
         <div id="controls-right" class="controls clr">
         <button id="playpause-btn" class="play-button" onclick={props.playpausecb.clone()}></button>
@@ -862,6 +2118,480 @@ pub fn Controls(props: &ControlsProps) -> Html {
     a
 }
 
+/// The `<track>` behind entry `index` of the subtitle menu.
+fn track_element(index: u32) -> Option<HtmlTrackElement> {
+    web_sys::window()?
+        .document()?
+        .get_element_by_id(&format!("sub-{index}"))?
+        .dyn_into::<HtmlTrackElement>()
+        .ok()
+}
+
+/// Step of the subtitle delay buttons.
+const OFFSET_STEP_MS: i64 = 100;
+
+/// Moves the cues of every loaded `<track>` to a new delay. Each track
+/// remembers the delay its cues carry in `data-offset`.
+fn resync_tracks(offset_ms: i64) {
+    let mut index = 0;
+    while let Some(element) = track_element(index) {
+        index += 1;
+        if element.src().is_empty() {
+            continue;
+        }
+        let applied = element
+            .get_attribute("data-offset")
+            .and_then(|v| v.parse::<i64>().ok())
+            .unwrap_or(0);
+        let delta = (offset_ms - applied) as f64 / 1000_f64;
+        if let Some(cues) = element.track().and_then(|t| t.cues()) {
+            for i in 0..cues.length() {
+                if let Some(cue) = cues.get(i) {
+                    cue.set_start_time((cue.start_time() + delta).max(0_f64));
+                    cue.set_end_time((cue.end_time() + delta).max(0_f64));
+                }
+            }
+        }
+        _ = element.set_attribute("data-offset", &offset_ms.to_string());
+    }
+}
+
+/// Fetches the vtt of a `<track>`, delayed by `offset_ms`, and sets its mode.
+async fn load_track(
+    element: &HtmlTrackElement,
+    ep_id: &str,
+    offset_ms: i64,
+    mode: TextTrackMode,
+) -> Result<(), CommandError> {
+    if element.src().is_empty() {
+        let locale = element.srclang();
+        let res = invoke_checked(
+            "get_subtitle",
+            to_value(&SubtitleArgs {
+                ep_id,
+                locale: &locale,
+                kind: &element.kind(),
+                format: None,
+                offset_ms,
+            })
+            .unwrap(),
+        )
+        .await
+        .map_err(CommandError::from_js)?;
+        let file: SubtitleFile =
+            serde_wasm_bindgen::from_value(res).map_err(|e| CommandError::Other(e.to_string()))?;
+        element.set_src(
+            &("data:text/vtt;base64,".to_string() + &base64::encode(file.data.as_bytes())),
+        );
+        _ = element.set_attribute("data-offset", &offset_ms.to_string());
+    }
+    if let Some(track) = element.track() {
+        track.set_mode(mode);
+    }
+    Ok(())
+}
+
+/// Asks for a subtitle file and converts it in the backend. `None` when the
+/// dialog is cancelled.
+async fn open_subtitle_file(offset_ms: i64) -> Result<Option<LocalTrack>, CommandError> {
+    let options = OpenDialogOptions {
+        multiple: false,
+        directory: false,
+        filters: vec![DialogFilter {
+            name: "Subtitles",
+            extensions: &["ass", "ssa", "srt", "vtt"],
+        }],
+    };
+    let picked = open_dialog(to_value(&options).unwrap())
+        .await
+        .map_err(CommandError::from_js)?;
+    let path = match picked.as_string() {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let res = invoke_checked(
+        "load_subtitle_file",
+        to_value(&LocalSubtitleArgs {
+            path: &path,
+            offset_ms,
+        })
+        .unwrap(),
+    )
+    .await
+    .map_err(CommandError::from_js)?;
+    let file: SubtitleFile =
+        serde_wasm_bindgen::from_value(res).map_err(|e| CommandError::Other(e.to_string()))?;
+    Ok(Some(LocalTrack {
+        label: file.locale,
+        data: file.data,
+        offset_ms,
+    }))
+}
+
+/// Asks where to save the `locale` track of `kind` of `ep_id`, the extension
+/// picks the format. `None` when the dialog is cancelled.
+async fn save_subtitles(
+    ep_id: &str,
+    locale: &str,
+    kind: &str,
+    offset_ms: i64,
+) -> Result<Option<String>, CommandError> {
+    // captions keep their own name next to the subtitles of the same locale
+    let name = if kind == "captions" {
+        format!("{locale}.cc")
+    } else {
+        locale.to_string()
+    };
+    let options = SaveDialogOptions {
+        default_path: format!("{ep_id}.{name}.ass"),
+        filters: vec![
+            DialogFilter {
+                name: "Advanced SubStation Alpha",
+                extensions: &["ass"],
+            },
+            DialogFilter {
+                name: "SubRip",
+                extensions: &["srt"],
+            },
+            DialogFilter {
+                name: "WebVTT",
+                extensions: &["vtt"],
+            },
+        ],
+    };
+    let picked = save_dialog(to_value(&options).unwrap())
+        .await
+        .map_err(CommandError::from_js)?;
+    let path = match picked.as_string() {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let format = match path.rsplit('.').next().map(|e| e.to_lowercase()) {
+        Some(e) if e == "srt" || e == "vtt" => e,
+        _ => "ass".to_string(),
+    };
+    let res = invoke_checked(
+        "download_subtitles",
+        to_value(&DownloadSubtitleArgs {
+            ep_id,
+            locale,
+            kind,
+            format: &format,
+            path: &path,
+            offset_ms,
+        })
+        .unwrap(),
+    )
+    .await
+    .map_err(CommandError::from_js)?;
+    Ok(res.as_string())
+}
+
+/// Asks where to save `ep_id`, `None` when the dialog was closed. The
+/// backend turns `.ts` into `.mp4` for fragmented mp4 streams.
+async fn pick_download_path(ep_id: &str) -> Result<Option<String>, CommandError> {
+    let options = SaveDialogOptions {
+        default_path: format!("{ep_id}.ts"),
+        filters: vec![
+            DialogFilter {
+                name: "MPEG transport stream",
+                extensions: &["ts"],
+            },
+            DialogFilter {
+                name: "MPEG-4",
+                extensions: &["mp4"],
+            },
+        ],
+    };
+    let picked = save_dialog(to_value(&options).unwrap())
+        .await
+        .map_err(CommandError::from_js)?;
+    Ok(picked.as_string())
+}
+
+fn preferred_subs(settings: &Settings) -> Vec<String> {
+    if settings.subtitle_locale.is_empty() {
+        vec![]
+    } else {
+        vec![settings.subtitle_locale.clone()]
+    }
+}
+
+/// Downloads `ep_id` right away with the preferred quality and subtitles.
+async fn download_episode(
+    ep_id: &str,
+    settings: &Settings,
+) -> Result<Option<DownloadReport>, CommandError> {
+    let path = match pick_download_path(ep_id).await? {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let res = invoke_checked(
+        "download_episode",
+        to_value(&DownloadEpisodeArgs {
+            ep_id,
+            quality: &settings.quality,
+            subs: preferred_subs(settings),
+            subs_kind: &settings.subtitle_kind,
+            path: &path,
+        })
+        .unwrap(),
+    )
+    .await
+    .map_err(CommandError::from_js)?;
+    Ok(serde_wasm_bindgen::from_value(res).ok())
+}
+
+/// `download_episode` through the download queue.
+async fn queue_download(
+    ep_id: &str,
+    title: &str,
+    settings: &Settings,
+) -> Result<Option<String>, CommandError> {
+    let path = match pick_download_path(ep_id).await? {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+    let res = invoke_checked(
+        "queue_download",
+        to_value(&QueueDownloadArgs {
+            ep_id,
+            title,
+            quality: &settings.quality,
+            subs: preferred_subs(settings),
+            subs_kind: &settings.subtitle_kind,
+            path: &path,
+        })
+        .unwrap(),
+    )
+    .await
+    .map_err(CommandError::from_js)?;
+    Ok(res.as_string())
+}
+
+/// The text of the cues of track `index` showing right now, without the vtt
+/// markup.
+fn active_cue_text(index: u32) -> Option<String> {
+    let cues = track_element(index)?.track()?.active_cues()?;
+    let lines: Vec<String> = (0..cues.length())
+        .filter_map(|i| cues.get(i))
+        .map(|cue| {
+            let mut text = String::new();
+            let mut in_tag = false;
+            for c in cue.text().chars() {
+                match c {
+                    '<' => in_tag = true,
+                    '>' => in_tag = false,
+                    c if !in_tag => text.push(c),
+                    _ => {}
+                }
+            }
+            text
+        })
+        .collect();
+    Some(lines.join("\n"))
+}
+
+async fn load_ass_script(ep_id: &str, locale: &str, kind: &str) -> Result<AssScript, CommandError> {
+    let res = invoke_checked(
+        "get_ass_script",
+        to_value(&SubtitleArgs {
+            ep_id,
+            locale,
+            kind,
+            format: None,
+            offset_ms: 0,
+        })
+        .unwrap(),
+    )
+    .await
+    .map_err(CommandError::from_js)?;
+    serde_wasm_bindgen::from_value(res).map_err(|e| CommandError::Other(e.to_string()))
+}
+
+/// `value` as a quoted css string, so a font name from a script can't end
+/// the declaration it is in.
+fn css_string(value: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            c if c.is_control() => quoted.push_str(&format!("\\{:x} ", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Draws the events of `script` showing at the video's current time, delayed
+/// by `offset_ms`, mapped from the script's resolution onto the picture
+/// (without the letterboxing).
+fn ass_overlay(script: &AssScript, offset_ms: i64, scale: f64) -> Html {
+    let video = match main_video() {
+        Some(video) => video,
+        None => return html! {},
+    };
+    let now = video.current_time() - offset_ms as f64 / 1000_f64;
+    let (cw, ch) = (video.offset_width() as f64, video.offset_height() as f64);
+    let (vw, vh) = match (video.video_width(), video.video_height()) {
+        (0, _) | (_, 0) => (cw, ch),
+        (w, h) => (w as f64, h as f64),
+    };
+    let fit = (cw / vw).min(ch / vh);
+    let (w, h) = (vw * fit, vh * fit);
+    let left = video.offset_left() as f64 + (cw - w) / 2_f64;
+    let top = video.offset_top() as f64 + (ch - h) / 2_f64;
+    let (sx, sy) = (w / script.play_res_x, h / script.play_res_y);
+    let events = script
+        .events
+        .iter()
+        .filter(|e| e.start <= now && now < e.end)
+        .map(|e| {
+            // numpad alignment: column left/center/right, row bottom/middle/top
+            let col = (e.alignment.clamp(1, 9) - 1) % 3;
+            let row = (e.alignment.clamp(1, 9) - 1) / 3;
+            let (x, y) = e.position_at(now).unwrap_or((
+                match col {
+                    0 => e.margin_l,
+                    1 => (script.play_res_x + e.margin_l - e.margin_r) / 2_f64,
+                    _ => script.play_res_x - e.margin_r,
+                },
+                match row {
+                    0 => script.play_res_y - e.margin_v,
+                    1 => script.play_res_y / 2_f64,
+                    _ => e.margin_v,
+                },
+            ));
+            let style = format!(
+                "left:{}px;top:{}px;transform:translate({}%,{}%);text-align:{};z-index:{};opacity:{};",
+                left + x * sx,
+                top + y * sy,
+                -50 * col as i32,
+                [-100, -50, 0][row as usize],
+                ["left", "center", "right"][col as usize],
+                e.layer,
+                e.opacity_at(now),
+            );
+            let segments = e
+                .segments
+                .iter()
+                .map(|s| {
+                    let o = s.outline * sy;
+                    let style = format!(
+                        "font-family:{},sans-serif;font-size:{}px;color:{};font-weight:{};font-style:{};text-decoration:{};text-shadow:{o}px 0 {c},-{o}px 0 {c},0 {o}px {c},0 -{o}px {c},{o}px {o}px {c},-{o}px -{o}px {c},{o}px -{o}px {c},-{o}px {o}px {c};",
+                        css_string(&s.font),
+                        s.size * sy * scale,
+                        s.color,
+                        if s.bold { "bold" } else { "normal" },
+                        if s.italic { "italic" } else { "normal" },
+                        if s.underline { "underline" } else { "none" },
+                        c = s.outline_color,
+                    );
+                    html! {<span style={style}>{s.text.clone()}</span>}
+                })
+                .collect::<Html>();
+            html! {<div class="ass-event" style={style}>{segments}</div>}
+        })
+        .collect::<Html>();
+    html! {<div class="ass-overlay">{events}</div>}
+}
+
+/// The `<track>` matching the preferred language, of the preferred kind if
+/// the episode has both.
+fn preferred_track(settings: &Settings) -> Option<u32> {
+    if settings.subtitle_locale.is_empty() {
+        return None;
+    }
+    let mut fallback = None;
+    let mut index = 0;
+    while let Some(element) = track_element(index) {
+        if element.srclang() == settings.subtitle_locale {
+            if element.kind() == settings.subtitle_kind {
+                return Some(index);
+            }
+            fallback = fallback.or(Some(index));
+        }
+        index += 1;
+    }
+    fallback
+}
+
+/// Drops the loaded subtitles before the `<track>` elements get reused for
+/// another episode.
+fn clear_tracks() {
+    let mut index = 0;
+    while let Some(element) = track_element(index) {
+        index += 1;
+        if let Some(track) = element.track() {
+            track.set_mode(TextTrackMode::Disabled);
+        }
+        _ = element.remove_attribute("src");
+        _ = element.remove_attribute("data-offset");
+    }
+}
+
+/// Points the player at another stream of the same episode, keeping the
+/// position and whether it was playing.
+fn reload_stream(hls: &Hls, url: String) {
+    let video = match main_video() {
+        Some(video) => video,
+        None => return,
+    };
+    let at = video.current_time();
+    let paused = video.paused();
+    hls.loadSource(JsValue::from(url));
+    spawn_local(async move {
+        // seeking before the new manifest is in gets ignored
+        for _ in 0..100 {
+            if video.ready_state() >= 1 {
+                break;
+            }
+            tick_x(0.1_f64).await;
+        }
+        video.set_current_time(at);
+        if !paused {
+            _ = video.play();
+        }
+    });
+}
+
+fn main_video() -> Option<HtmlVideoElement> {
+    web_sys::window()?
+        .document()?
+        .get_element_by_id("mainvideo")?
+        .dyn_into::<HtmlVideoElement>()
+        .ok()
+}
+
+#[derive(Serialize, Deserialize)]
+struct EpIdArgs {
+    ep_id: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ProgressArgs {
+    ep_id: String,
+    position: f64,
+    duration: Option<f64>,
+}
+
+fn send_progress(ep_id: String, position: f64, duration: f64) {
+    spawn_local(async move {
+        let args = ProgressArgs {
+            ep_id,
+            position,
+            duration: Some(duration).filter(|d| !d.is_nan()),
+        };
+        if let Err(c) = invoke_checked("record_progress", to_value(&args).unwrap()).await {
+            log_error(c);
+        }
+    });
+}
+
 fn video_progress() -> f64 {
     if let Some(a) = web_sys::window()
         .unwrap()
@@ -910,9 +2640,7 @@ pub struct Levs(pub Vec<Lev>);
 
 impl Display for Levs {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        self.0.iter().fold(Ok(()), |result, album| {
-            result.and_then(|_| writeln!(f, "{album}"))
-        })
+        self.0.iter().try_for_each(|album| writeln!(f, "{album}"))
     }
 }
 impl Display for Lev {
@@ -930,13 +2658,10 @@ pub fn NextEps(props: &Nep) -> Html {
     });
     let is_loading = use_state(|| true);
     let is_clicked = use_state(|| false);
-    let Nep {
-        ep_id,
-        ep_type: _,
-        cb,
-    } = props;
+    let Nep { ep_id, ep_type, cb } = props;
     {
         let ep_id2 = ep_id.clone();
+        let offline = ep_type == "offline";
         let is_loading2 = is_loading.clone();
         let search_result2 = search_result.clone();
         let search_result3 = search_result.clone();
@@ -946,9 +2671,28 @@ pub fn NextEps(props: &Nep) -> Html {
                     if ep_id2.is_empty() {
                         return;
                     }
-                    let new_msg = invoke_checked(
-                        "get_episodes",
-                        to_value(&EpOrSeries {
+                    if offline {
+                        // the library knows the title, there is no artwork
+                        match invoke_checked_no_arg("list_offline").await {
+                            Ok(c) => {
+                                let entries: Vec<LibraryEntry> =
+                                    serde_wasm_bindgen::from_value(c).unwrap_or_default();
+                                if let Some(entry) = entries.into_iter().find(|e| e.ep_id == ep_id2)
+                                {
+                                    search_result2.set(vec![SearchResult {
+                                        name: entry.title,
+                                        ..Default::default()
+                                    }]);
+                                    is_loading2.set(false);
+                                }
+                            }
+                            Err(c) => log_error(c),
+                        }
+                        return;
+                    }
+                    let new_msg = invoke_checked(
+                        "get_episodes",
+                        to_value(&EpOrSeries {
                             series_id: None,
                             ep_id: Some(ep_id2),
                         })
@@ -958,7 +2702,7 @@ pub fn NextEps(props: &Nep) -> Html {
                     let b = match new_msg {
                         Ok(c) => Some(c),
                         Err(c) => {
-                            log(&c.as_string().unwrap());
+                            log_error(c);
                             None
                         }
                     };
@@ -992,11 +2736,217 @@ pub fn NextEps(props: &Nep) -> Html {
     let is_loaded = is_loading;
     html! {
         if !*is_loaded{
-        <b onclick={onclick} class="secondary" style={let sr2 = search_result3.clone();"background: url(".to_owned()+sr2.clone().first().unwrap().img.to_owned().unwrap().split(' ').collect::<Vec<&str>>().first().unwrap().to_string().as_str()+")"}>{{let sr2 = search_result3.clone();sr2.clone().first().unwrap().name.to_owned()} }</b>
+        <b onclick={onclick} class="secondary" style={search_result3.first().map(|y| y.images.background(GRID_IMAGE_WIDTH)).unwrap_or_default()}>{{let sr2 = search_result3.clone();sr2.clone().first().unwrap().name.to_owned()} }</b>
         }
     }
 }
 
+#[derive(Serialize, Debug, Deserialize, Clone, Default, PartialEq)]
+pub struct HistoryEntry {
+    pub episode_id: String,
+    pub series_id: String,
+    pub title: String,
+    pub series_title: String,
+    pub timestamp: u64,
+    pub position: f64,
+    pub duration: f64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HistoryArgs {
+    limit: Option<usize>,
+}
+
+fn format_time(secs: f64) -> String {
+    let secs = if secs.is_nan() { 0_u64 } else { secs as u64 };
+    format!("{:0>2}:{:0>2}", secs / 60, secs % 60)
+}
+
+#[derive(PartialEq, Properties)]
+pub struct RecentlyWatchedProps {
+    pub on_select: Callback<String>,
+}
+
+#[function_component]
+pub fn RecentlyWatched(props: &RecentlyWatchedProps) -> Html {
+    let history: UseStateHandle<Vec<HistoryEntry>> = use_state(Vec::new);
+    {
+        let history = history.clone();
+        use_effect_with_deps(
+            move |_| {
+                spawn_local(async move {
+                    let new_msg = invoke_checked(
+                        "get_history",
+                        to_value(&HistoryArgs { limit: Some(10) }).unwrap(),
+                    )
+                    .await;
+                    match new_msg {
+                        Ok(c) => match serde_wasm_bindgen::from_value::<Vec<HistoryEntry>>(c) {
+                            Ok(mess) => history.set(mess),
+                            Err(e) => log(&format!("{e}")),
+                        },
+                        Err(c) => log_error(c),
+                    }
+                });
+                || {}
+            },
+            (),
+        );
+    }
+    let toaster = use_toaster();
+    let clear = {
+        let history = history.clone();
+        Callback::from(move |_: MouseEvent| {
+            let history = history.clone();
+            let toaster = toaster.clone();
+            spawn_local(async move {
+                match invoke_checked_no_arg("clear_history").await {
+                    Ok(_) => history.set(vec![]),
+                    Err(c) => toast_error(&toaster, CommandError::from_js(c), None),
+                }
+            });
+        })
+    };
+    if history.is_empty() {
+        return html! {};
+    }
+    let items = history
+        .iter()
+        .map(|h| {
+            let on_select = props.on_select.clone();
+            let ep_id = h.episode_id.clone();
+            html! {<b class="recent" onclick={Callback::from(move |_| on_select.emit(ep_id.clone()))}>
+                {format!("{} - {} ({})", h.series_title, h.title, format_time(h.position))}
+            </b>}
+        })
+        .collect::<Html>();
+    html! {
+        <div class="recentrow"><b>{"Recently watched :"}</b>{items}
+        <button type="button" class="btn" onclick={clear}>{"Clear"}</button></div>
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SeriesIdArgs<'a> {
+    series_id: &'a str,
+}
+
+#[derive(PartialEq, Properties)]
+pub struct WatchlistProps {
+    pub on_select: Callback<(String, String)>,
+}
+
+#[function_component]
+pub fn Watchlist(props: &WatchlistProps) -> Html {
+    let watchlist: UseStateHandle<Vec<SearchResult>> = use_state(Vec::new);
+    let toaster = use_toaster();
+    {
+        let watchlist = watchlist.clone();
+        use_effect_with_deps(
+            move |_| {
+                spawn_local(async move {
+                    match invoke_checked_no_arg("get_watchlist").await {
+                        Ok(c) => match serde_wasm_bindgen::from_value::<Vec<SearchResult>>(c) {
+                            Ok(mess) => watchlist.set(mess),
+                            Err(e) => log(&format!("{e}")),
+                        },
+                        Err(c) => match CommandError::from_js(c) {
+                            // anonymous sessions have no watchlist
+                            CommandError::NotLoggedIn => {}
+                            e => toast_error(&toaster, e, None),
+                        },
+                    }
+                });
+                || {}
+            },
+            (),
+        );
+    }
+    if watchlist.is_empty() {
+        return html! {};
+    }
+    let items = watchlist
+        .iter()
+        .map(|y| {
+            let on_select = props.on_select.clone();
+            let res = (y.id.clone(), y.name.clone());
+            html! {<b class="secondary" onclick={Callback::from(move |_| on_select.emit(res.clone()))}
+                style={y.images.background(GRID_IMAGE_WIDTH)}>
+                {y.name.clone()}
+            </b>}
+        })
+        .collect::<Html>();
+    html! {
+        <div class="recentrow"><b>{"Watchlist :"}</b>{items}</div>
+    }
+}
+
+#[derive(PartialEq, Properties)]
+pub struct WatchlistToggleProps {
+    pub series_id: String,
+}
+
+/// Follow/unfollow button for a series.
+#[function_component]
+pub fn WatchlistToggle(props: &WatchlistToggleProps) -> Html {
+    let following = use_state(|| false);
+    {
+        let following = following.clone();
+        use_effect_with_deps(
+            move |series_id: &String| {
+                let series_id = series_id.clone();
+                spawn_local(async move {
+                    if let Ok(c) = invoke_checked_no_arg("get_watchlist").await {
+                        if let Ok(list) = serde_wasm_bindgen::from_value::<Vec<SearchResult>>(c) {
+                            following.set(list.iter().any(|s| s.id == series_id));
+                        }
+                    }
+                });
+                || {}
+            },
+            props.series_id.clone(),
+        );
+    }
+    let toaster = use_toaster();
+    let onclick = {
+        let following = following.clone();
+        let series_id = props.series_id.clone();
+        Callback::from(move |_: MouseEvent| {
+            let following = following.clone();
+            let series_id = series_id.clone();
+            let toaster = toaster.clone();
+            spawn_local(async move {
+                let cmd = if *following {
+                    "remove_from_watchlist"
+                } else {
+                    "add_to_watchlist"
+                };
+                match invoke_checked(
+                    cmd,
+                    to_value(&SeriesIdArgs {
+                        series_id: &series_id,
+                    })
+                    .unwrap(),
+                )
+                .await
+                {
+                    Ok(c) => {
+                        toaster
+                            .info(serde_wasm_bindgen::from_value::<String>(c).unwrap_or_default());
+                        following.set(!*following);
+                    }
+                    Err(c) => toast_error(&toaster, CommandError::from_js(c), None),
+                }
+            });
+        })
+    };
+    html! {
+        <button type="button" class="btn watchlist-btn" {onclick}>
+            {if *following { "Unfollow" } else { "Follow" }}
+        </button>
+    }
+}
+
 #[derive(Eq, PartialEq, Properties)]
 pub struct SearchProps {
     pub search_string: String,
@@ -1006,10 +2956,13 @@ pub struct SearchProps {
 pub fn Series(props: &SearchResult) -> Html {
     let SearchResult { id, .. } = props;
     let search_result: UseStateHandle<Vec<SearchResult>> = use_state(Vec::new);
+    let toaster = use_toaster();
+    let attempt = use_state(|| 0_u32);
     {
         let search_result = search_result.clone();
         let series_name = id.clone();
         let series_name2 = id.clone();
+        let attempt2 = *attempt;
         use_effect_with_deps(
             move |_| {
                 spawn_local(async move {
@@ -1027,7 +2980,11 @@ pub fn Series(props: &SearchResult) -> Html {
                     let b = match new_msg {
                         Ok(c) => Some(c),
                         Err(c) => {
-                            log(&c.as_string().unwrap());
+                            toast_error(
+                                &toaster,
+                                CommandError::from_js(c),
+                                Some(Callback::from(move |_| attempt.set(*attempt + 1))),
+                            );
                             None
                         }
                     };
@@ -1046,7 +3003,7 @@ pub fn Series(props: &SearchResult) -> Html {
                     }
                 });
             },
-            series_name2,
+            (series_name2, attempt2),
         );
     }
     let selected_episode = use_state(|| (String::from(""), String::from("")));
@@ -1056,6 +3013,7 @@ pub fn Series(props: &SearchResult) -> Html {
     let srs = search_result;
 
     if scoped_sr.1.is_empty() {
+        a.push(html! {<WatchlistToggle series_id={id.clone()}/>});
         for i in srs.iter() {
             let y = i.clone();
             a.push(html! {<div onclick={
@@ -1071,9 +3029,7 @@ pub fn Series(props: &SearchResult) -> Html {
                 }
                         id={y.clone().name}
                         class="season">
-                        if y.clone().img.is_some()
-                            { <b class="seasonName" style={"background: url(".to_owned()+y.clone().img.unwrap().split(' ').collect::<Vec<&str>>().first().unwrap().to_string().as_str()+")"}>{y.clone().name} </b>}
-                            else{<b>{y.clone().name} </b>}
+                        <b class="seasonName" style={y.images.background(GRID_IMAGE_WIDTH)}>{y.clone().name} </b>
                         </div>})
         }
     } else {
@@ -1089,14 +3045,19 @@ pub fn Search(props: &SearchProps) -> Html {
     let SearchProps { search_string } = props;
     let search_input_ref = use_node_ref();
     let selected_series = use_state(|| (String::from(""), String::from("")));
+    let selected_episode = use_state(String::new);
 
     let series_name = use_state(|| search_string.clone());
     let search_result: UseStateHandle<Vec<SearchResult>> = use_state(Vec::new);
+    let toaster = use_toaster();
+    let attempt = use_state(|| 0_u32);
     {
         let search_result = search_result.clone();
         let series_name = series_name.clone();
         let sr = selected_series.clone();
-        let series_name2 = series_name.clone();
+        let series_name2 = (*series_name).clone();
+        let attempt2 = *attempt;
+        let toaster = toaster.clone();
         use_effect_with_deps(
             move |_| {
                 spawn_local(async move {
@@ -1115,7 +3076,11 @@ pub fn Search(props: &SearchProps) -> Html {
                     let b = match new_msg {
                         Ok(c) => Some(c),
                         Err(c) => {
-                            log(&c.as_string().unwrap());
+                            toast_error(
+                                &toaster,
+                                CommandError::from_js(c),
+                                Some(Callback::from(move |_| attempt.set(*attempt + 1))),
+                            );
                             None
                         }
                     };
@@ -1132,7 +3097,7 @@ pub fn Search(props: &SearchProps) -> Html {
                     }
                 });
             },
-            series_name2,
+            (series_name2, attempt2),
         );
     }
     let i = series_name.clone();
@@ -1153,7 +3118,6 @@ pub fn Search(props: &SearchProps) -> Html {
     let was_set_back = use_state(|| false);
     let b2: Callback<String> = {
         let display_state_final = display_state.clone();
-        let sn = sn;
         let set_back = was_set_back.clone();
         Callback::from({
             move |name: String| {
@@ -1186,8 +3150,24 @@ pub fn Search(props: &SearchProps) -> Html {
         let render = &*(display_state);
         return render.clone();
     }
-    let second_part = if selected_series.1.is_empty() {
-        html! {
+    let second_part = if !selected_episode.is_empty() {
+        html! {<div class="seasonview">
+        <ViewEp key={(*selected_episode).clone()} ep_id={(*selected_episode).clone()} ep_type={"main".to_string()} cb={
+            let selected_episode = selected_episode.clone();
+            Callback::from(move |name: String| selected_episode.set(name))
+        }/></div>}
+    } else if selected_series.1.is_empty() {
+        html! {<>
+        if srs.is_empty() {
+            <RecentlyWatched on_select={
+                let selected_episode = selected_episode.clone();
+                Callback::from(move |name: String| selected_episode.set(name))
+            }/>
+            <Watchlist on_select={
+                let selected_series = selected_series.clone();
+                Callback::from(move |res: (String, String)| selected_series.set(res))
+            }/>
+        }
          <div id="test"><div class="row" id="search-header"><b><div class="col">{"Name:"}</div></b><div class="col">{"Desc"}</div></div>
         {  (*search_result).iter().enumerate().map(|(x,y)|
             html!{<div class="row" key={x.to_string()}  id={"animetitle".to_owned()+&x.to_string()}><b onclick=
@@ -1201,9 +3181,9 @@ pub fn Search(props: &SearchProps) -> Html {
                             log(&res.0);
                             scoped_sr.set(res);
                         })
-                    }><div class="col"  >{y.name.to_owned()+ ":"}</div></b><div class="col" style={"background: url(".to_owned()+y.clone().img.unwrap().split(' ').collect::<Vec<&str>>().first().unwrap().to_string().as_str()+")"}>{y.desc.to_owned()}</div></div>
+                    }><div class="col"  >{y.name.to_owned()+ ":"}</div></b><div class="col" style={y.images.background(BANNER_IMAGE_WIDTH)}>{y.desc.to_owned()}</div></div>
                 }).collect::<Html>()
-        }</div>}
+        }</div></>}
     } else {
         html! {<div class="seasonview">
         <Series name={let sr = selected_series.clone();sr.1.to_owned()} id={let sr = selected_series.clone();sr.0.to_owned()} desc={"".to_string()}/></div>}
@@ -1219,52 +3199,788 @@ pub fn Search(props: &SearchProps) -> Html {
     }
 }
 
+#[derive(Serialize, Debug, Deserialize, Default, Clone, PartialEq, Eq)]
+pub struct AccountList {
+    pub active: Option<String>,
+    pub saved: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LogoutArgs {
+    forget: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SwitchArgs<'a> {
+    username: &'a str,
+}
+
 #[derive(PartialEq, Properties)]
-pub struct BackProps {
-    pub location: String,
-    pub callback: Callback<MouseEvent>,
+pub struct AccountMenuProps {
+    pub accounts: AccountList,
+    pub on_switched: Callback<()>,
+    pub on_logged_out: Callback<()>,
+    /// Asks for the login form, the current account stays signed in.
+    pub on_add_account: Callback<()>,
 }
 
 #[function_component]
-pub fn Backbtn(props: &BackProps) -> Html {
-    let BackProps {
-        location: _,
-        callback,
+pub fn AccountMenu(props: &AccountMenuProps) -> Html {
+    let AccountMenuProps {
+        accounts,
+        on_switched,
+        on_logged_out,
+        on_add_account,
     } = props;
-    html! {<button onclick={callback} type="button" class="btn">{"Back"}</button>}
+    let toaster = use_toaster();
+    let add_account = {
+        let on_add_account = on_add_account.clone();
+        Callback::from(move |_: MouseEvent| on_add_account.emit(()))
+    };
+    let logout = |forget: bool| {
+        let on_logged_out = on_logged_out.clone();
+        let toaster = toaster.clone();
+        Callback::from(move |_: MouseEvent| {
+            let on_logged_out = on_logged_out.clone();
+            let toaster = toaster.clone();
+            spawn_local(async move {
+                match invoke_checked("logout", to_value(&LogoutArgs { forget }).unwrap()).await {
+                    Ok(c) => {
+                        toaster
+                            .info(serde_wasm_bindgen::from_value::<String>(c).unwrap_or_default());
+                        on_logged_out.emit(());
+                    }
+                    Err(c) => toast_error(&toaster, CommandError::from_js(c), None),
+                }
+            });
+        })
+    };
+    let mut items = vec![html! {}];
+    for name in accounts
+        .saved
+        .iter()
+        .filter(|n| Some(*n) != accounts.active.as_ref())
+    {
+        let name2 = name.clone();
+        let on_switched = on_switched.clone();
+        let toaster = toaster.clone();
+        items.push(html! {<a class="account" onclick={Callback::from(move |_| {
+            let name = name2.clone();
+            let on_switched = on_switched.clone();
+            let toaster = toaster.clone();
+            spawn_local(async move {
+                match invoke_checked("switch_account", to_value(&SwitchArgs { username: &name }).unwrap()).await {
+                    Ok(c) => {
+                        toaster.info(serde_wasm_bindgen::from_value::<String>(c).unwrap_or_default());
+                        on_switched.emit(());
+                    }
+                    Err(c) => toast_error(&toaster, CommandError::from_js(c), None),
+                }
+            });
+        })}>{name}</a>});
+    }
+    html! {
+        <div class="accountmenu">
+            <button type="button" class="btn accountbtn">{accounts.active.clone().unwrap_or_else(|| "Anon".to_string())}
+            <div class="account-dropdown">
+                {items}
+                <a class="account" onclick={add_account}>{"Add account"}</a>
+                <a class="account" onclick={logout(false)}>{"Log out"}</a>
+                if accounts.active.is_some() {
+                    <a class="account" onclick={logout(true)}>{"Log out and forget"}</a>
+                }
+            </div>
+            </button>
+        </div>
+    }
+}
+
+fn format_bytes(bytes: f64) -> String {
+    const MB: f64 = 1024_f64 * 1024_f64;
+    if bytes >= 1024_f64 * MB {
+        format!("{:.2} GB", bytes / 1024_f64 / MB)
+    } else {
+        format!("{:.1} MB", bytes / MB)
+    }
+}
+
+#[derive(PartialEq, Properties)]
+pub struct DownloadsViewProps {
+    pub onclose: Callback<()>,
+}
+
+/// The download queue, kept current through the queue events.
+#[function_component]
+pub fn DownloadsView(props: &DownloadsViewProps) -> Html {
+    let toaster = use_toaster();
+    let jobs: UseStateHandle<Vec<DownloadJob>> = use_state(Vec::new);
+    {
+        let jobs = jobs.clone();
+        let toaster = toaster.clone();
+        use_effect_with_deps(
+            move |_| {
+                spawn_local(async move {
+                    match invoke_checked_no_arg("list_downloads").await {
+                        Ok(c) => jobs.set(serde_wasm_bindgen::from_value(c).unwrap_or_default()),
+                        Err(c) => toast_error(&toaster, CommandError::from_js(c), None),
+                    }
+                });
+                || {}
+            },
+            (),
+        );
+    }
+    {
+        let jobs = jobs.clone();
+        use_tauri_event(
+            DOWNLOAD_QUEUE_EVENT,
+            Callback::from(move |list: Vec<DownloadJob>| jobs.set(list)),
+        );
+    }
+    let action = |command: &'static str, id: u64| {
+        let toaster = toaster.clone();
+        Callback::from(move |_: MouseEvent| {
+            let toaster = toaster.clone();
+            spawn_local(async move {
+                if let Err(c) =
+                    invoke_checked(command, to_value(&DownloadIdArgs { id }).unwrap()).await
+                {
+                    toast_error(&toaster, CommandError::from_js(c), None);
+                }
+            });
+        })
+    };
+    let onclear = {
+        let toaster = toaster.clone();
+        Callback::from(move |_: MouseEvent| {
+            let toaster = toaster.clone();
+            spawn_local(async move {
+                if let Err(c) = invoke_checked_no_arg("clear_finished_downloads").await {
+                    toast_error(&toaster, CommandError::from_js(c), None);
+                }
+            });
+        })
+    };
+    let onclose = {
+        let onclose = props.onclose.clone();
+        Callback::from(move |_: MouseEvent| onclose.emit(()))
+    };
+    let rows = jobs.iter().map(|job| {
+        let percent = if job.total > 0 {
+            job.done as f64 * 100_f64 / job.total as f64
+        } else {
+            0_f64
+        };
+        let mut details = vec![format!("{:.0}%", percent), format_bytes(job.bytes as f64)];
+        if job.state == "running" && job.speed > 0_f64 {
+            details.push(format!("{}/s", format_bytes(job.speed)));
+        }
+        if let Some(eta) = job.eta_secs.filter(|_| job.state == "running") {
+            details.push(format!("{} left", format_time(eta as f64)));
+        }
+        let toggle = match job.state.as_str() {
+            "queued" | "running" | "retrying" => html! {<a class="textbtn" onclick={action("pause_download", job.id)}>{"Pause"}</a>},
+            "paused" | "failed" => html! {<a class="textbtn" onclick={action("resume_download", job.id)}>{"Resume"}</a>},
+            _ => html! {},
+        };
+        let cancel = if job.state == "done" { "Remove" } else { "Cancel" };
+        html! {
+            <div class="download-row" key={job.id.to_string()}>
+                <div class="download-title" title={job.request.path.clone()}>{job.request.title.clone()}</div>
+                <progress class="download-progress" value={percent.to_string()} max="100"></progress>
+                <div class="download-details">
+                    <span class={classes!("download-state", job.state.clone())}>{job.state.clone()}</span>
+                    {details.join(" · ")}
+                </div>
+                if let Some(error) = &job.error {
+                    <div class="download-error">{error.clone()}</div>
+                }
+                <div class="download-actions">
+                    {toggle}
+                    <a class="textbtn" onclick={action("cancel_download", job.id)}>{cancel}</a>
+                </div>
+            </div>
+        }
+    });
+    html! {
+        <div class="settings-backdrop">
+        <div class="settings-panel">
+            <h2>{"Downloads"}</h2>
+            if jobs.is_empty() {
+                <p>{"Nothing queued."}</p>
+            }
+            {for rows}
+            <div class="settings-buttons">
+                <button type="button" onclick={onclear}>{"Clear finished"}</button>
+                <button type="button" onclick={onclose}>{"Close"}</button>
+            </div>
+        </div>
+        </div>
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct LocalSubtitle {
+    pub locale: String,
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct LibraryEntry {
+    pub ep_id: String,
+    pub title: String,
+    pub series_id: String,
+    pub series_title: String,
+    pub number: i32,
+    pub desc: String,
+    pub path: String,
+    pub subtitles: Vec<LocalSubtitle>,
+    pub bytes: u64,
+    pub added: u64,
+}
+
+#[derive(PartialEq, Properties)]
+pub struct OfflineLibraryProps {
+    pub onclose: Callback<()>,
+}
+
+/// Downloaded episodes by series, played from disk without a session.
+#[function_component]
+pub fn OfflineLibrary(props: &OfflineLibraryProps) -> Html {
+    let toaster = use_toaster();
+    let entries: UseStateHandle<Vec<LibraryEntry>> = use_state(Vec::new);
+    let playing: UseStateHandle<String> = use_state(String::new);
+    // bumped after a delete to list the library again
+    let generation = use_state(|| 0_u32);
+    {
+        let entries = entries.clone();
+        let toaster = toaster.clone();
+        use_effect_with_deps(
+            move |_| {
+                spawn_local(async move {
+                    match invoke_checked_no_arg("list_offline").await {
+                        Ok(c) => entries.set(serde_wasm_bindgen::from_value(c).unwrap_or_default()),
+                        Err(c) => toast_error(&toaster, CommandError::from_js(c), None),
+                    }
+                });
+                || {}
+            },
+            *generation,
+        );
+    }
+    let onclose = {
+        let onclose = props.onclose.clone();
+        Callback::from(move |_: MouseEvent| onclose.emit(()))
+    };
+    if !playing.is_empty() {
+        let onback = {
+            let playing = playing.clone();
+            Callback::from(move |_: MouseEvent| playing.set(String::new()))
+        };
+        let onnext = {
+            let playing = playing.clone();
+            Callback::from(move |ep_id: String| playing.set(ep_id))
+        };
+        return html! {<>
+            <div class="library-bar">
+                <a class="textbtn" onclick={onback}>{"Back to library"}</a>
+            </div>
+            <ViewEp key={(*playing).clone()} ep_id={(*playing).clone()} ep_type={"offline".to_string()} cb={onnext}/>
+        </>};
+    }
+    let delete = |entry: &LibraryEntry| {
+        let toaster = toaster.clone();
+        let generation = generation.clone();
+        let ep_id = entry.ep_id.clone();
+        Callback::from(move |_: MouseEvent| {
+            let toaster = toaster.clone();
+            let generation = generation.clone();
+            let ep_id = ep_id.clone();
+            spawn_local(async move {
+                match invoke_checked("delete_offline", to_value(&EpIdArgs { ep_id }).unwrap()).await
+                {
+                    Ok(c) => {
+                        toaster
+                            .info(serde_wasm_bindgen::from_value::<String>(c).unwrap_or_default());
+                        generation.set(*generation + 1);
+                    }
+                    Err(c) => toast_error(&toaster, CommandError::from_js(c), None),
+                }
+            });
+        })
+    };
+    let mut rows = vec![];
+    let mut series = "";
+    for entry in entries.iter() {
+        // the backend sorts by series, a heading starts each one
+        if entry.series_title != series {
+            series = &entry.series_title;
+            rows.push(html! {<h3 class="library-series">{series.to_string()}</h3>});
+        }
+        let onplay = {
+            let playing = playing.clone();
+            let ep_id = entry.ep_id.clone();
+            Callback::from(move |_: MouseEvent| playing.set(ep_id.clone()))
+        };
+        let locales = entry
+            .subtitles
+            .iter()
+            .map(|s| s.locale.clone())
+            .collect::<Vec<_>>();
+        let mut details = vec![format_bytes(entry.bytes as f64)];
+        if !locales.is_empty() {
+            details.push(format!("Subtitles: {}", locales.join(", ")));
+        }
+        rows.push(html! {
+            <div class="download-row" key={entry.ep_id.clone()}>
+                <div class="download-title" title={entry.desc.clone()}>
+                    {format!("{}. {}", entry.number, entry.title)}
+                </div>
+                <div class="download-details">{details.join(" · ")}</div>
+                <div class="download-actions">
+                    <a class="textbtn" onclick={onplay}>{"Play"}</a>
+                    <a class="textbtn" onclick={delete(entry)}>{"Delete"}</a>
+                </div>
+            </div>
+        });
+    }
+    html! {
+        <div class="library">
+            <div class="library-bar">
+                <h2>{"Offline library"}</h2>
+                <a class="textbtn" onclick={onclose}>{"Close"}</a>
+            </div>
+            if entries.is_empty() {
+                <p>{"Nothing downloaded yet."}</p>
+            }
+            {rows}
+        </div>
+    }
 }
 
-#[derive(Clone, PartialEq, Eq, Debug)]
-pub struct Timer {
-    clock: Option<()>,
+#[derive(PartialEq, Properties)]
+pub struct SettingsViewProps {
+    pub onclose: Callback<()>,
+}
+
+/// Edits a copy of the settings, nothing is applied before "Save".
+#[function_component]
+pub fn SettingsView(props: &SettingsViewProps) -> Html {
+    let toaster = use_toaster();
+    let current = use_settings();
+    let draft = use_state(|| current.clone());
+
+    // one handler for every field, `update` gets the draft and the raw value
+    // (checkboxes give "true" or "false")
+    let field = |update: fn(&mut Settings, String)| {
+        let draft = draft.clone();
+        Callback::from(move |e: Event| {
+            let target = e.target();
+            let value = if let Some(input) = target
+                .clone()
+                .and_then(|t| t.dyn_into::<HtmlInputElement>().ok())
+            {
+                if input.type_() == "checkbox" {
+                    input.checked().to_string()
+                } else {
+                    input.value()
+                }
+            } else if let Some(select) = target.and_then(|t| t.dyn_into::<HtmlSelectElement>().ok())
+            {
+                select.value()
+            } else {
+                return;
+            };
+            let mut next = (*draft).clone();
+            update(&mut next, value);
+            draft.set(next);
+        })
+    };
+    let clear = |command: &'static str| {
+        let toaster = toaster.clone();
+        Callback::from(move |_: MouseEvent| {
+            let toaster = toaster.clone();
+            spawn_local(async move {
+                match invoke_checked_no_arg(command).await {
+                    Ok(c) => toaster
+                        .info(serde_wasm_bindgen::from_value::<String>(c).unwrap_or_default()),
+                    Err(c) => toast_error(&toaster, CommandError::from_js(c), None),
+                }
+            });
+        })
+    };
+    let onsave = {
+        let draft = draft.clone();
+        let toaster = toaster.clone();
+        let onclose = props.onclose.clone();
+        Callback::from(move |_: MouseEvent| {
+            let settings = (*draft).clone();
+            let toaster = toaster.clone();
+            let onclose = onclose.clone();
+            spawn_local(async move {
+                match invoke_checked(
+                    "set_settings",
+                    to_value(&SettingsArgs { settings }).unwrap(),
+                )
+                .await
+                {
+                    Ok(_) => {
+                        toaster.info("Settings saved.".to_string());
+                        onclose.emit(());
+                    }
+                    Err(c) => toast_error(&toaster, CommandError::from_js(c), None),
+                }
+            });
+        })
+    };
+    let oncancel = {
+        let onclose = props.onclose.clone();
+        Callback::from(move |_: MouseEvent| onclose.emit(()))
+    };
+    let onreset = {
+        let draft = draft.clone();
+        let toaster = toaster.clone();
+        Callback::from(move |_: MouseEvent| {
+            let draft = draft.clone();
+            let toaster = toaster.clone();
+            spawn_local(async move {
+                match invoke_checked_no_arg("default_settings").await {
+                    Ok(c) => match serde_wasm_bindgen::from_value(c) {
+                        Ok(defaults) => draft.set(defaults),
+                        Err(e) => log(&format!("{e}")),
+                    },
+                    Err(c) => toast_error(&toaster, CommandError::from_js(c), None),
+                }
+            });
+        })
+    };
+
+    let languages = |selected: &str, none: &str| {
+        html! {<>
+            <option value="" selected={selected.is_empty()}>{none.to_string()}</option>
+            {for LANGUAGES.iter().map(|(code, name)| html! {
+                <option value={*code} selected={selected == *code}>{*name}</option>
+            })}
+        </>}
+    };
+    let choice = |selected: &str, options: &[(&'static str, &'static str)]| {
+        html! {<>
+            {for options.iter().map(|(value, name)| html! {
+                <option value={*value} selected={selected == *value}>{*name}</option>
+            })}
+        </>}
+    };
+    let s = &*draft;
+    html! {
+        <div class="settings-backdrop">
+        <div class="settings-panel">
+            <h2>{"Settings"}</h2>
+            <h3>{"Playback"}</h3>
+            <label>{"Audio language"}
+                <select onchange={field(|s, v| s.audio_locale = v)}>
+                    {languages(&s.audio_locale, "No preference")}
+                </select>
+            </label>
+            <label>{"Starting quality"}
+                <select onchange={field(|s, v| s.quality = v)}>
+                    {choice(&s.quality, &[("auto", "Automatic"), ("lowest", "Lowest"), ("highest", "Highest")])}
+                </select>
+            </label>
+            <label>{"Volume"}
+                <input type="range" min="0" max="1" step="0.05" value={s.volume.to_string()}
+                    onchange={field(|s, v| s.volume = v.parse().unwrap_or(s.volume))}/>
+            </label>
+            <label>{"Autoplay"}
+                <input type="checkbox" checked={s.autoplay}
+                    onchange={field(|s, v| s.autoplay = v == "true")}/>
+            </label>
+            <label>{"Progress refresh (ms)"}
+                <input type="number" min="50" max="1000" step="50" value={s.progress_tick_ms.to_string()}
+                    onchange={field(|s, v| s.progress_tick_ms = v.parse().unwrap_or(s.progress_tick_ms))}/>
+            </label>
+            <h3>{"Subtitles"}</h3>
+            <label>{"Language"}
+                <select onchange={field(|s, v| s.subtitle_locale = v)}>
+                    {languages(&s.subtitle_locale, "Off")}
+                </select>
+            </label>
+            <label>{"Kind"}
+                <select onchange={field(|s, v| s.subtitle_kind = v)}>
+                    {choice(&s.subtitle_kind, &[("subtitles", "Subtitles"), ("captions", "Captions")])}
+                </select>
+            </label>
+            <label>{"Size"}
+                <input type="range" min="0.5" max="3" step="0.1" value={s.subtitle_scale.to_string()}
+                    onchange={field(|s, v| s.subtitle_scale = v.parse().unwrap_or(s.subtitle_scale))}/>
+            </label>
+            <label>{"Color"}
+                <input type="color" value={s.subtitle_color.clone()}
+                    onchange={field(|s, v| s.subtitle_color = v)}/>
+            </label>
+            <label>{"Background"}
+                <input type="checkbox" checked={s.subtitle_background}
+                    onchange={field(|s, v| s.subtitle_background = v == "true")}/>
+            </label>
+            <h3>{"Storage"}</h3>
+            <label>{"Parallel downloads"}
+                <input type="number" min="1" max="16" value={s.download_parallel.to_string()}
+                    onchange={field(|s, v| s.download_parallel = v.parse().unwrap_or(s.download_parallel))}/>
+            </label>
+            <label>{"Image cache (MB)"}
+                <input type="number" min="16" value={s.image_cache_mb.to_string()}
+                    onchange={field(|s, v| s.image_cache_mb = v.parse().unwrap_or(s.image_cache_mb))}/>
+                <a class="textbtn" onclick={clear("clear_image_cache")}>{"Clear"}</a>
+            </label>
+            <label>{"Subtitle cache (MB)"}
+                <input type="number" min="4" value={s.subtitle_cache_mb.to_string()}
+                    onchange={field(|s, v| s.subtitle_cache_mb = v.parse().unwrap_or(s.subtitle_cache_mb))}/>
+                <a class="textbtn" onclick={clear("clear_subtitle_cache")}>{"Clear"}</a>
+            </label>
+            <div class="settings-buttons">
+                <button type="button" onclick={onreset}>{"Reset defaults"}</button>
+                <button type="button" onclick={oncancel}>{"Cancel"}</button>
+                <button type="button" onclick={onsave}>{"Save"}</button>
+            </div>
+        </div>
+        </div>
+    }
 }
 
 #[function_component(App)]
 pub fn app() -> Html {
     let state = use_state(|| true);
+    let toasts = use_reducer(Toasts::default);
+    let toaster = Toaster::new(toasts.dispatcher());
+    {
+        let state = state.clone();
+        let toaster = toaster.clone();
+        use_effect_with_deps(
+            move |_| {
+                spawn_local(async move {
+                    match invoke_checked_no_arg("restore_session").await {
+                        Ok(c) => {
+                            if let Ok(mess) = serde_wasm_bindgen::from_value::<String>(c) {
+                                log(&mess);
+                            }
+                            state.set(false);
+                        }
+                        Err(c) => {
+                            match CommandError::from_js(c) {
+                                // the api is unreachable, the vault would not get further
+                                e @ CommandError::Network(_) => {
+                                    toast_error(&toaster, e, None);
+                                    return;
+                                }
+                                // nobody saved yet, not worth a toast
+                                CommandError::NotFound(_) => {}
+                                e => toaster.warn(format!("Please log in again. {e}")),
+                            }
+                            // fall back to a "remember me" vault that needs no passphrase
+                            let status: VaultStatus =
+                                match invoke_checked_no_arg("vault_status").await {
+                                    Ok(c) => serde_wasm_bindgen::from_value(c).unwrap_or_default(),
+                                    Err(_) => VaultStatus::default(),
+                                };
+                            if status.exists && !status.passphrase {
+                                match invoke_checked(
+                                    "vault_unlock",
+                                    to_value(&PassphraseArgs { passphrase: None }).unwrap(),
+                                )
+                                .await
+                                {
+                                    Ok(_) => state.set(false),
+                                    Err(c) => toast_error(&toaster, CommandError::from_js(c), None),
+                                }
+                            }
+                        }
+                    }
+                });
+                || {}
+            },
+            (),
+        );
+    }
+
+    let accounts = use_state(AccountList::default);
+    // bumped on every logout so `Login` starts over with an empty form
+    let session_gen = use_state(|| 0_u32);
+    // bumped on every account switch so `Search` reloads for the new account
+    let account_gen = use_state(|| 0_u32);
+    {
+        let accounts = accounts.clone();
+        let toaster = toaster.clone();
+        use_effect_with_deps(
+            move |(logged_out, _)| {
+                let logged_out = *logged_out;
+                spawn_local(async move {
+                    if logged_out {
+                        return;
+                    }
+                    match invoke_checked_no_arg("list_accounts").await {
+                        Ok(c) => {
+                            if let Ok(list) = serde_wasm_bindgen::from_value::<AccountList>(c) {
+                                let signed_in = list.active.is_some();
+                                accounts.set(list);
+                                if signed_in {
+                                    if let Err(c) = invoke_checked_no_arg("sync_playheads").await {
+                                        match CommandError::from_js(c) {
+                                            CommandError::Network(_) => toaster.warn(
+                                                "Offline, your progress will sync later."
+                                                    .to_string(),
+                                            ),
+                                            e => toast_error(&toaster, e, None),
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        Err(c) => log_error(c),
+                    }
+                });
+                || {}
+            },
+            (*state, *account_gen),
+        );
+    }
+
+    let settings = use_state(Settings::default);
+    let show_settings = use_state(|| false);
+    let show_downloads = use_state(|| false);
+    let show_library = use_state(|| false);
+    {
+        let settings = settings.clone();
+        let show_settings = show_settings.clone();
+        let show_downloads = show_downloads.clone();
+        let show_library = show_library.clone();
+        use_effect_with_deps(
+            move |_| {
+                spawn_local(async move {
+                    match invoke_checked_no_arg("get_settings").await {
+                        Ok(c) => {
+                            settings.set(serde_wasm_bindgen::from_value(c).unwrap_or_default())
+                        }
+                        Err(c) => log_error(c),
+                    }
+                    // the listeners live as long as the window, so they are leaked
+                    let changed = Closure::wrap(Box::new(move |e: JsValue| {
+                        if let Ok(e) = serde_wasm_bindgen::from_value::<TauriEvent<Settings>>(e) {
+                            settings.set(e.payload);
+                        }
+                    }) as Box<dyn FnMut(JsValue)>);
+                    listen(SETTINGS_EVENT, &changed).await;
+                    changed.forget();
+                    let open = Closure::wrap(Box::new(move |_: JsValue| {
+                        show_settings.set(true);
+                    }) as Box<dyn FnMut(JsValue)>);
+                    listen("open-settings", &open).await;
+                    open.forget();
+                    let open = Closure::wrap(Box::new(move |_: JsValue| {
+                        show_downloads.set(true);
+                    }) as Box<dyn FnMut(JsValue)>);
+                    listen("open-downloads", &open).await;
+                    open.forget();
+                    let open = Closure::wrap(Box::new(move |_: JsValue| {
+                        show_library.set(true);
+                    }) as Box<dyn FnMut(JsValue)>);
+                    listen("open-library", &open).await;
+                    open.forget();
+                });
+                || {}
+            },
+            (),
+        );
+    }
 
     // use_effect(move || {
     //     if web_sys::window().unwrap().document().unwrap().onmousemove().unwrap()
     // });
+    // the login form shown over a signed in account, until it logs in or is
+    // cancelled
+    let adding_account = use_state(|| false);
     let maybe_display_link = move || -> Html {
         let state = state.clone();
         let cb: Callback<(), ()> = {
             let state = state.clone();
             Callback::from(move |_| state.set(false))
         };
+        let on_switched: Callback<()> = {
+            let account_gen = account_gen.clone();
+            Callback::from(move |_| account_gen.set(*account_gen + 1))
+        };
+        let on_logged_out: Callback<()> = {
+            let state = state.clone();
+            let session_gen = session_gen.clone();
+            Callback::from(move |_| {
+                session_gen.set(*session_gen + 1);
+                state.set(true);
+            })
+        };
+        let on_add_account: Callback<()> = {
+            let adding_account = adding_account.clone();
+            Callback::from(move |_| adding_account.set(true))
+        };
+        if *adding_account && !*state {
+            // the new login becomes the active account, the old one stays saved
+            let on_added: Callback<()> = {
+                let adding_account = adding_account.clone();
+                let account_gen = account_gen.clone();
+                Callback::from(move |_| {
+                    adding_account.set(false);
+                    account_gen.set(*account_gen + 1);
+                })
+            };
+            let oncancel = {
+                let adding_account = adding_account.clone();
+                Callback::from(move |_: MouseEvent| adding_account.set(false))
+            };
+            return html! {<>
+                <Login key="add-account" username={"".to_string()} password={"".to_string()} state={false} on_logged_in={on_added}/>
+                <a class="textbtn" onclick={oncancel}>{"Cancel"}</a>
+            </>};
+        }
         html! {<>
 
-                         <Login username={"".to_string()} password={"".to_string()} state={false} on_logged_in={cb}/>
+                         <Login key={format!("login-{}", *session_gen)} username={"".to_string()} password={"".to_string()} state={!*state} on_logged_in={cb}/>
         if *state {} else {
-
-                     <Search search_string={"".to_string()}/>
+                     <AccountMenu accounts={(*accounts).clone()} on_switched={on_switched} on_logged_out={on_logged_out} on_add_account={on_add_account}/>
+                     <Search key={format!("search-{}", *account_gen)} search_string={"".to_string()}/>
                  }
                  </>
              }
     };
+    let close_settings = {
+        let show_settings = show_settings.clone();
+        Callback::from(move |_| show_settings.set(false))
+    };
+    let close_downloads = {
+        let show_downloads = show_downloads.clone();
+        Callback::from(move |_| show_downloads.set(false))
+    };
+    let close_library = {
+        let show_library = show_library.clone();
+        Callback::from(move |_| show_library.set(false))
+    };
     html! {
+        <ContextProvider<Toaster> context={toaster.clone()}>
+        <ContextProvider<Settings> context={(*settings).clone()}>
         <main class="container">
-        {maybe_display_link()}
+        // the library stands in for login and search, it works offline
+        if *show_library {
+            <OfflineLibrary onclose={close_library}/>
+        } else {
+            {maybe_display_link()}
+        }
         </main>
+        if *show_settings {
+            <SettingsView onclose={close_settings}/>
+        }
+        if *show_downloads {
+            <DownloadsView onclose={close_downloads}/>
+        }
+        <ToastStack toasts={toasts.list.clone()} {toaster}/>
+        </ContextProvider<Settings>>
+        </ContextProvider<Toaster>>
     }
 }
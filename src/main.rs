@@ -1,4 +1,5 @@
 mod app;
+mod toast;
 use app::App;
 
 fn main() {
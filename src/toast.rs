@@ -0,0 +1,186 @@
+use std::rc::Rc;
+use std::time::Duration;
+use yew::platform::spawn_local;
+use yew::platform::time::sleep;
+use yew::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToastLevel {
+    Error,
+    Warn,
+    Info,
+}
+
+impl ToastLevel {
+    fn class(&self) -> &'static str {
+        match self {
+            ToastLevel::Error => "toast error",
+            ToastLevel::Warn => "toast warn",
+            ToastLevel::Info => "toast info",
+        }
+    }
+
+    /// Errors stay up longer, they usually need reading.
+    fn timeout(&self) -> Duration {
+        match self {
+            ToastLevel::Error => Duration::from_secs(8),
+            ToastLevel::Warn => Duration::from_secs(6),
+            ToastLevel::Info => Duration::from_secs(4),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Toast {
+    pub id: u32,
+    pub level: ToastLevel,
+    pub message: String,
+    /// Shown as a "Retry" button, which also dismisses the toast.
+    pub retry: Option<Callback<()>>,
+}
+
+pub enum ToastAction {
+    Push(ToastLevel, String, Option<Callback<()>>),
+    Dismiss(u32),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Toasts {
+    next_id: u32,
+    pub list: Vec<Toast>,
+}
+
+/// At most this many toasts are on screen, the oldest go first.
+const MAX_TOASTS: usize = 4;
+
+impl Reducible for Toasts {
+    type Action = ToastAction;
+
+    fn reduce(self: Rc<Self>, action: Self::Action) -> Rc<Self> {
+        let mut toasts = (*self).clone();
+        match action {
+            ToastAction::Push(level, message, retry) => {
+                // the same failure reported twice in a row only shows once
+                if matches!(toasts.list.last(), Some(t) if t.level == level && t.message == message)
+                {
+                    return self;
+                }
+                toasts.list.push(Toast {
+                    id: toasts.next_id,
+                    level,
+                    message,
+                    retry,
+                });
+                toasts.next_id += 1;
+                if toasts.list.len() > MAX_TOASTS {
+                    toasts.list.remove(0);
+                }
+            }
+            ToastAction::Dismiss(id) => toasts.list.retain(|t| t.id != id),
+        }
+        Rc::new(toasts)
+    }
+}
+
+/// Handed down from `App` through a context, lets any component show a toast.
+#[derive(Clone, PartialEq)]
+pub struct Toaster {
+    dispatcher: UseReducerDispatcher<Toasts>,
+}
+
+impl Toaster {
+    pub fn new(dispatcher: UseReducerDispatcher<Toasts>) -> Toaster {
+        Toaster { dispatcher }
+    }
+
+    pub fn push(&self, level: ToastLevel, message: String, retry: Option<Callback<()>>) {
+        self.dispatcher
+            .dispatch(ToastAction::Push(level, message, retry));
+    }
+
+    pub fn error(&self, message: String) {
+        self.push(ToastLevel::Error, message, None);
+    }
+
+    pub fn warn(&self, message: String) {
+        self.push(ToastLevel::Warn, message, None);
+    }
+
+    pub fn info(&self, message: String) {
+        self.push(ToastLevel::Info, message, None);
+    }
+
+    pub fn dismiss(&self, id: u32) {
+        self.dispatcher.dispatch(ToastAction::Dismiss(id));
+    }
+}
+
+#[hook]
+pub fn use_toaster() -> Toaster {
+    use_context::<Toaster>().expect("use_toaster needs the Toaster context from App")
+}
+
+#[derive(PartialEq, Properties)]
+pub struct ToastViewProps {
+    pub toast: Toast,
+    pub toaster: Toaster,
+}
+
+#[function_component]
+pub fn ToastView(props: &ToastViewProps) -> Html {
+    let ToastViewProps { toast, toaster } = props;
+    {
+        let toaster = toaster.clone();
+        use_effect_with_deps(
+            move |(id, level)| {
+                let (id, timeout) = (*id, level.timeout());
+                spawn_local(async move {
+                    sleep(timeout).await;
+                    toaster.dismiss(id);
+                });
+                || {}
+            },
+            (toast.id, toast.level),
+        );
+    }
+    let close = {
+        let toaster = toaster.clone();
+        let id = toast.id;
+        Callback::from(move |_: MouseEvent| toaster.dismiss(id))
+    };
+    let retry = toast.retry.clone().map(|retry| {
+        let toaster = toaster.clone();
+        let id = toast.id;
+        Callback::from(move |_: MouseEvent| {
+            toaster.dismiss(id);
+            retry.emit(());
+        })
+    });
+    html! {
+        <div class={toast.level.class()} role="alert">
+            <span>{ &toast.message }</span>
+            if let Some(retry) = retry {
+                <button onclick={retry}>{"Retry"}</button>
+            }
+            <button class="toast-close" onclick={close}>{"×"}</button>
+        </div>
+    }
+}
+
+#[derive(PartialEq, Properties)]
+pub struct ToastStackProps {
+    pub toasts: Vec<Toast>,
+    pub toaster: Toaster,
+}
+
+#[function_component]
+pub fn ToastStack(props: &ToastStackProps) -> Html {
+    let ToastStackProps { toasts, toaster } = props;
+    html! {
+        <div class="toasts">
+            { for toasts.iter().map(|t| html! {
+                <ToastView key={t.id} toast={t.clone()} toaster={toaster.clone()}/>
+            }) }
+        </div>
+    }
+}